@@ -0,0 +1,291 @@
+use std::{fmt::Write, net::SocketAddr, sync::Arc, time::Instant};
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Request, State},
+    http::{header, HeaderMap, HeaderName},
+    middleware::Next,
+    response::Response,
+};
+use futures_util::StreamExt;
+
+use crate::compression::Uncompressed;
+
+/// A field of an access log line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Method,
+    Path,
+    Status,
+    DurationUs,
+    Bytes,
+    Ip,
+    UserAgent,
+    Referer,
+    Encoding,
+    Ratio,
+}
+
+impl Field {
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Method => "method",
+            Self::Path => "path",
+            Self::Status => "status",
+            Self::DurationUs => "duration_us",
+            Self::Bytes => "bytes",
+            Self::Ip => "ip",
+            Self::UserAgent => "user_agent",
+            Self::Referer => "referer",
+            Self::Encoding => "encoding",
+            Self::Ratio => "ratio",
+        }
+    }
+}
+
+impl std::str::FromStr for Field {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().replace('-', "_").as_str() {
+            "method" => Ok(Self::Method),
+            "path" => Ok(Self::Path),
+            "status" => Ok(Self::Status),
+            "duration_us" => Ok(Self::DurationUs),
+            "bytes" => Ok(Self::Bytes),
+            "ip" => Ok(Self::Ip),
+            "user_agent" => Ok(Self::UserAgent),
+            "referer" => Ok(Self::Referer),
+            "encoding" => Ok(Self::Encoding),
+            "ratio" => Ok(Self::Ratio),
+            _ => Err(s.to_string()),
+        }
+    }
+}
+
+/// Parses a comma-separated list of fields.
+pub fn parse_fields(s: &str) -> Result<Arc<[Field]>, String> {
+    s.split(',')
+        .filter(|field| !field.trim().is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+/// The values of a request and its response available to the access log.
+struct Entry {
+    method: String,
+    path: String,
+    status: u16,
+    duration_us: u128,
+    bytes: Option<String>,
+    ip: Option<String>,
+    user_agent: Option<String>,
+    referer: Option<String>,
+    encoding: Option<String>,
+    ratio: Option<String>,
+}
+
+impl Entry {
+    /// Formats the selected fields as `key=value` pairs in the given order.
+    fn format(&self, fields: &[Field]) -> String {
+        let mut line = String::new();
+        for field in fields {
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            let _ = match field {
+                Field::Method => write!(line, "{}={}", field.name(), self.method),
+                Field::Path => write!(line, "{}={}", field.name(), self.path),
+                Field::Status => write!(line, "{}={}", field.name(), self.status),
+                Field::DurationUs => write!(line, "{}={}", field.name(), self.duration_us),
+                Field::Bytes => write!(line, "{}={}", field.name(), or_dash(self.bytes.as_ref())),
+                Field::Ip => write!(line, "{}={}", field.name(), or_dash(self.ip.as_ref())),
+                Field::UserAgent => write!(
+                    line,
+                    "{}={:?}",
+                    field.name(),
+                    or_dash(self.user_agent.as_ref())
+                ),
+                Field::Referer => write!(
+                    line,
+                    "{}={:?}",
+                    field.name(),
+                    or_dash(self.referer.as_ref())
+                ),
+                Field::Encoding => {
+                    write!(line, "{}={}", field.name(), or_dash(self.encoding.as_ref()))
+                }
+                Field::Ratio => write!(line, "{}={}", field.name(), or_dash(self.ratio.as_ref())),
+            };
+        }
+        line
+    }
+}
+
+fn or_dash(value: Option<&String>) -> &str {
+    value.map_or("-", String::as_str)
+}
+
+fn header_value(headers: &HeaderMap, name: &HeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(ToString::to_string)
+}
+
+/// An entry logged once the body of its compressed response has been sent,
+/// when the compression ratio is known.
+struct Deferred {
+    entry: Entry,
+    fields: Arc<[Field]>,
+    uncompressed: u64,
+    sent: u64,
+}
+
+impl Deferred {
+    const fn count(&mut self, len: usize) {
+        self.sent += len as u64;
+    }
+}
+
+impl Drop for Deferred {
+    fn drop(&mut self) {
+        if self.sent > 0 {
+            #[allow(clippy::cast_precision_loss)]
+            let ratio = self.uncompressed as f64 / self.sent as f64;
+            self.entry.ratio = Some(format!("{ratio:.2}"));
+        }
+        self.entry.bytes = Some(self.sent.to_string());
+        tracing::info!("{}", self.entry.format(&self.fields));
+    }
+}
+
+/// Logs one line per request containing the configured fields.
+///
+/// If the ratio is logged, the line of a compressed response is logged once
+/// its body has been sent.
+pub async fn log(State(fields): State<Arc<[Field]>>, req: Request, next: Next) -> Response {
+    let start = Instant::now();
+    let method = req.method().to_string();
+    let path = req.uri().path().to_string();
+    let ip = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_string());
+    let user_agent = header_value(req.headers(), &header::USER_AGENT);
+    let referer = header_value(req.headers(), &header::REFERER);
+
+    let response = next.run(req).await;
+
+    let entry = Entry {
+        method,
+        path,
+        status: response.status().as_u16(),
+        duration_us: start.elapsed().as_micros(),
+        bytes: header_value(response.headers(), &header::CONTENT_LENGTH),
+        ip,
+        user_agent,
+        referer,
+        encoding: header_value(response.headers(), &header::CONTENT_ENCODING),
+        ratio: None,
+    };
+    let uncompressed = response.extensions().get::<Uncompressed>().copied();
+    let Some(Uncompressed(uncompressed)) =
+        uncompressed.filter(|_| entry.encoding.is_some() && fields.contains(&Field::Ratio))
+    else {
+        tracing::info!("{}", entry.format(&fields));
+        return response;
+    };
+
+    let mut deferred = Deferred {
+        entry,
+        fields,
+        uncompressed,
+        sent: 0,
+    };
+    response.map(|body| {
+        let stream = body.into_data_stream().inspect(move |chunk| {
+            if let Ok(chunk) = chunk {
+                deferred.count(chunk.len());
+            }
+        });
+        Body::from_stream(stream)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{http::StatusCode, routing::get, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::captured::Captured;
+
+    fn request(uri: &str) -> Request {
+        Request::get(uri)
+            .header(header::USER_AGENT, "curl/8.0")
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn parse_fields_accepts_known_names() {
+        assert_eq!(
+            parse_fields("method, Duration-US,,user_agent").unwrap()[..],
+            [Field::Method, Field::DurationUs, Field::UserAgent]
+        );
+        assert_eq!(parse_fields("method,size").unwrap_err(), "size");
+    }
+
+    #[tokio::test]
+    async fn log_writes_the_selected_fields_in_order() {
+        let fields = parse_fields("status,method,path,bytes,ip,user_agent,referer").unwrap();
+        let app = Router::new()
+            .route(
+                "/a",
+                get(|| async { ([(header::CONTENT_LENGTH, "2")], "ok") }),
+            )
+            .layer(axum::middleware::from_fn_with_state(fields, log));
+        let (captured, _guard) = Captured::start();
+        let response = app.oneshot(request("/a?q=1")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            captured.lines(),
+            ["status=200 method=GET path=/a bytes=2 ip=- user_agent=\"curl/8.0\" referer=\"-\""]
+        );
+    }
+
+    #[tokio::test]
+    async fn compressed_responses_are_logged_with_their_ratio() {
+        use http_body_util::BodyExt;
+
+        let fields = parse_fields("encoding,ratio,bytes").unwrap();
+        let page = "compressible ".repeat(200);
+        let app = Router::new()
+            .route(
+                "/page",
+                get(move || async move { ([(header::CONTENT_LENGTH, page.len())], page) }),
+            )
+            .layer(axum::middleware::from_fn(crate::compression::record_length))
+            .layer(tower_http::compression::CompressionLayer::new())
+            .layer(axum::middleware::from_fn_with_state(fields, log));
+        let (captured, _guard) = Captured::start();
+        let response = app.oneshot(request("/page")).await.unwrap();
+        // The line is logged once the body has been sent
+        assert!(captured.lines().is_empty());
+        let sent = response
+            .into_body()
+            .collect()
+            .await
+            .unwrap()
+            .to_bytes()
+            .len();
+        #[allow(clippy::cast_precision_loss)]
+        let ratio = 2600.0 / sent as f64;
+        assert_eq!(
+            captured.lines(),
+            [format!("encoding=gzip ratio={ratio:.2} bytes={sent}")]
+        );
+    }
+}
@@ -0,0 +1,191 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Extension,
+};
+use bytes::Bytes;
+use sha2::{Digest, Sha256};
+
+use crate::{etag, middleware::Generated};
+
+/// The maximum number of bundles kept in the cache.
+const MAX_BUNDLES: usize = 64;
+
+/// Bundles of the files in the document root, concatenated on request.
+///
+/// A bundle is cached until one of its files is modified.
+pub struct Bundles {
+    root: PathBuf,
+    cache: Mutex<HashMap<String, Bundle>>,
+}
+
+struct Bundle {
+    files: Vec<(SystemTime, u64)>,
+    body: Bytes,
+    etag: HeaderValue,
+}
+
+impl Bundles {
+    pub fn new(root: &Path) -> Self {
+        Self {
+            root: root.to_path_buf(),
+            cache: Mutex::default(),
+        }
+    }
+
+    /// Returns the concatenated comma-separated, percent-encoded `files` and
+    /// their `ETag`, or `None` if a file is missing or not of type `ext`.
+    async fn get(&self, files: &str, ext: &str) -> Option<(Bytes, HeaderValue)> {
+        let mut paths = Vec::new();
+        let mut metas = Vec::new();
+        for file in files.split(',') {
+            let file = file.trim();
+            let path = etag::resolve(&self.root, file).filter(|path| {
+                !file.is_empty()
+                    && !file.ends_with('/')
+                    && path
+                        .extension()
+                        .is_some_and(|e| e.eq_ignore_ascii_case(ext))
+            })?;
+            let meta = tokio::fs::metadata(&path).await.ok()?;
+            if !meta.is_file() {
+                return None;
+            }
+            metas.push((meta.modified().ok()?, meta.len()));
+            paths.push(path);
+        }
+
+        let cached = self
+            .cache
+            .lock()
+            .unwrap()
+            .get(files)
+            .filter(|bundle| bundle.files == metas)
+            .map(|bundle| (bundle.body.clone(), bundle.etag.clone()));
+        if cached.is_some() {
+            return cached;
+        }
+
+        let mut body = Vec::new();
+        for path in &paths {
+            body.extend(tokio::fs::read(path).await.ok()?);
+            if !body.ends_with(b"\n") {
+                body.push(b'\n');
+            }
+        }
+        let body = Bytes::from(body);
+        let etag = etag::from_digest(&Sha256::digest(&body));
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= MAX_BUNDLES {
+            cache.clear();
+        }
+        cache.insert(
+            files.to_string(),
+            Bundle {
+                files: metas,
+                body: body.clone(),
+                etag: etag.clone(),
+            },
+        );
+        drop(cache);
+        Some((body, etag))
+    }
+}
+
+/// Serves the files listed in the `files` query parameter, e.g.
+/// "/bundle.js?files=a.js,b.js", concatenated in order.
+///
+/// Only files of the bundle's type below the document root can be bundled,
+/// otherwise the bundle is not found.
+pub async fn bundle(State(bundles): State<Arc<Bundles>>, req: Request) -> Response {
+    let (ext, content_type) = if req.uri().path() == "/bundle.css" {
+        ("css", "text/css")
+    } else {
+        ("js", "text/javascript")
+    };
+    let files = req
+        .uri()
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .find_map(|param| param.strip_prefix("files="));
+    let Some(files) = files.filter(|files| !files.is_empty()) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let Some((body, etag)) = bundles.get(files, ext).await else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    if let Some(matched) = etag::matching(req.headers(), &etag) {
+        return (StatusCode::NOT_MODIFIED, [(header::ETAG, matched)]).into_response();
+    }
+    (
+        [
+            (header::CONTENT_TYPE, HeaderValue::from_static(content_type)),
+            (header::ETAG, etag),
+        ],
+        Extension(Generated),
+        body,
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, routing::get, Router};
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::temp_dir::TempDir;
+
+    #[tokio::test]
+    async fn bundles_concatenate_the_files_in_order() {
+        let dir = TempDir::new(&[("a.js", "a()"), ("lib/b.js", "b()\n"), ("c.css", "c{}")]);
+        let bundles = Arc::new(Bundles::new(&dir));
+        let app = Router::new()
+            .route("/bundle.js", get(bundle).with_state(bundles.clone()))
+            .route("/bundle.css", get(bundle).with_state(bundles));
+        let send = |uri: &str, if_none_match: Option<&HeaderValue>| {
+            let mut req = Request::get(uri);
+            if let Some(etag) = if_none_match {
+                req = req.header(header::IF_NONE_MATCH, etag);
+            }
+            app.clone().oneshot(req.body(Body::empty()).unwrap())
+        };
+
+        let response = send("/bundle.js?files=lib%2Fb.js,a.js", None)
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/javascript");
+        let etag = response.headers()[header::ETAG].clone();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "b()\na()\n");
+        assert_eq!(etag, etag::from_digest(&Sha256::digest(&body)));
+        let response = send("/bundle.js?files=lib%2Fb.js,a.js", Some(&etag))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        let response = send("/bundle.css?files=c.css", None).await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/css");
+        for uri in [
+            "/bundle.js",
+            "/bundle.js?files=c.css",
+            "/bundle.js?files=missing.js",
+            "/bundle.js?files=../a.js",
+            "/bundle.css?files=a.js",
+        ] {
+            let response = send(uri, None).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{uri}");
+        }
+    }
+}
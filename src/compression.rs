@@ -0,0 +1,988 @@
+use std::{
+    io,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header, Extensions, HeaderMap, HeaderValue, Method, StatusCode, Version},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use brotli::enc::{
+    interface::{PredictionModeContextMap, StaticCommand},
+    BrotliEncoderParams, IoReaderWrapper, IoWriterWrapper, StandardAlloc,
+};
+use bytes::Bytes;
+use futures_util::StreamExt;
+use http_body_util::BodyExt;
+use sha2::{Digest, Sha256};
+use tower::{Layer, ServiceExt};
+use tower_http::{
+    compression::{
+        predicate::{DefaultPredicate, Predicate, SizeAbove},
+        CompressionLayer, CompressionLevel,
+    },
+    services::ServeFile,
+};
+
+use crate::{etag, glob::Glob, precompressed};
+
+/// Disables compression for range requests.
+///
+/// Ranges refer to the identity representation of a file, so a request with a
+/// `Range` header must be served from the raw file with a matching
+/// `Content-Range` and `Content-Length`. Removing `Accept-Encoding` guarantees
+/// that the compression layer leaves such responses untouched.
+pub async fn identity_for_ranges(mut req: Request, next: Next) -> Response {
+    if req.headers().contains_key(header::RANGE) {
+        req.headers_mut().remove(header::ACCEPT_ENCODING);
+    }
+    next.run(req).await
+}
+
+/// The length of a response before it was compressed.
+#[derive(Debug, Clone, Copy)]
+pub struct Uncompressed(pub u64);
+
+/// Records the length of responses as [`Uncompressed`] before the compression
+/// layer replaces it, so the access log can report the compression ratio.
+pub async fn record_length(req: Request, next: Next) -> Response {
+    let mut response = next.run(req).await;
+    let len = response
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.parse::<u64>().ok());
+    if let Some(len) = len {
+        response.extensions_mut().insert(Uncompressed(len));
+    }
+    response
+}
+
+/// Marks a `HEAD` request passed to the compression layer as a `GET`.
+#[derive(Debug, Clone, Copy)]
+struct HeadRequest;
+
+/// Answers `HEAD` requests with the headers of the equivalent `GET`, including
+/// the `Content-Encoding` of a compressed body.
+///
+/// The compression layer leaves `HEAD` responses uncompressed, so it is passed
+/// a `GET`, while [`restore_head`] passes the `HEAD` on to the inner layers. A
+/// compressed response is sent without a `Content-Length` like the `GET`, as
+/// the length of a compressed body depends on how it is read.
+pub async fn head_as_get(mut req: Request, next: Next) -> Response {
+    if req.method() != Method::HEAD || !req.headers().contains_key(header::ACCEPT_ENCODING) {
+        return next.run(req).await;
+    }
+    *req.method_mut() = Method::GET;
+    req.extensions_mut().insert(HeadRequest);
+    next.run(req).await.map(|_| Body::empty())
+}
+
+/// Passes the `HEAD` requests [`head_as_get`] marked on to the inner layers as
+/// `HEAD`, below the compression layer.
+///
+/// The empty body of the response is replaced by one of unknown length, so the
+/// compression layer decides by the `Content-Length` whether it would compress
+/// the `GET`.
+pub async fn restore_head(mut req: Request, next: Next) -> Response {
+    if req.extensions_mut().remove::<HeadRequest>().is_none() {
+        return next.run(req).await;
+    }
+    *req.method_mut() = Method::HEAD;
+    next.run(req)
+        .await
+        .map(|_| Body::from_stream(futures_util::stream::empty::<Result<Bytes, axum::Error>>()))
+}
+
+/// Weakens the `ETag` of compressed responses.
+///
+/// A strong `ETag` identifies the exact bytes of a representation, which differ
+/// between the compressed response and the file it was compressed from.
+pub async fn weaken_etag(req: Request, next: Next) -> Response {
+    let mut response = next.run(req).await;
+    if !response.headers().contains_key(header::CONTENT_ENCODING) {
+        return response;
+    }
+    let weak = response
+        .headers()
+        .get(header::ETAG)
+        .filter(|etag| !etag.as_bytes().starts_with(b"W/"))
+        .and_then(|etag| etag.to_str().ok())
+        .and_then(|etag| HeaderValue::from_str(&format!("W/{etag}")).ok());
+    if let Some(weak) = weak {
+        response.headers_mut().insert(header::ETAG, weak);
+    }
+    response
+}
+
+/// Disables compression for paths matching one of the globs, e.g. for a tree of
+/// already compressed files.
+pub async fn skip_paths(
+    State(globs): State<Arc<[Glob]>>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    if globs.iter().any(|glob| glob.matches(req.uri().path())) {
+        req.headers_mut().remove(header::ACCEPT_ENCODING);
+    }
+    next.run(req).await
+}
+
+/// The magic number starting a body compressed with a shared dictionary.
+const DCB_MAGIC: [u8; 4] = [0xff, 0x44, 0x43, 0x42];
+
+/// The largest shared dictionary, as it is kept in memory and prepended to the
+/// compression window.
+pub const DICTIONARY_MAX_SIZE: u64 = 1024 * 1024;
+
+/// The largest response compressed with the shared dictionary, as it is
+/// buffered to be compressed at once.
+const DICTIONARY_MAX_RESPONSE: u64 = 1024 * 1024;
+
+/// A shared Brotli dictionary, used for the clients announcing it in
+/// `Available-Dictionary` (RFC 9842).
+pub struct Dictionary {
+    bytes: Vec<u8>,
+    hash: [u8; 32],
+    available: String,
+    quality: i32,
+}
+
+impl Dictionary {
+    /// Creates the dictionary of `bytes`, compressing at the Brotli quality of
+    /// `level` like the compression layer, but between 2 and 9, as the encoder
+    /// does not use the dictionary at the fastest and the best qualities.
+    pub fn new(bytes: Vec<u8>, level: CompressionLevel) -> Self {
+        let hash: [u8; 32] = Sha256::digest(&bytes).into();
+        let quality = match level {
+            CompressionLevel::Fastest => 2,
+            CompressionLevel::Best => 9,
+            CompressionLevel::Precise(quality) => quality.clamp(2, 9),
+            _ => 4,
+        };
+        Self {
+            available: format!(":{}:", BASE64_STANDARD.encode(hash)),
+            bytes,
+            hash,
+            quality,
+        }
+    }
+
+    /// Returns whether the client accepts `dcb` and has the dictionary.
+    fn is_available(&self, req: &Request) -> bool {
+        req.headers()
+            .get("available-dictionary")
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.trim() == self.available)
+            && req
+                .headers()
+                .get(header::ACCEPT_ENCODING)
+                .and_then(|v| v.to_str().ok())
+                .is_some_and(|accept| precompressed::accepts(accept, "dcb"))
+    }
+
+    /// Compresses `content` into a `dcb` body, the magic number and the
+    /// SHA-256 of the dictionary followed by the Brotli stream.
+    pub fn compress(&self, content: &[u8]) -> io::Result<Vec<u8>> {
+        // The window must reach back over the content into the dictionary
+        let window = (self.bytes.len() + content.len() + 16).next_power_of_two();
+        let params = BrotliEncoderParams {
+            quality: self.quality,
+            lgwin: i32::try_from(window.trailing_zeros()).map_or(24, |lgwin| lgwin.clamp(16, 24)),
+            ..BrotliEncoderParams::default()
+        };
+
+        let mut body = Vec::with_capacity(content.len() / 2 + 36);
+        body.extend_from_slice(&DCB_MAGIC);
+        body.extend_from_slice(&self.hash);
+        let mut nop = |_: &mut PredictionModeContextMap<brotli::InputReferenceMut>,
+                       _: &mut [StaticCommand],
+                       _: brotli::InputPair,
+                       _: &mut StandardAlloc| ();
+        brotli::enc::BrotliCompressCustomIoCustomDict(
+            &mut IoReaderWrapper(&mut &content[..]),
+            &mut IoWriterWrapper(&mut body),
+            &mut [0; 4096],
+            &mut [0; 4096],
+            &params,
+            StandardAlloc::default(),
+            &mut nop,
+            &self.bytes,
+            io::Error::new(io::ErrorKind::UnexpectedEof, "unexpected end of content"),
+        )?;
+        Ok(body)
+    }
+}
+
+/// Compresses responses with the shared dictionary for the clients that have
+/// it, leaving the others to the compression layer.
+///
+/// Only complete responses up to [`DICTIONARY_MAX_RESPONSE`] bytes the
+/// compression layer would compress are compressed with the dictionary, as
+/// they are buffered. They vary by `Available-Dictionary` for every client.
+pub async fn dictionary(
+    State(dictionary): State<Arc<Dictionary>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let available = dictionary.is_available(&req);
+    let head = req.extensions().get::<HeadRequest>().is_some();
+    let mut response = next.run(req).await;
+    if response.status() != StatusCode::OK
+        || response.headers().contains_key(header::CONTENT_ENCODING)
+        || !predicate().should_compress(&response)
+    {
+        return response;
+    }
+    response.headers_mut().append(
+        header::VARY,
+        HeaderValue::from_static("available-dictionary"),
+    );
+    let len = response
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.parse::<u64>().ok());
+    if !available || len.is_none_or(|len| len > DICTIONARY_MAX_RESPONSE) {
+        return response;
+    }
+
+    let headers = response.headers_mut();
+    headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static("dcb"));
+    headers.append(header::VARY, HeaderValue::from_static("accept-encoding"));
+    headers.remove(header::ACCEPT_RANGES);
+    if head {
+        // Like a compressed `GET` without compressing the empty body
+        headers.remove(header::CONTENT_LENGTH);
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    let content = match body.collect().await {
+        Ok(content) => content.to_bytes(),
+        Err(e) => {
+            tracing::error!("failed to read response to compress: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let compressed = {
+        let content = content.clone();
+        tokio::task::spawn_blocking(move || dictionary.compress(&content))
+            .await
+            .unwrap_or_else(|e| Err(io::Error::other(e)))
+    };
+    match compressed {
+        Ok(compressed) => {
+            parts
+                .headers
+                .insert(header::CONTENT_LENGTH, HeaderValue::from(compressed.len()));
+            Response::from_parts(parts, Body::from(compressed))
+        }
+        Err(e) => {
+            tracing::error!("failed to compress with the shared dictionary: {}", e);
+            parts.headers.remove(header::CONTENT_ENCODING);
+            Response::from_parts(parts, Body::from(content))
+        }
+    }
+}
+
+/// Requests currently handled, counted to shed compression under load.
+#[derive(Default)]
+pub struct InFlight(AtomicUsize);
+
+/// A request counted in flight until its body is dropped.
+struct InFlightGuard(Arc<InFlight>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0 .0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Disables compression while more than `threshold` requests are in flight,
+/// keeping latency bounded when compression would starve the CPU.
+///
+/// A request is in flight until the body of its response is dropped, as the
+/// body is compressed while it is sent.
+pub async fn shed_under_load(
+    State((in_flight, threshold)): State<(Arc<InFlight>, usize)>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let count = in_flight.0.fetch_add(1, Ordering::Relaxed) + 1;
+    let guard = InFlightGuard(in_flight);
+    if count > threshold {
+        req.headers_mut().remove(header::ACCEPT_ENCODING);
+    }
+    next.run(req).await.map(|body| {
+        // The request leaves the flight once the body is dropped
+        let stream = body.into_data_stream().inspect(move |_| {
+            let _ = &guard;
+        });
+        Body::from_stream(stream)
+    })
+}
+
+/// What to do with a response that would exceed the in-flight byte budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BudgetAction {
+    /// Serve the response without compression.
+    Identity,
+    /// Respond with `503 Service Unavailable`.
+    Shed,
+}
+
+impl std::str::FromStr for BudgetAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "identity" => Ok(Self::Identity),
+            "shed" | "503" => Ok(Self::Shed),
+            _ => Err(s.to_string()),
+        }
+    }
+}
+
+/// The bytes of the files currently being served, bounded by `max`.
+pub struct ByteBudget {
+    in_flight: AtomicU64,
+    max: u64,
+    action: BudgetAction,
+}
+
+impl ByteBudget {
+    pub const fn new(max: u64, action: BudgetAction) -> Self {
+        Self {
+            in_flight: AtomicU64::new(0),
+            max,
+            action,
+        }
+    }
+}
+
+/// Marks a response the compression layer leaves uncompressed, as it exceeds
+/// the in-flight byte budget.
+#[derive(Debug, Clone, Copy)]
+pub struct Identity;
+
+/// The responses to compress: those of the default predicate not marked
+/// [`Identity`].
+pub fn predicate() -> impl Predicate {
+    DefaultPredicate::new().and(
+        |_: StatusCode, _: Version, _: &HeaderMap, extensions: &Extensions| {
+            extensions.get::<Identity>().is_none()
+        },
+    )
+}
+
+/// The compression layer of the site, compressing at `level` with gzip, brotli
+/// and, if `zstd`, zstd.
+pub fn layer(level: CompressionLevel, zstd: bool) -> CompressionLayer<impl Predicate> {
+    let layer = CompressionLayer::new().quality(level);
+    let layer = if zstd { layer } else { layer.no_zstd() };
+    layer.compress_when(predicate())
+}
+
+/// Bytes of a response reserved from the budget until its body is dropped.
+struct Reservation {
+    budget: Arc<ByteBudget>,
+    len: u64,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.in_flight.fetch_sub(self.len, Ordering::Relaxed);
+    }
+}
+
+/// Bounds the total size of the files below `root` being served, which may be
+/// buffered or compressed in memory.
+///
+/// A file exceeding the budget is served uncompressed or shed, depending on
+/// the action of the budget. A file larger than the whole budget is served as
+/// usual while no other file is in flight, so it can be served at all.
+pub async fn byte_budget(
+    State((budget, root)): State<(Arc<ByteBudget>, Arc<Path>)>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    if req.method() != Method::GET {
+        return next.run(req).await;
+    }
+    let Some(path) = etag::resolve(&root, req.uri().path()) else {
+        return next.run(req).await;
+    };
+    let len = match tokio::fs::metadata(&path).await {
+        Ok(meta) if meta.is_file() => meta.len(),
+        _ => return next.run(req).await,
+    };
+    let in_flight = budget.in_flight.fetch_add(len, Ordering::Relaxed) + len;
+    let reservation = Reservation {
+        budget: budget.clone(),
+        len,
+    };
+    if in_flight > budget.max && in_flight > len {
+        drop(reservation);
+        tracing::debug!("'{}' exceeds the in-flight byte budget", path.display());
+        return match budget.action {
+            BudgetAction::Identity => {
+                // Removing `Accept-Encoding` skips precompressed files, the
+                // marker skips the compression layer wrapping the routes
+                req.headers_mut().remove(header::ACCEPT_ENCODING);
+                let mut response = next.run(req).await;
+                response.extensions_mut().insert(Identity);
+                response
+            }
+            BudgetAction::Shed => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                [(header::RETRY_AFTER, HeaderValue::from_static("1"))],
+            )
+                .into_response(),
+        };
+    }
+
+    next.run(req).await.map(|body| {
+        // The reservation is released once the body is dropped
+        let stream = body.into_data_stream().inspect(move |_| {
+            let _ = &reservation;
+        });
+        Body::from_stream(stream)
+    })
+}
+
+/// The encodings the compression layer supports.
+const BENCHMARK_ENCODINGS: &[&str] = &["gzip", "br", "zstd"];
+
+/// The compression levels benchmarked for each encoding.
+const BENCHMARK_LEVELS: &[(&str, CompressionLevel)] = &[
+    ("fastest", CompressionLevel::Fastest),
+    ("default", CompressionLevel::Default),
+    ("best", CompressionLevel::Best),
+];
+
+/// Parses a compression level, either one of the benchmarked levels by name,
+/// e.g. "best", or a number specific to the encoding.
+pub fn parse_level(s: &str) -> Result<CompressionLevel, String> {
+    let s = s.trim();
+    BENCHMARK_LEVELS
+        .iter()
+        .find(|(name, _)| s.eq_ignore_ascii_case(name))
+        .map(|&(_, level)| level)
+        .or_else(|| s.parse().ok().map(CompressionLevel::Precise))
+        .ok_or_else(|| s.to_string())
+}
+
+/// The compressed size and time of an encoding at a level.
+pub struct Measurement {
+    pub encoding: &'static str,
+    pub level: &'static str,
+    pub bytes: usize,
+    pub elapsed: Duration,
+}
+
+/// Serves `sample` through the compression layer with each encoding and level
+/// and measures the size and time of each.
+pub async fn measure(sample: &Path) -> std::io::Result<Vec<Measurement>> {
+    let mut measurements = Vec::new();
+    for &encoding in BENCHMARK_ENCODINGS {
+        for &(name, level) in BENCHMARK_LEVELS {
+            let service = CompressionLayer::new()
+                .quality(level)
+                .compress_when(SizeAbove::new(0))
+                .layer(ServeFile::new(sample));
+            let req = Request::builder()
+                .header(header::ACCEPT_ENCODING, encoding)
+                .body(Body::empty())
+                .expect("request is valid");
+            let start = Instant::now();
+            let Ok(response) = service.oneshot(req).await;
+            let bytes = response
+                .into_body()
+                .collect()
+                .await
+                .map_err(std::io::Error::other)?
+                .to_bytes()
+                .len();
+            measurements.push(Measurement {
+                encoding,
+                level: name,
+                bytes,
+                elapsed: start.elapsed(),
+            });
+        }
+    }
+    Ok(measurements)
+}
+
+/// Prints the size and time of `sample` compressed with each encoding and
+/// level, to choose `WEBSERVER_COMPRESSION_LEVEL`.
+pub async fn benchmark(sample: &Path) -> std::io::Result<()> {
+    let len = tokio::fs::metadata(sample).await?.len();
+    let measurements = measure(sample).await?;
+    println!("'{}': {} bytes", sample.display(), len);
+    println!("encoding level        bytes  ratio      ms");
+    for measurement in measurements {
+        #[allow(clippy::cast_precision_loss)]
+        let ratio = measurement.bytes as f64 / len.max(1) as f64;
+        println!(
+            "{:<8} {:<8} {:>10} {:>6.3} {:>7}",
+            measurement.encoding,
+            measurement.level,
+            measurement.bytes,
+            ratio,
+            measurement.elapsed.as_millis()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_level_accepts_names_and_numbers() {
+        assert!(matches!(parse_level("best"), Ok(CompressionLevel::Best)));
+        assert!(matches!(
+            parse_level(" Fastest "),
+            Ok(CompressionLevel::Fastest)
+        ));
+        assert!(matches!(
+            parse_level("default"),
+            Ok(CompressionLevel::Default)
+        ));
+        assert!(matches!(parse_level("5"), Ok(CompressionLevel::Precise(5))));
+        assert_eq!(parse_level("fast").err().as_deref(), Some("fast"));
+    }
+
+    #[tokio::test]
+    async fn benchmark_measures_each_encoding_and_level() {
+        let dir =
+            crate::temp_dir::TempDir::new(&[("sample.txt", &"compressible text ".repeat(1000))]);
+        let measurements = measure(&dir.join("sample.txt")).await.unwrap();
+        assert_eq!(
+            measurements.len(),
+            BENCHMARK_ENCODINGS.len() * BENCHMARK_LEVELS.len()
+        );
+        for encoding in BENCHMARK_ENCODINGS {
+            for (level, _) in BENCHMARK_LEVELS {
+                let measurement = measurements
+                    .iter()
+                    .find(|m| m.encoding == *encoding && m.level == *level)
+                    .unwrap();
+                assert!(measurement.bytes > 0 && measurement.bytes < 18_000);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn shed_under_load_counts_requests_until_the_body_is_dropped() {
+        let in_flight = Arc::new(InFlight::default());
+        let app = axum::Router::new()
+            .route(
+                "/",
+                axum::routing::get(|headers: axum::http::HeaderMap| async move {
+                    headers.contains_key(header::ACCEPT_ENCODING).to_string()
+                }),
+            )
+            .layer(axum::middleware::from_fn_with_state(
+                (in_flight.clone(), 1),
+                shed_under_load,
+            ));
+        let req = || {
+            Request::get("/")
+                .header(header::ACCEPT_ENCODING, "gzip")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let first = app.clone().oneshot(req()).await.unwrap();
+        assert_eq!(in_flight.0.load(Ordering::Relaxed), 1);
+        let second = app.clone().oneshot(req()).await.unwrap();
+        let body = second.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "false");
+        let body = first.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "true");
+        assert_eq!(in_flight.0.load(Ordering::Relaxed), 0);
+        let body = app.oneshot(req()).await.unwrap().into_body();
+        assert_eq!(body.collect().await.unwrap().to_bytes(), "true");
+    }
+
+    /// The compression layers of the site around `ServeDir` with `ETag`s.
+    fn compressed_site(dir: &Path) -> axum::Router {
+        let etags = Arc::new(etag::Etags::new(
+            None,
+            etag::Algo::default(),
+            false,
+            true,
+            true,
+        ));
+        axum::Router::new()
+            .fallback_service(tower_http::services::ServeDir::new(dir))
+            .layer(axum::middleware::from_fn_with_state(
+                (etags, Arc::from(dir)),
+                etag::etag,
+            ))
+            .layer(axum::middleware::from_fn(restore_head))
+            .layer(axum::middleware::from_fn(record_length))
+            .layer(CompressionLayer::new())
+            .layer(axum::middleware::from_fn(weaken_etag))
+            .layer(axum::middleware::from_fn(head_as_get))
+    }
+
+    fn gzip_request(method: Method, uri: &str) -> Request {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn head_reports_the_headers_of_get() {
+        let dir = crate::temp_dir::TempDir::new(&[
+            ("page.txt", &"compressible ".repeat(500)),
+            ("tiny.txt", "tiny"),
+        ]);
+        let app = compressed_site(&dir);
+        for uri in ["/page.txt", "/tiny.txt"] {
+            let head = app
+                .clone()
+                .oneshot(gzip_request(Method::HEAD, uri))
+                .await
+                .unwrap();
+            let get = app
+                .clone()
+                .oneshot(gzip_request(Method::GET, uri))
+                .await
+                .unwrap();
+            for name in [
+                header::CONTENT_ENCODING,
+                header::CONTENT_LENGTH,
+                header::ETAG,
+            ] {
+                assert_eq!(
+                    head.headers().get(&name),
+                    get.headers().get(&name),
+                    "{uri} {name}"
+                );
+            }
+            assert!(head
+                .into_body()
+                .collect()
+                .await
+                .unwrap()
+                .to_bytes()
+                .is_empty());
+        }
+        let head = app
+            .clone()
+            .oneshot(gzip_request(Method::HEAD, "/page.txt"))
+            .await
+            .unwrap();
+        assert_eq!(head.headers()[header::CONTENT_ENCODING], "gzip");
+        let head = app
+            .oneshot(gzip_request(Method::HEAD, "/tiny.txt"))
+            .await
+            .unwrap();
+        assert_eq!(head.headers()[header::CONTENT_LENGTH], "4");
+    }
+
+    #[tokio::test]
+    async fn head_keeps_its_method_for_the_inner_layers() {
+        let dir = crate::temp_dir::TempDir::new(&[("page.txt", &"compressible ".repeat(500))]);
+        let app = compressed_site(&dir);
+        let head = app
+            .clone()
+            .oneshot(gzip_request(Method::HEAD, "/page.txt"))
+            .await
+            .unwrap();
+        assert_eq!(head.headers()["x-cache"], "MISS");
+        let get = app
+            .oneshot(gzip_request(Method::GET, "/page.txt"))
+            .await
+            .unwrap();
+        assert_eq!(get.headers()["x-cache"], "MISS");
+    }
+
+    /// Decodes a `dcb` body compressed with `dictionary`.
+    fn decompress(body: &[u8], dictionary: &[u8]) -> Vec<u8> {
+        assert_eq!(body[..4], DCB_MAGIC);
+        assert_eq!(body[4..36], Sha256::digest(dictionary)[..]);
+        let mut content = Vec::new();
+        brotli_decompressor::BrotliDecompressCustomDict(
+            &mut &body[36..],
+            &mut content,
+            &mut [0; 4096],
+            &mut [0; 4096],
+            dictionary.to_vec(),
+        )
+        .unwrap();
+        content
+    }
+
+    const DICTIONARY: &str = r#"{"id": 0, "name": "", "description": "an item of the catalog", "tags": ["catalog"], "created": "2025-01-01T00:00:00Z"}"#;
+
+    #[test]
+    fn dictionary_output_decodes_with_the_dictionary() {
+        let content = DICTIONARY.replace(r#""id": 0, "name": """#, r#""id": 42, "name": "answer""#);
+        for level in [
+            CompressionLevel::Fastest,
+            CompressionLevel::Default,
+            CompressionLevel::Best,
+            CompressionLevel::Precise(11),
+        ] {
+            let dictionary = Dictionary::new(DICTIONARY.into(), level);
+            let body = dictionary.compress(content.as_bytes()).unwrap();
+            assert_eq!(decompress(&body, DICTIONARY.as_bytes()), content.as_bytes());
+            // The content shared with the dictionary is referenced
+            let plain = Dictionary::new(Vec::new(), level);
+            assert!(body.len() * 3 < plain.compress(content.as_bytes()).unwrap().len() * 2);
+        }
+    }
+
+    fn dictionary_request(method: Method, accept: &str, available: &str) -> Request {
+        Request::builder()
+            .method(method)
+            .uri("/page.json")
+            .header(header::ACCEPT_ENCODING, accept)
+            .header("available-dictionary", available)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn dictionary_compresses_for_clients_with_the_dictionary() {
+        let content = DICTIONARY.replace("\"id\": 0", "\"id\": 1").repeat(4);
+        let dir = crate::temp_dir::TempDir::new(&[("page.json", &content)]);
+        let dictionary = Arc::new(Dictionary::new(
+            DICTIONARY.into(),
+            CompressionLevel::Default,
+        ));
+        let available = dictionary.available.clone();
+        let app = axum::Router::new()
+            .fallback_service(tower_http::services::ServeDir::new(&*dir))
+            .layer(axum::middleware::from_fn(restore_head))
+            .layer(axum::middleware::from_fn_with_state(
+                dictionary,
+                self::dictionary,
+            ))
+            .layer(CompressionLayer::new())
+            .layer(axum::middleware::from_fn(head_as_get));
+
+        let response = app
+            .clone()
+            .oneshot(dictionary_request(Method::GET, "br, dcb", &available))
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "dcb");
+        let vary: Vec<_> = response.headers().get_all(header::VARY).iter().collect();
+        assert_eq!(vary, ["available-dictionary", "accept-encoding"]);
+        let len = response.headers()[header::CONTENT_LENGTH].clone();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(len, body.len().to_string().as_str());
+        assert_eq!(decompress(&body, DICTIONARY.as_bytes()), content.as_bytes());
+
+        let head = app
+            .clone()
+            .oneshot(dictionary_request(Method::HEAD, "br, dcb", &available))
+            .await
+            .unwrap();
+        assert_eq!(head.headers()[header::CONTENT_ENCODING], "dcb");
+        assert!(!head.headers().contains_key(header::CONTENT_LENGTH));
+
+        // Other clients fall back to the compression layer
+        for (accept, available) in [
+            ("br, dcb", ":AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=:"),
+            ("br, dcb;q=0", available.as_str()),
+            ("br", available.as_str()),
+        ] {
+            let response = app
+                .clone()
+                .oneshot(dictionary_request(Method::GET, accept, available))
+                .await
+                .unwrap();
+            assert_eq!(
+                response.headers()[header::CONTENT_ENCODING],
+                "br",
+                "{accept}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn ranges_are_served_from_the_identity_representation() {
+        let content = "compressible ".repeat(500);
+        let dir = crate::temp_dir::TempDir::new(&[("page.txt", &content)]);
+        let app = compressed_site(&dir).layer(axum::middleware::from_fn(identity_for_ranges));
+        let full = app
+            .clone()
+            .oneshot(gzip_request(Method::GET, "/page.txt"))
+            .await
+            .unwrap();
+        assert_eq!(full.headers()[header::CONTENT_ENCODING], "gzip");
+        assert!(full.headers()[header::ETAG].as_bytes().starts_with(b"W/\""));
+
+        let mut req = gzip_request(Method::GET, "/page.txt");
+        req.headers_mut()
+            .insert(header::RANGE, HeaderValue::from_static("bytes=0-11"));
+        let range = app.oneshot(req).await.unwrap();
+        assert_eq!(range.status(), StatusCode::PARTIAL_CONTENT);
+        assert!(!range.headers().contains_key(header::CONTENT_ENCODING));
+        assert!(range.headers()[header::ETAG].as_bytes().starts_with(b"\""));
+        assert_eq!(
+            range.headers()[header::CONTENT_RANGE],
+            format!("bytes 0-11/{}", content.len()).as_str()
+        );
+        let body = range.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "compressible");
+    }
+
+    #[tokio::test]
+    async fn skip_paths_are_served_uncompressed() {
+        let dir = crate::temp_dir::TempDir::new(&[
+            ("page.txt", &"compressible ".repeat(500)),
+            ("archive/page.txt", &"compressible ".repeat(500)),
+        ]);
+        let globs: Arc<[Glob]> = Arc::from(["/archive/**".parse().unwrap()]);
+        let app =
+            compressed_site(&dir).layer(axum::middleware::from_fn_with_state(globs, skip_paths));
+        let response = app
+            .clone()
+            .oneshot(gzip_request(Method::GET, "/page.txt"))
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+        let response = app
+            .oneshot(gzip_request(Method::GET, "/archive/page.txt"))
+            .await
+            .unwrap();
+        assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+    }
+
+    #[tokio::test]
+    async fn byte_budget_bounds_the_files_being_served() {
+        let dir = crate::temp_dir::TempDir::new(&[("a.txt", "aaaa"), ("b.txt", "bbbb")]);
+        let app = |action| {
+            let budget = Arc::new(ByteBudget::new(6, action));
+            let app = axum::Router::new()
+                .fallback_service(tower_http::services::ServeDir::new(&*dir))
+                .layer(axum::middleware::from_fn(
+                    |req: Request, next: Next| async move {
+                        let accepts = req.headers().contains_key(header::ACCEPT_ENCODING);
+                        let mut response = next.run(req).await;
+                        let accepts = HeaderValue::from_static(if accepts { "yes" } else { "no" });
+                        response.headers_mut().insert("x-accepts", accepts);
+                        response
+                    },
+                ))
+                .layer(axum::middleware::from_fn_with_state(
+                    (budget.clone(), Arc::from(&*dir)),
+                    byte_budget,
+                ));
+            (app, budget)
+        };
+
+        let (identity, budget) = app(BudgetAction::Identity);
+        let first = identity
+            .clone()
+            .oneshot(gzip_request(Method::GET, "/a.txt"))
+            .await
+            .unwrap();
+        assert_eq!(first.headers()["x-accepts"], "yes");
+        assert_eq!(budget.in_flight.load(Ordering::Relaxed), 4);
+        let second = identity
+            .clone()
+            .oneshot(gzip_request(Method::GET, "/b.txt"))
+            .await
+            .unwrap();
+        assert_eq!(second.headers()["x-accepts"], "no");
+        assert!(second.extensions().get::<Identity>().is_some());
+        drop(first);
+        assert_eq!(budget.in_flight.load(Ordering::Relaxed), 0);
+
+        let (shed, _budget) = app(BudgetAction::Shed);
+        let _first = shed
+            .clone()
+            .oneshot(gzip_request(Method::GET, "/a.txt"))
+            .await
+            .unwrap();
+        let second = shed
+            .oneshot(gzip_request(Method::GET, "/b.txt"))
+            .await
+            .unwrap();
+        assert_eq!(second.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!("503".parse(), Ok(BudgetAction::Shed));
+    }
+
+    #[tokio::test]
+    async fn byte_budget_serves_a_lone_larger_file() {
+        let dir = crate::temp_dir::TempDir::new(&[("big.txt", "0123456789"), ("a.txt", "a")]);
+        let budget = Arc::new(ByteBudget::new(6, BudgetAction::Shed));
+        let app = axum::Router::new()
+            .fallback_service(tower_http::services::ServeDir::new(&*dir))
+            .layer(axum::middleware::from_fn_with_state(
+                (budget.clone(), Arc::from(&*dir)),
+                byte_budget,
+            ));
+        let big = app
+            .clone()
+            .oneshot(gzip_request(Method::GET, "/big.txt"))
+            .await
+            .unwrap();
+        assert_eq!(big.status(), StatusCode::OK);
+        assert_eq!(budget.in_flight.load(Ordering::Relaxed), 10);
+        let shed = app
+            .clone()
+            .oneshot(gzip_request(Method::GET, "/a.txt"))
+            .await
+            .unwrap();
+        assert_eq!(shed.status(), StatusCode::SERVICE_UNAVAILABLE);
+        drop(big);
+        let a = app
+            .oneshot(gzip_request(Method::GET, "/a.txt"))
+            .await
+            .unwrap();
+        assert_eq!(a.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn zstd_is_negotiated_for_clients_accepting_it() {
+        let dir = crate::temp_dir::TempDir::new(&[("page.txt", &"text ".repeat(100))]);
+        let app = |zstd| {
+            axum::Router::new()
+                .fallback_service(tower_http::services::ServeDir::new(&*dir))
+                .layer(layer(CompressionLevel::Default, zstd))
+        };
+        let encoding = |app: axum::Router, accept: &'static str| async move {
+            let req = Request::get("/page.txt")
+                .header(header::ACCEPT_ENCODING, accept)
+                .body(Body::empty())
+                .unwrap();
+            let response = app.oneshot(req).await.unwrap();
+            let encoding = response.headers().get(header::CONTENT_ENCODING).cloned();
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            (encoding, body)
+        };
+
+        let (zstd, body) = encoding(app(true), "gzip, br, zstd").await;
+        assert_eq!(zstd.unwrap(), "zstd");
+        // A zstd frame starts with its magic number
+        assert!(body.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]));
+        let (gzip, _) = encoding(app(true), "gzip").await;
+        assert_eq!(gzip.unwrap(), "gzip");
+        let (disabled, _) = encoding(app(false), "zstd").await;
+        assert!(disabled.is_none());
+        let (disabled, _) = encoding(app(false), "gzip, zstd").await;
+        assert_eq!(disabled.unwrap(), "gzip");
+    }
+}
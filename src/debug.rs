@@ -0,0 +1,208 @@
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use axum::{
+    extract::{Request, State},
+    http::header,
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{etag::Etags, middleware::Generated};
+
+/// Counters exposed at `/debug/vars`, in the spirit of Go's expvar.
+pub struct Vars {
+    started: Instant,
+    requests: AtomicU64,
+    bytes: AtomicU64,
+    active_connections: AtomicU64,
+}
+
+impl Vars {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            requests: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            active_connections: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Counts the requests and the bytes of the responses with a known length.
+pub async fn count(State(vars): State<Arc<Vars>>, req: Request, next: Next) -> Response {
+    vars.requests.fetch_add(1, Ordering::Relaxed);
+    let response = next.run(req).await;
+    let bytes = response
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.parse::<u64>().ok());
+    if let Some(bytes) = bytes {
+        vars.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+    response
+}
+
+/// Responds with the counters as a JSON object.
+pub async fn vars(
+    State((vars, etags)): State<(Arc<Vars>, Option<Arc<Etags>>)>,
+) -> impl IntoResponse {
+    let (cache_hits, cache_misses, cache_coalesced) =
+        etags.map_or((0, 0, 0), |etags| etags.stats());
+    let body = Json(serde_json::json!({
+        "requests": vars.requests.load(Ordering::Relaxed),
+        "bytes": vars.bytes.load(Ordering::Relaxed),
+        "cache_hits": cache_hits,
+        "cache_misses": cache_misses,
+        "cache_coalesced": cache_coalesced,
+        "active_connections": vars.active_connections.load(Ordering::Relaxed),
+        "uptime_seconds": vars.started.elapsed().as_secs(),
+    }));
+    (Extension(Generated), body)
+}
+
+/// A TCP listener counting the active connections.
+pub struct Listener {
+    inner: TcpListener,
+    vars: Arc<Vars>,
+}
+
+impl Listener {
+    pub const fn new(inner: TcpListener, vars: Arc<Vars>) -> Self {
+        Self { inner, vars }
+    }
+}
+
+impl axum::serve::Listener for Listener {
+    type Io = Connection;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        let (stream, addr) = axum::serve::Listener::accept(&mut self.inner).await;
+        self.vars.active_connections.fetch_add(1, Ordering::Relaxed);
+        let connection = Connection {
+            stream,
+            vars: self.vars.clone(),
+        };
+        (connection, addr)
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        self.inner.local_addr()
+    }
+}
+
+/// A connection accepted by [`Listener`], counted until it is dropped.
+pub struct Connection {
+    stream: TcpStream,
+    vars: Arc<Vars>,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.vars.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, routing::get, Router};
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn vars_count_requests_and_bytes() {
+        let vars = Arc::new(Vars::new());
+        let app = Router::new()
+            .route(
+                "/a",
+                get(|| async { ([(header::CONTENT_LENGTH, "3")], "abc") }),
+            )
+            .route(
+                "/debug/vars",
+                get(self::vars).with_state((vars.clone(), None)),
+            )
+            .layer(axum::middleware::from_fn_with_state(vars, count));
+        for _ in 0..2 {
+            let req = Request::get("/a").body(Body::empty()).unwrap();
+            app.clone().oneshot(req).await.unwrap();
+        }
+        let req = Request::get("/debug/vars").body(Body::empty()).unwrap();
+        let response = app.oneshot(req).await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let vars: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(vars["requests"], 3);
+        assert_eq!(vars["bytes"], 6);
+        assert_eq!(vars["cache_hits"], 0);
+    }
+
+    #[tokio::test]
+    async fn listener_counts_active_connections() {
+        let vars = Arc::new(Vars::new());
+        let inner = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut listener = Listener::new(inner, vars.clone());
+        let addr = axum::serve::Listener::local_addr(&listener).unwrap();
+        let _client = TcpStream::connect(addr).await.unwrap();
+        let (connection, _) = axum::serve::Listener::accept(&mut listener).await;
+        assert_eq!(vars.active_connections.load(Ordering::Relaxed), 1);
+        drop(connection);
+        assert_eq!(vars.active_connections.load(Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,387 @@
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use sha2::{Digest, Sha256};
+
+use crate::etag;
+
+/// The settings of a directory config file, applying to the files below its
+/// directory.
+///
+/// Each line is a `key = value` pair, empty lines and lines starting with `#`
+/// are ignored:
+///
+/// - `cache-control = <value>` sets the `Cache-Control` of successful
+///   responses
+/// - `redirect = <path> <location>` redirects the `path` relative to the
+///   directory to `location` with `308 Permanent Redirect`
+/// - `auth = <user> <sha256>` requires basic authentication as one of the
+///   users, given the hex SHA-256 of the password
+#[derive(Debug, Default)]
+struct DirConfig {
+    cache_control: Option<HeaderValue>,
+    redirects: Vec<(String, HeaderValue)>,
+    users: Vec<(String, String)>,
+}
+
+impl std::str::FromStr for DirConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = Self::default();
+        for (n, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || format!("line {}: invalid '{line}'", n + 1);
+            let (key, value) = line.split_once('=').ok_or_else(invalid)?;
+            let value = value.trim();
+            match key.trim() {
+                "cache-control" if config.cache_control.is_none() => {
+                    config.cache_control =
+                        Some(HeaderValue::from_str(value).map_err(|_| invalid())?);
+                }
+                "redirect" => {
+                    let (path, location) = value.split_once(' ').ok_or_else(invalid)?;
+                    let path = path.trim_start_matches('/').to_string();
+                    let location = HeaderValue::from_str(location.trim()).map_err(|_| invalid())?;
+                    if path.is_empty() || path.split('/').any(|s| s.is_empty() || s == "..") {
+                        return Err(invalid());
+                    }
+                    config.redirects.push((path, location));
+                }
+                "auth" => {
+                    let (user, hash) = value.split_once(' ').ok_or_else(invalid)?;
+                    let hash = hash.trim().to_ascii_lowercase();
+                    if user.is_empty()
+                        || user.contains(':')
+                        || hash.len() != 64
+                        || !hash.bytes().all(|b| b.is_ascii_hexdigit())
+                    {
+                        return Err(invalid());
+                    }
+                    config.users.push((user.to_string(), hash));
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// The directory config files named `name` below a document root.
+///
+/// A file is parsed once per modification time. Deeper files override the
+/// `Cache-Control` and the users of the directories above them.
+pub struct DirConfigs {
+    name: String,
+    parsed: Mutex<HashMap<PathBuf, (SystemTime, Parsed)>>,
+}
+
+type Parsed = Arc<Result<DirConfig, String>>;
+
+impl DirConfigs {
+    pub fn new(name: String) -> Self {
+        Self {
+            name,
+            parsed: Mutex::default(),
+        }
+    }
+
+    /// Returns the config file in `dir`, or `None` if there is none.
+    async fn get(&self, dir: &Path) -> Option<Parsed> {
+        let path = dir.join(&self.name);
+        let modified = tokio::fs::metadata(&path).await.ok()?.modified().ok()?;
+        let cached = self
+            .parsed
+            .lock()
+            .unwrap()
+            .get(&path)
+            .filter(|(m, _)| *m == modified)
+            .map(|(_, config)| config.clone());
+        if cached.is_some() {
+            return cached;
+        }
+
+        let config = match tokio::fs::read_to_string(&path).await {
+            Ok(config) => config.parse(),
+            Err(e) => Err(e.to_string()),
+        };
+        if let Err(e) = &config {
+            tracing::error!("invalid directory config '{}': {}", path.display(), e);
+        }
+        let config = Arc::new(config);
+        self.parsed
+            .lock()
+            .unwrap()
+            .insert(path, (modified, config.clone()));
+        Some(config)
+    }
+}
+
+/// Applies the directory config files of the directories from `root` down to
+/// the requested file.
+///
+/// The config files themselves are never served. An invalid config file
+/// fails the requests below its directory with `500 Internal Server Error`,
+/// and requests without the credentials of a user of a directory requiring
+/// authentication are answered with `401 Unauthorized`.
+pub async fn apply(
+    State((configs, root)): State<(Arc<DirConfigs>, Arc<Path>)>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Some(file) = etag::resolve(&root, req.uri().path()) else {
+        return next.run(req).await;
+    };
+    if file.file_name().is_some_and(|name| *name == *configs.name) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    let Ok(relative) = file.strip_prefix(&root) else {
+        return next.run(req).await;
+    };
+
+    let mut cache_control = None;
+    let mut auth = None;
+    let mut dir = root.to_path_buf();
+    let mut components = relative.components().peekable();
+    loop {
+        if let Some(config) = configs.get(&dir).await {
+            let Ok(config) = &*config else {
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            };
+            if !config.users.is_empty() {
+                auth = Some((dir.clone(), config.users.clone()));
+            }
+            let rest = file.strip_prefix(&dir).unwrap_or(&file);
+            let redirect = config
+                .redirects
+                .iter()
+                .find(|(path, _)| Path::new(path) == rest);
+            if let Some((_, location)) = redirect {
+                if let Some(unauthorized) = unauthorized(&req, &root, auth.as_ref()) {
+                    return unauthorized;
+                }
+                return (
+                    StatusCode::PERMANENT_REDIRECT,
+                    [(header::LOCATION, location.clone())],
+                )
+                    .into_response();
+            }
+            cache_control = config.cache_control.clone().or(cache_control);
+        }
+        match components.next() {
+            // The last component is the file itself
+            Some(component) if components.peek().is_some() => dir.push(component),
+            _ => break,
+        }
+    }
+
+    if let Some(unauthorized) = unauthorized(&req, &root, auth.as_ref()) {
+        return unauthorized;
+    }
+    let mut response = next.run(req).await;
+    if let Some(cache_control) = cache_control {
+        let status = response.status();
+        if status.is_success() || status == StatusCode::NOT_MODIFIED {
+            response
+                .headers_mut()
+                .insert(header::CACHE_CONTROL, cache_control);
+        }
+    }
+    response
+}
+
+/// Checks the basic authentication of `req` against the users of the deepest
+/// directory below `root` requiring it, if any, returning the response to an
+/// unauthorized request.
+fn unauthorized(
+    req: &Request,
+    root: &Path,
+    auth: Option<&(PathBuf, Vec<(String, String)>)>,
+) -> Option<Response> {
+    let (dir, users) = auth?;
+    let credentials = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("basic"))
+        .and_then(|(_, credentials)| BASE64_STANDARD.decode(credentials.trim()).ok())
+        .and_then(|credentials| String::from_utf8(credentials).ok());
+    let authorized = credentials
+        .as_deref()
+        .and_then(|credentials| credentials.split_once(':'))
+        .is_some_and(|(user, password)| {
+            let hash = Sha256::digest(password)
+                .iter()
+                .fold(String::new(), |mut hex, b| {
+                    let _ = write!(hex, "{b:02x}");
+                    hex
+                });
+            users.iter().any(|(u, h)| u == user && *h == hash)
+        });
+    if authorized {
+        return None;
+    }
+
+    let realm = format!("/{}", dir.strip_prefix(root).unwrap_or(dir).display())
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"");
+    let challenge = HeaderValue::from_str(&format!("Basic realm=\"{realm}\", charset=\"UTF-8\""))
+        .unwrap_or_else(|_| HeaderValue::from_static("Basic"));
+    Some(
+        (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, challenge)],
+        )
+            .into_response(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, Router};
+    use tower::ServiceExt;
+    use tower_http::services::ServeDir;
+
+    use super::*;
+    use crate::temp_dir::TempDir;
+
+    /// The SHA-256 of "secret".
+    const SECRET: &str = "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b";
+
+    fn app(dir: &Path) -> Router {
+        let configs = Arc::new(DirConfigs::new(".webserver".to_string()));
+        Router::new().fallback_service(ServeDir::new(dir)).layer(
+            axum::middleware::from_fn_with_state((configs, Arc::from(dir)), apply),
+        )
+    }
+
+    async fn get(app: &Router, uri: &str, authorization: Option<&str>) -> Response {
+        let mut req = Request::get(uri);
+        if let Some(authorization) = authorization {
+            req = req.header(header::AUTHORIZATION, authorization);
+        }
+        app.clone()
+            .oneshot(req.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn parse_is_strict() {
+        let config: DirConfig = "# comment\n\ncache-control = no-cache\nredirect = old new\n"
+            .parse()
+            .unwrap();
+        assert_eq!(config.cache_control.unwrap(), "no-cache");
+        assert_eq!(config.redirects[0].0, "old");
+        for invalid in [
+            "cache-control",
+            "cache = no-cache",
+            "cache-control = a\ncache-control = b",
+            "redirect = old",
+            "redirect = ../old new",
+            "auth = alice",
+            "auth = alice secret",
+            "auth = al:ice 2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b",
+        ] {
+            assert!(invalid.parse::<DirConfig>().is_err(), "{invalid}");
+        }
+    }
+
+    #[tokio::test]
+    async fn cache_control_applies_to_the_subtree() {
+        let dir = TempDir::new(&[
+            ("index.txt", "root"),
+            ("docs/.webserver", "cache-control = max-age=60"),
+            ("docs/a.txt", "a"),
+            ("docs/deep/.webserver", "cache-control = no-cache"),
+            ("docs/deep/b.txt", "b"),
+            ("docs/deep/more/c.txt", "c"),
+        ]);
+        let app = app(&dir);
+        let root = get(&app, "/index.txt", None).await;
+        assert!(!root.headers().contains_key(header::CACHE_CONTROL));
+        for (uri, cache_control) in [
+            ("/docs/a.txt", "max-age=60"),
+            ("/docs/deep/b.txt", "no-cache"),
+            ("/docs/deep/more/c.txt", "no-cache"),
+        ] {
+            let response = get(&app, uri, None).await;
+            assert_eq!(response.status(), StatusCode::OK, "{uri}");
+            assert_eq!(response.headers()[header::CACHE_CONTROL], cache_control);
+        }
+        let missing = get(&app, "/docs/missing.txt", None).await;
+        assert!(!missing.headers().contains_key(header::CACHE_CONTROL));
+        let config = get(&app, "/docs/.webserver", None).await;
+        assert_eq!(config.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn redirects_and_invalid_configs() {
+        let dir = TempDir::new(&[
+            ("docs/.webserver", "redirect = old.txt /docs/new.txt"),
+            ("broken/.webserver", "unknown = key"),
+            ("broken/a.txt", "a"),
+        ]);
+        let app = app(&dir);
+        let response = get(&app, "/docs/old.txt", None).await;
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(response.headers()[header::LOCATION], "/docs/new.txt");
+        let response = get(&app, "/broken/a.txt", None).await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn auth_requires_the_credentials_of_a_user() {
+        let dir = TempDir::new(&[
+            ("public.txt", "public"),
+            (
+                "private/.webserver",
+                &format!("auth = alice {SECRET}\nredirect = old.txt /private/a.txt"),
+            ),
+            ("private/a.txt", "a"),
+            ("private/open/.webserver", "auth = bob 00"),
+        ]);
+        let app = app(&dir);
+        assert_eq!(
+            get(&app, "/public.txt", None).await.status(),
+            StatusCode::OK
+        );
+
+        let alice = format!("Basic {}", BASE64_STANDARD.encode("alice:secret"));
+        let wrong = format!("basic {}", BASE64_STANDARD.encode("alice:wrong"));
+        for authorization in [None, Some(wrong.as_str()), Some("Bearer token")] {
+            let response = get(&app, "/private/a.txt", authorization).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+            assert_eq!(
+                response.headers()[header::WWW_AUTHENTICATE],
+                "Basic realm=\"/private\", charset=\"UTF-8\""
+            );
+        }
+        let response = get(&app, "/private/old.txt", None).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = get(&app, "/private/a.txt", Some(&alice)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = get(&app, "/private/old.txt", Some(&alice)).await;
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        // An invalid config below denies the subtree
+        let response = get(&app, "/private/open/a.txt", Some(&alice)).await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
@@ -0,0 +1,162 @@
+use std::{
+    convert::Infallible,
+    future::Future,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use axum::{extract::Request, response::Response, Router};
+use tower::{Service, ServiceExt};
+
+type Build = dyn Fn(&Path) -> Router + Send + Sync;
+
+/// Serves the document root at the target its path currently resolves to.
+///
+/// The path is resolved once per request, so atomically swapping a symlinked
+/// document root takes effect without a restart while every request is served
+/// entirely from either the old or the new target. If the path cannot be
+/// resolved to a directory, e.g. in the middle of a non-atomic swap, the last
+/// good target is kept.
+#[derive(Clone)]
+pub struct Resolving {
+    dir: PathBuf,
+    build: Arc<Build>,
+    current: Arc<Mutex<(PathBuf, Router)>>,
+}
+
+impl Resolving {
+    pub fn new<F>(dir: impl Into<PathBuf>, build: F) -> Self
+    where
+        F: Fn(&Path) -> Router + Send + Sync + 'static,
+    {
+        let dir = dir.into();
+        let target = std::fs::canonicalize(&dir).unwrap_or_else(|_| dir.clone());
+        let router = build(&target);
+
+        tracing::info!("document root resolves to '{}'", target.display());
+        Self {
+            dir,
+            build: Arc::new(build),
+            current: Arc::new(Mutex::new((target, router))),
+        }
+    }
+
+    async fn router(&self) -> Router {
+        match tokio::fs::canonicalize(&self.dir).await {
+            Ok(target) if tokio::fs::metadata(&target).await.is_ok_and(|m| m.is_dir()) => {
+                let mut current = self.current.lock().unwrap();
+                if current.0 != target {
+                    tracing::info!("document root switched to '{}'", target.display());
+                    let router = (self.build)(&target);
+                    *current = (target, router);
+                }
+                current.1.clone()
+            }
+            _ => {
+                let current = self.current.lock().unwrap();
+                tracing::warn!(
+                    "failed to resolve '{}', keeping '{}'",
+                    self.dir.display(),
+                    current.0.display()
+                );
+                current.1.clone()
+            }
+        }
+    }
+}
+
+impl Service<Request> for Resolving {
+    type Response = Response;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let this = self.clone();
+        Box::pin(async move { this.router().await.oneshot(req).await })
+    }
+}
+
+/// Opens the document root at startup and returns a path to the opened
+/// directory, which keeps being served from even if `dir` is later renamed,
+/// removed or replaced.
+#[cfg(target_os = "linux")]
+pub fn snapshot(dir: &Path) -> std::io::Result<PathBuf> {
+    use std::os::fd::IntoRawFd;
+
+    let file = std::fs::File::open(dir)?;
+    if !file.metadata()?.is_dir() {
+        return Err(std::io::Error::other(format!(
+            "'{}' is not a directory",
+            dir.display()
+        )));
+    }
+    // The directory stays open for the lifetime of the server
+    let fd = file.into_raw_fd();
+    Ok(PathBuf::from(format!("/proc/self/fd/{fd}")))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn snapshot(_dir: &Path) -> std::io::Result<PathBuf> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "snapshots of the document root are only supported on Linux",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use http_body_util::BodyExt;
+    use tower_http::services::ServeDir;
+
+    use super::*;
+    use crate::temp_dir::TempDir;
+
+    async fn get(service: Resolving, uri: &str) -> String {
+        let req = Request::get(uri).body(Body::empty()).unwrap();
+        let body = service.oneshot(req).await.unwrap().into_body();
+        String::from_utf8(body.collect().await.unwrap().to_bytes().to_vec()).unwrap()
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn resolving_follows_symlink_swaps() {
+        let dir = TempDir::new(&[("a/f.txt", "a"), ("b/f.txt", "b")]);
+        let current = dir.join("current");
+        std::os::unix::fs::symlink(dir.join("a"), &current).unwrap();
+        let service = Resolving::new(&current, |target| {
+            Router::new().fallback_service(ServeDir::new(target))
+        });
+        assert_eq!(get(service.clone(), "/f.txt").await, "a");
+
+        let next = dir.join("next");
+        std::os::unix::fs::symlink(dir.join("b"), &next).unwrap();
+        std::fs::rename(&next, &current).unwrap();
+        assert_eq!(get(service.clone(), "/f.txt").await, "b");
+
+        // The last good target is kept while the root cannot be resolved
+        std::fs::remove_file(&current).unwrap();
+        assert_eq!(get(service, "/f.txt").await, "b");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn snapshot_keeps_serving_the_opened_directory() {
+        let dir = TempDir::new(&[("root/f.txt", "old"), ("new/f.txt", "new")]);
+        let root = dir.join("root");
+        let opened = snapshot(&root).unwrap();
+        std::fs::rename(&root, dir.join("old")).unwrap();
+        std::fs::rename(dir.join("new"), &root).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(opened.join("f.txt")).unwrap(),
+            "old"
+        );
+        assert!(snapshot(&root.join("f.txt")).is_err());
+    }
+}
@@ -0,0 +1,220 @@
+use std::{
+    fmt::Write,
+    path::Path,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+
+/// Characters not allowed unencoded in an RFC 8187 extended parameter value.
+const ATTR_CHAR: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'!')
+    .remove(b'#')
+    .remove(b'$')
+    .remove(b'&')
+    .remove(b'+')
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'^')
+    .remove(b'_')
+    .remove(b'`')
+    .remove(b'|')
+    .remove(b'~');
+
+/// A part of a download filename template.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    Basename,
+    Ext,
+    Date,
+}
+
+/// Template of the filename of forced downloads, e.g. "{basename}-{date}.{ext}".
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template(Vec<Part>);
+
+impl std::str::FromStr for Template {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = Vec::new();
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(Part::Text(rest[..start].to_string()));
+            }
+            let end = rest[start..].find('}').ok_or_else(|| s.to_string())? + start;
+            parts.push(match &rest[start + 1..end] {
+                "basename" => Part::Basename,
+                "ext" => Part::Ext,
+                "date" => Part::Date,
+                _ => return Err(s.to_string()),
+            });
+            rest = &rest[end + 1..];
+        }
+        if rest.contains('}') {
+            return Err(s.to_string());
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.to_string()));
+        }
+        Ok(Self(parts))
+    }
+}
+
+impl Template {
+    /// Renders the filename of `path` downloaded at `now`.
+    fn render(&self, path: &Path, now: SystemTime) -> String {
+        let basename = path.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+        let ext = path.extension().and_then(|s| s.to_str()).unwrap_or("");
+        let mut name = String::new();
+        for part in &self.0 {
+            match part {
+                Part::Text(text) => name.push_str(text),
+                Part::Basename => name.push_str(basename),
+                Part::Ext => name.push_str(ext),
+                Part::Date => {
+                    let (year, month, day) = civil_date(now);
+                    let _ = write!(name, "{year:04}-{month:02}-{day:02}");
+                }
+            }
+        }
+        name
+    }
+}
+
+/// Returns the UTC calendar date of `time`.
+fn civil_date(time: SystemTime) -> (i64, u32, u32) {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    #[allow(clippy::cast_possible_wrap)]
+    let days = (secs / 86_400) as i64;
+    // Converts days since 1970-01-01 to a proleptic Gregorian date, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Files with the given extensions are downloaded instead of displayed.
+pub struct Downloads {
+    pub extensions: Arc<[String]>,
+    pub template: Template,
+}
+
+/// Builds a `Content-Disposition: attachment` value for `filename`.
+///
+/// The plain `filename` parameter is restricted to ASCII for old clients, the
+/// full name is sent percent-encoded in `filename*`.
+fn content_disposition(filename: &str) -> Option<HeaderValue> {
+    let ascii = filename
+        .chars()
+        .map(|c| match c {
+            '"' | '\\' => '_',
+            c if c.is_ascii() && !c.is_ascii_control() => c,
+            _ => '_',
+        })
+        .collect::<String>();
+    let encoded = utf8_percent_encode(filename, ATTR_CHAR);
+    HeaderValue::from_str(&format!(
+        "attachment; filename=\"{ascii}\"; filename*=UTF-8''{encoded}"
+    ))
+    .ok()
+}
+
+/// Sends files with a download extension as attachments named after the
+/// template.
+pub async fn attachment(
+    State(downloads): State<Arc<Downloads>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let path = percent_encoding::percent_decode_str(req.uri().path())
+        .decode_utf8_lossy()
+        .into_owned();
+    let path = Path::new(&path);
+    let download = path.extension().is_some_and(|ext| {
+        downloads
+            .extensions
+            .iter()
+            .any(|download| ext.eq_ignore_ascii_case(download))
+    });
+    let mut response = next.run(req).await;
+    if download
+        && matches!(
+            response.status(),
+            StatusCode::OK | StatusCode::PARTIAL_CONTENT
+        )
+    {
+        let filename = downloads.template.render(path, SystemTime::now());
+        if let Some(value) = content_disposition(&filename) {
+            response
+                .headers_mut()
+                .insert(header::CONTENT_DISPOSITION, value);
+        }
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::{body::Body, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[test]
+    fn template_renders_the_parts() {
+        let template = "{basename}-{date}.{ext}".parse::<Template>().unwrap();
+        // 2000-02-29T23:59:59Z
+        let now = UNIX_EPOCH + Duration::from_secs(951_868_799);
+        assert_eq!(
+            template.render(Path::new("/files/report.pdf"), now),
+            "report-2000-02-29.pdf"
+        );
+        assert_eq!(civil_date(UNIX_EPOCH), (1970, 1, 1));
+        for invalid in ["{name}", "{basename", "basename}"] {
+            assert_eq!(invalid.parse::<Template>(), Err(invalid.to_string()));
+        }
+    }
+
+    #[tokio::test]
+    async fn attachment_names_downloads_after_the_template() {
+        let downloads = Downloads {
+            extensions: Arc::from(["pdf".to_string()]),
+            template: "{basename}.{ext}".parse().unwrap(),
+        };
+        let app = Router::new().fallback(|| async { "file" }).layer(
+            axum::middleware::from_fn_with_state(Arc::new(downloads), attachment),
+        );
+        let get = |uri: &str| {
+            app.clone()
+                .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+        };
+        let response = get("/r%C3%A9sum%C3%A9.PDF").await.unwrap();
+        assert_eq!(
+            response.headers()[header::CONTENT_DISPOSITION],
+            "attachment; filename=\"r_sum_.PDF\"; filename*=UTF-8''r%C3%A9sum%C3%A9.PDF"
+        );
+        let response = get("/index.html").await.unwrap();
+        assert!(!response.headers().contains_key(header::CONTENT_DISPOSITION));
+    }
+}
@@ -0,0 +1,560 @@
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    io::Read,
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use sha2::{Digest, Sha256, Sha512};
+use tokio::sync::OnceCell;
+
+static X_CACHE: HeaderName = HeaderName::from_static("x-cache");
+
+/// The hash algorithm of content-hash `ETag`s.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Algo {
+    #[default]
+    Sha256,
+    Sha512,
+}
+
+impl std::str::FromStr for Algo {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().replace('-', "").as_str() {
+            "sha256" => Ok(Self::Sha256),
+            "sha512" => Ok(Self::Sha512),
+            _ => Err(s.to_string()),
+        }
+    }
+}
+
+/// Content-hash `ETag`s of the files in the document root.
+///
+/// An `ETag` only depends on the bytes of a file, so replicas serving the same
+/// files send the same `ETag`s. The hash of a file is computed once per
+/// modification time and length.
+/// Concurrent requests for the same file share a single computation. If a `ttl`
+/// is set, hashes are recomputed once they are older, which catches changes on
+/// file systems with a coarse modification time.
+///
+/// Unless `head_warms` is set, `HEAD` requests do not store the hashes they
+/// compute. With `head_parity` unset, they only use hashes already computed
+/// for a `GET` and are sent without an `ETag` otherwise. With `status_header`
+/// set, responses tell whether their hash was cached with `X-Cache: HIT` or
+/// `X-Cache: MISS`.
+#[derive(Default)]
+pub struct Etags {
+    cache: Mutex<HashMap<PathBuf, Entry>>,
+    ttl: Option<Duration>,
+    algo: Algo,
+    head_warms: bool,
+    head_parity: bool,
+    status_header: bool,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    coalesced: AtomicU64,
+}
+
+struct Entry {
+    modified: SystemTime,
+    len: u64,
+    created: Instant,
+    etag: Arc<OnceCell<HeaderValue>>,
+    waiters: Arc<AtomicUsize>,
+}
+
+impl Entry {
+    fn new(modified: SystemTime, len: u64) -> Self {
+        Self {
+            modified,
+            len,
+            created: Instant::now(),
+            etag: Arc::default(),
+            waiters: Arc::default(),
+        }
+    }
+}
+
+impl Etags {
+    pub fn new(
+        ttl: Option<Duration>,
+        algo: Algo,
+        head_warms: bool,
+        head_parity: bool,
+        status_header: bool,
+    ) -> Self {
+        Self {
+            cache: Mutex::default(),
+            ttl,
+            algo,
+            head_warms,
+            head_parity,
+            status_header,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            coalesced: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the number of cache hits, misses and requests that waited for
+    /// the computation of another request.
+    pub fn stats(&self) -> (u64, u64, u64) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+            self.coalesced.load(Ordering::Relaxed),
+        )
+    }
+
+    fn is_expired(&self, entry: &Entry) -> bool {
+        self.ttl.is_some_and(|ttl| entry.created.elapsed() >= ttl)
+    }
+
+    /// Returns the `ETag` of `path` and whether it was cached, computing it if
+    /// necessary and `compute` is set. A computed `ETag` is only cached if
+    /// `store` is set.
+    async fn get(
+        &self,
+        path: &Path,
+        compute: bool,
+        store: bool,
+    ) -> std::io::Result<Option<(HeaderValue, bool)>> {
+        let meta = match tokio::fs::metadata(path).await {
+            Ok(meta) if meta.is_file() => meta,
+            Ok(_) => return Ok(None),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let modified = meta.modified()?;
+        let len = meta.len();
+        let entry = {
+            let mut cache = self.cache.lock().unwrap();
+            let fresh = cache.get(path).is_some_and(|entry| {
+                entry.modified == modified && entry.len == len && !self.is_expired(entry)
+            });
+            let counter = if fresh { &self.hits } else { &self.misses };
+            counter.fetch_add(1, Ordering::Relaxed);
+            if !compute {
+                let etag = fresh.then(|| cache[path].etag.get().cloned()).flatten();
+                return Ok(etag.map(|etag| (etag, true)));
+            }
+            if !fresh && store {
+                if self.ttl.is_some() {
+                    cache.retain(|_, entry| !self.is_expired(entry));
+                }
+                cache.insert(path.to_path_buf(), Entry::new(modified, len));
+            }
+            let entry = (fresh || store).then(|| {
+                let entry = &cache[path];
+                (entry.etag.clone(), entry.waiters.clone(), fresh)
+            });
+            drop(cache);
+            entry
+        };
+        let path = path.to_path_buf();
+        let algo = self.algo;
+        let Some((cell, waiters, cached)) = entry else {
+            let etag = tokio::task::spawn_blocking(move || hash_file(&path, algo))
+                .await
+                .map_err(std::io::Error::other)??;
+            return Ok(Some((etag, false)));
+        };
+
+        if let Some(etag) = cell.get() {
+            return Ok(Some((etag.clone(), cached)));
+        }
+        let waiter = Waiter::new(&waiters);
+        if waiter.coalesced {
+            self.coalesced.fetch_add(1, Ordering::Relaxed);
+            #[cfg(feature = "metrics")]
+            metrics::counter!("http_coalesced_requests_total").increment(1);
+        }
+        let etag = cell
+            .get_or_try_init(|| async move {
+                tokio::task::spawn_blocking(move || hash_file(&path, algo))
+                    .await
+                    .map_err(std::io::Error::other)?
+            })
+            .await?;
+        drop(waiter);
+        Ok(Some((etag.clone(), cached)))
+    }
+}
+
+/// A request waiting for the computation of an `ETag`, which is coalesced if
+/// another request already computes it.
+struct Waiter<'a> {
+    waiters: &'a AtomicUsize,
+    coalesced: bool,
+}
+
+impl<'a> Waiter<'a> {
+    fn new(waiters: &'a AtomicUsize) -> Self {
+        let coalesced = waiters.fetch_add(1, Ordering::Relaxed) > 0;
+        #[cfg(feature = "metrics")]
+        if coalesced {
+            metrics::gauge!("http_cache_singleflight_waiters").increment(1);
+        }
+        Self { waiters, coalesced }
+    }
+}
+
+impl Drop for Waiter<'_> {
+    fn drop(&mut self) {
+        self.waiters.fetch_sub(1, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        if self.coalesced {
+            metrics::gauge!("http_cache_singleflight_waiters").decrement(1);
+        }
+    }
+}
+
+fn hash_file(path: &Path, algo: Algo) -> std::io::Result<HeaderValue> {
+    match algo {
+        Algo::Sha256 => hash_with::<Sha256>(path),
+        Algo::Sha512 => hash_with::<Sha512>(path),
+    }
+}
+
+fn hash_with<D: Digest>(path: &Path) -> std::io::Result<HeaderValue> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = D::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+
+    Ok(from_digest(&hasher.finalize()))
+}
+
+/// Formats the first 128 bits of a digest as a strong `ETag`.
+pub fn from_digest(digest: &[u8]) -> HeaderValue {
+    let hex = digest[..16].iter().fold(String::new(), |mut hex, b| {
+        let _ = write!(hex, "{b:02x}");
+        hex
+    });
+    HeaderValue::from_str(&format!("\"{hex}\"")).expect("hex is a valid header value")
+}
+
+/// Maps the request path to a file below `root` the way `ServeDir` does,
+/// returning `None` for paths escaping the root.
+pub fn resolve(root: &Path, path: &str) -> Option<PathBuf> {
+    let decoded = percent_encoding::percent_decode_str(path.trim_start_matches('/'))
+        .decode_utf8()
+        .ok()?;
+    let mut resolved = root.to_path_buf();
+    for component in Path::new(&*decoded).components() {
+        match component {
+            Component::Normal(segment) => resolved.push(segment),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    if path.ends_with('/') {
+        resolved.push("index.html");
+    }
+    Some(resolved)
+}
+
+/// Returns the entity tag of the `If-None-Match` lists in `headers` matching
+/// `etag`, or `etag` itself for `*`, or `None` if none matches.
+///
+/// `If-None-Match` uses the weak comparison, so the weakened `ETag` of a
+/// compressed response matches as well.
+pub fn matching(headers: &HeaderMap, etag: &HeaderValue) -> Option<HeaderValue> {
+    for list in headers.get_all(header::IF_NONE_MATCH) {
+        let mut rest = list.as_bytes();
+        loop {
+            rest = rest.trim_ascii_start();
+            match rest {
+                [] => break,
+                [b',', tail @ ..] => rest = tail,
+                [b'*', ..] => return Some(etag.clone()),
+                _ => {
+                    let start = rest;
+                    let opaque = rest.strip_prefix(b"W/").unwrap_or(rest);
+                    // The opaque tag is quoted and may contain commas
+                    let Some(len) = opaque
+                        .strip_prefix(b"\"")
+                        .and_then(|tag| tag.iter().position(|&b| b == b'"'))
+                    else {
+                        break;
+                    };
+                    let (opaque, tail) = opaque.split_at(len + 2);
+                    if opaque == etag.as_bytes() {
+                        let matched = &start[..start.len() - tail.len()];
+                        return HeaderValue::from_bytes(matched).ok();
+                    }
+                    rest = tail;
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Adds content-hash `ETag`s to files served from `root` and answers matching
+/// `If-None-Match` requests with `304 Not Modified`.
+pub async fn etag(
+    State((etags, root)): State<(Arc<Etags>, Arc<Path>)>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return next.run(req).await;
+    }
+    let Some(path) = resolve(&root, req.uri().path()) else {
+        return next.run(req).await;
+    };
+    let store = req.method() == Method::GET || etags.head_warms;
+    let compute = store || etags.head_parity;
+    let (etag, cached) = match etags.get(&path, compute, store).await {
+        Ok(Some(etag)) => etag,
+        Ok(None) => return next.run(req).await,
+        Err(e) => {
+            tracing::warn!("failed to compute etag of '{}': {}", path.display(), e);
+            return next.run(req).await;
+        }
+    };
+
+    tracing::debug!(
+        "etag of '{}' {}",
+        path.display(),
+        if cached { "cached" } else { "computed" }
+    );
+    let mut response = conditional(req, next, etag).await;
+    if etags.status_header {
+        let status = HeaderValue::from_static(if cached { "HIT" } else { "MISS" });
+        response.headers_mut().insert(X_CACHE.clone(), status);
+    }
+    response
+}
+
+/// Answers matching `If-None-Match` requests with `304 Not Modified` and adds
+/// the `ETag` to successful responses.
+async fn conditional(mut req: Request, next: Next, etag: HeaderValue) -> Response {
+    if let Some(matched) = matching(req.headers(), &etag) {
+        return (StatusCode::NOT_MODIFIED, [(header::ETAG, matched)]).into_response();
+    }
+    if req.headers().contains_key(header::IF_NONE_MATCH) {
+        // If-None-Match takes precedence over If-Modified-Since
+        req.headers_mut().remove(header::IF_MODIFIED_SINCE);
+    }
+
+    let mut response = next.run(req).await;
+    if matches!(
+        response.status(),
+        StatusCode::OK | StatusCode::PARTIAL_CONTENT
+    ) {
+        response.headers_mut().insert(header::ETAG, etag);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, Router};
+    use tower::ServiceExt;
+    use tower_http::services::ServeDir;
+
+    use super::*;
+    use crate::temp_dir::TempDir;
+
+    fn app(dir: &Path, etags: Etags) -> Router {
+        Router::new().fallback_service(ServeDir::new(dir)).layer(
+            axum::middleware::from_fn_with_state((Arc::new(etags), Arc::from(dir)), etag),
+        )
+    }
+
+    async fn send(
+        app: &Router,
+        method: Method,
+        uri: &str,
+        if_none_match: Option<&str>,
+    ) -> Response {
+        let mut req = Request::builder().method(method).uri(uri);
+        if let Some(if_none_match) = if_none_match {
+            req = req.header(header::IF_NONE_MATCH, if_none_match);
+        }
+        app.clone()
+            .oneshot(req.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn etag_is_the_hash_of_the_content() {
+        let dir = TempDir::new(&[("a.txt", "same"), ("b.txt", "same"), ("c.txt", "other")]);
+        let app = app(&dir, Etags::new(None, Algo::Sha256, false, false, false));
+        let a = send(&app, Method::GET, "/a.txt", None).await;
+        let etag = a.headers()[header::ETAG].clone();
+        assert_eq!(etag, from_digest(&Sha256::digest("same")));
+        let b = send(&app, Method::GET, "/b.txt", None).await;
+        assert_eq!(b.headers()[header::ETAG], etag);
+        let c = send(&app, Method::GET, "/c.txt", None).await;
+        assert_ne!(c.headers()[header::ETAG], etag);
+
+        let etag = etag.to_str().unwrap();
+        let response = send(&app, Method::GET, "/a.txt", Some(etag)).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[header::ETAG], etag);
+        let response = send(&app, Method::GET, "/c.txt", Some(etag)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = send(&app, Method::GET, "/missing.txt", None).await;
+        assert!(!response.headers().contains_key(header::ETAG));
+    }
+
+    #[tokio::test]
+    async fn concurrent_requests_share_one_computation() {
+        let dir = TempDir::new(&[("a.txt", "a")]);
+        let etags = Arc::new(Etags::new(None, Algo::Sha256, false, false, false));
+        let path = dir.join("a.txt");
+        let lookups = (0..8).map(|_| {
+            let etags = etags.clone();
+            let path = path.clone();
+            tokio::spawn(async move { etags.get(&path, true, true).await.unwrap() })
+        });
+        for lookup in lookups {
+            let (etag, _) = lookup.await.unwrap().unwrap();
+            assert_eq!(etag, from_digest(&Sha256::digest("a")));
+        }
+        let (hits, misses, _) = etags.stats();
+        assert_eq!((hits, misses), (7, 1));
+
+        // A modified file is hashed again
+        std::fs::write(&path, "changed").unwrap();
+        let (etag, cached) = etags.get(&path, true, true).await.unwrap().unwrap();
+        assert_eq!(etag, from_digest(&Sha256::digest("changed")));
+        assert!(!cached);
+    }
+
+    #[tokio::test]
+    async fn etags_older_than_the_ttl_are_recomputed() {
+        let dir = TempDir::new(&[("a.txt", "a")]);
+        let path = dir.join("a.txt");
+        let expiring = Etags::new(Some(Duration::ZERO), Algo::Sha256, false, false, false);
+        let kept = Etags::new(
+            Some(Duration::from_secs(30)),
+            Algo::Sha256,
+            false,
+            false,
+            false,
+        );
+        for _ in 0..3 {
+            assert!(!expiring.get(&path, true, true).await.unwrap().unwrap().1);
+            kept.get(&path, true, true).await.unwrap();
+        }
+        assert_eq!(expiring.stats(), (0, 3, 0));
+        assert_eq!(kept.stats(), (2, 1, 0));
+    }
+
+    #[tokio::test]
+    async fn head_requests_only_store_etags_if_warming() {
+        let dir = TempDir::new(&[("a.txt", "a")]);
+        let expected = from_digest(&Sha256::digest("a"));
+        let warming = Arc::new(Etags::new(None, Algo::Sha256, true, false, false));
+        let cold = Arc::new(Etags::new(None, Algo::Sha256, false, true, false));
+        for etags in [&warming, &cold] {
+            let app = Router::new().fallback_service(ServeDir::new(&*dir)).layer(
+                axum::middleware::from_fn_with_state((etags.clone(), Arc::from(&*dir)), etag),
+            );
+            for _ in 0..2 {
+                let response = send(&app, Method::HEAD, "/a.txt", None).await;
+                assert_eq!(response.headers()[header::ETAG], expected);
+            }
+        }
+        // The second HEAD request of the cold cache computed the hash again
+        assert_eq!(warming.stats(), (1, 1, 0));
+        assert_eq!(cold.stats(), (0, 2, 0));
+    }
+
+    #[test]
+    fn waiters_after_the_first_are_coalesced() {
+        let waiters = AtomicUsize::new(0);
+        let first = Waiter::new(&waiters);
+        let second = Waiter::new(&waiters);
+        assert!(!first.coalesced);
+        assert!(second.coalesced);
+        drop((first, second));
+        assert_eq!(waiters.load(Ordering::Relaxed), 0);
+        assert!(!Waiter::new(&waiters).coalesced);
+    }
+
+    #[tokio::test]
+    async fn the_status_header_tells_whether_the_etag_was_cached() {
+        let dir = TempDir::new(&[("a.txt", "a")]);
+        let marked = app(&dir, Etags::new(None, Algo::Sha256, false, false, true));
+        let response = send(&marked, Method::GET, "/a.txt", None).await;
+        assert_eq!(response.headers()[&X_CACHE], "MISS");
+        let response = send(&marked, Method::GET, "/a.txt", None).await;
+        assert_eq!(response.headers()[&X_CACHE], "HIT");
+
+        let unmarked = app(&dir, Etags::new(None, Algo::Sha256, false, false, false));
+        let response = send(&unmarked, Method::GET, "/a.txt", None).await;
+        assert!(!response.headers().contains_key(&X_CACHE));
+    }
+
+    #[tokio::test]
+    async fn head_requests_without_parity_only_use_cached_etags() {
+        let dir = TempDir::new(&[("a.txt", "a")]);
+        let app = app(&dir, Etags::new(None, Algo::Sha256, false, false, false));
+        let response = send(&app, Method::HEAD, "/a.txt", None).await;
+        assert!(!response.headers().contains_key(header::ETAG));
+        let get = send(&app, Method::GET, "/a.txt", None).await;
+        let response = send(&app, Method::HEAD, "/a.txt", None).await;
+        assert_eq!(
+            response.headers()[header::ETAG],
+            get.headers()[header::ETAG]
+        );
+    }
+
+    #[tokio::test]
+    async fn the_algo_selects_the_content_hash() {
+        assert_eq!("SHA-512".parse(), Ok(Algo::Sha512));
+        assert_eq!("md5".parse::<Algo>(), Err("md5".to_string()));
+
+        let dir = TempDir::new(&[("a.txt", "a")]);
+        let app = app(&dir, Etags::new(None, Algo::Sha512, false, false, false));
+        let response = send(&app, Method::GET, "/a.txt", None).await;
+        assert_eq!(
+            response.headers()[header::ETAG],
+            from_digest(&Sha512::digest("a"))
+        );
+    }
+
+    #[test]
+    fn matching_searches_every_list_of_entity_tags() {
+        let etag = HeaderValue::from_static("\"a,b\"");
+        let headers = |lists: &[&'static str]| {
+            let mut headers = HeaderMap::new();
+            for list in lists {
+                headers.append(header::IF_NONE_MATCH, HeaderValue::from_static(list));
+            }
+            headers
+        };
+        let matched = |lists| matching(&headers(lists), &etag);
+        assert_eq!(matched(&["\"x\", \"a,b\""]).unwrap(), "\"a,b\"");
+        assert_eq!(matched(&["\"x\"", " W/\"a,b\" "]).unwrap(), "W/\"a,b\"");
+        assert_eq!(matched(&["*"]).unwrap(), etag);
+        assert_eq!(matched(&["\"x\", \"a\""]), None);
+        assert_eq!(matched(&["\"a,b"]), None);
+        assert_eq!(matched(&[]), None);
+    }
+}
@@ -0,0 +1,160 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+
+use crate::glob::Glob;
+
+/// Who may embed a page in a frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FramePolicy {
+    /// No page may embed it.
+    Deny,
+    /// Only pages of the same origin may embed it.
+    SameOrigin,
+    /// Only pages of the given space-separated origins may embed it.
+    Allow(String),
+}
+
+impl std::str::FromStr for FramePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.eq_ignore_ascii_case("deny") {
+            Ok(Self::Deny)
+        } else if s.eq_ignore_ascii_case("sameorigin") {
+            Ok(Self::SameOrigin)
+        } else if !s.is_empty() && HeaderValue::from_str(s).is_ok() && !s.contains([';', ',']) {
+            Ok(Self::Allow(s.to_string()))
+        } else {
+            Err(s.to_string())
+        }
+    }
+}
+
+impl FramePolicy {
+    /// Returns the `X-Frame-Options` and the `frame-ancestors` directive of the
+    /// `Content-Security-Policy` enforcing the policy.
+    ///
+    /// `X-Frame-Options` cannot name origins, so only `frame-ancestors` is sent
+    /// to allow specific origins.
+    fn headers(&self) -> (Option<HeaderValue>, HeaderValue) {
+        match self {
+            Self::Deny => (
+                Some(HeaderValue::from_static("DENY")),
+                HeaderValue::from_static("frame-ancestors 'none'"),
+            ),
+            Self::SameOrigin => (
+                Some(HeaderValue::from_static("SAMEORIGIN")),
+                HeaderValue::from_static("frame-ancestors 'self'"),
+            ),
+            Self::Allow(origins) => (
+                None,
+                HeaderValue::from_str(&format!("frame-ancestors {origins}"))
+                    .expect("origins are a valid header value"),
+            ),
+        }
+    }
+}
+
+/// Parses a comma-separated list of `glob=policy` overrides, e.g.
+/// "/embed/**=SAMEORIGIN".
+pub fn parse_overrides(s: &str) -> Result<Arc<[(Glob, FramePolicy)]>, String> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (glob, policy) = entry.split_once('=').ok_or_else(|| entry.to_string())?;
+            Ok((glob.trim().parse()?, policy.parse()?))
+        })
+        .collect()
+}
+
+/// The frame policy of the site and its overrides by path.
+pub struct Frames {
+    pub default: Option<FramePolicy>,
+    pub overrides: Arc<[(Glob, FramePolicy)]>,
+}
+
+/// Sets `X-Frame-Options` and `Content-Security-Policy: frame-ancestors`
+/// according to the policy of the first override matching the path, or the
+/// default policy.
+pub async fn frame_options(
+    State(frames): State<Arc<Frames>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let policy = frames
+        .overrides
+        .iter()
+        .find(|(glob, _)| glob.matches(req.uri().path()))
+        .map(|(_, policy)| policy)
+        .or(frames.default.as_ref())
+        .map(FramePolicy::headers);
+    let mut response = next.run(req).await;
+    if let Some((frame_options, frame_ancestors)) = policy {
+        let headers = response.headers_mut();
+        if let Some(frame_options) = frame_options {
+            headers.insert(header::X_FRAME_OPTIONS, frame_options);
+        }
+        headers.append(header::CONTENT_SECURITY_POLICY, frame_ancestors);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[test]
+    fn parse_overrides_requires_globs_and_policies() {
+        let overrides =
+            parse_overrides("/embed/**=sameorigin, /widget/*=https://a.example").unwrap();
+        assert_eq!(overrides[0].1, FramePolicy::SameOrigin);
+        assert_eq!(
+            overrides[1].1,
+            FramePolicy::Allow("https://a.example".to_string())
+        );
+        for invalid in ["/embed/**", "embed=DENY", "/embed=", "/embed=a;b"] {
+            assert!(parse_overrides(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[tokio::test]
+    async fn frame_options_apply_the_first_matching_policy() {
+        let frames = Frames {
+            default: Some(FramePolicy::Deny),
+            overrides: parse_overrides("/embed/**=https://a.example,/embed/**=SAMEORIGIN").unwrap(),
+        };
+        let app = Router::new()
+            .fallback(|| async {})
+            .layer(axum::middleware::from_fn_with_state(
+                Arc::new(frames),
+                frame_options,
+            ));
+        let get = |uri: &str| {
+            app.clone()
+                .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+        };
+        let response = get("/index.html").await.unwrap();
+        assert_eq!(response.headers()[header::X_FRAME_OPTIONS], "DENY");
+        assert_eq!(
+            response.headers()[header::CONTENT_SECURITY_POLICY],
+            "frame-ancestors 'none'"
+        );
+        let response = get("/embed/page.html").await.unwrap();
+        assert!(!response.headers().contains_key(header::X_FRAME_OPTIONS));
+        assert_eq!(
+            response.headers()[header::CONTENT_SECURITY_POLICY],
+            "frame-ancestors https://a.example"
+        );
+    }
+}
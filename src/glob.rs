@@ -0,0 +1,81 @@
+use std::sync::Arc;
+
+/// A glob pattern matching request paths.
+///
+/// `*` matches any characters within a path segment, `**` matches across
+/// segments and `?` matches a single character other than `/`. E.g.
+/// "/precompressed/**" matches every path below "/precompressed/".
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Glob(String);
+
+impl std::str::FromStr for Glob {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with('/') {
+            Ok(Self(s.to_string()))
+        } else {
+            Err(s.to_string())
+        }
+    }
+}
+
+impl Glob {
+    pub fn matches(&self, path: &str) -> bool {
+        matches(self.0.as_bytes(), path.as_bytes())
+    }
+}
+
+/// Parses a comma-separated list of globs.
+pub fn parse_list(s: &str) -> Result<Arc<[Glob]>, String> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|glob| !glob.is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+fn matches(pattern: &[u8], path: &[u8]) -> bool {
+    match pattern {
+        [] => path.is_empty(),
+        [b'*', b'*', rest @ ..] => (0..=path.len()).any(|i| matches(rest, &path[i..])),
+        [b'*', rest @ ..] => {
+            let segment = path.iter().position(|&b| b == b'/').unwrap_or(path.len());
+            (0..=segment).any(|i| matches(rest, &path[i..]))
+        }
+        [b'?', rest @ ..] => path
+            .split_first()
+            .is_some_and(|(&b, path)| b != b'/' && matches(rest, path)),
+        [c, rest @ ..] => path
+            .split_first()
+            .is_some_and(|(b, path)| b == c && matches(rest, path)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn glob(s: &str) -> Glob {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn globs_match_within_and_across_segments() {
+        assert!(glob("/precompressed/**").matches("/precompressed/a/b.gz"));
+        assert!(!glob("/precompressed/**").matches("/other/a.gz"));
+        assert!(glob("/*.zip").matches("/a.zip"));
+        assert!(!glob("/*.zip").matches("/dir/a.zip"));
+        assert!(glob("/**/*.zip").matches("/dir/sub/a.zip"));
+        assert!(glob("/file?.txt").matches("/file1.txt"));
+        assert!(!glob("/file?.txt").matches("/file/.txt"));
+        assert!(!glob("/file?.txt").matches("/file.txt"));
+    }
+
+    #[test]
+    fn parse_list_requires_absolute_globs() {
+        let globs = parse_list(" /a/**, ,/b.txt ").unwrap();
+        assert_eq!(&*globs, [glob("/a/**"), glob("/b.txt")]);
+        assert_eq!(parse_list("/a,b/*"), Err("b/*".to_string()));
+    }
+}
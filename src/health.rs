@@ -0,0 +1,189 @@
+use std::{path::PathBuf, sync::Arc};
+
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Extension, Json};
+
+use crate::middleware::Generated;
+
+/// Readiness of the site to serve requests.
+pub struct Readiness {
+    dir: PathBuf,
+    min_free_disk: u64,
+}
+
+impl Readiness {
+    pub const fn new(dir: PathBuf, min_free_disk: u64) -> Self {
+        Self { dir, min_free_disk }
+    }
+
+    /// Checks that the document root is a directory on a file system with at
+    /// least the minimum free space, returning the reason if not.
+    async fn check(&self) -> Result<(), String> {
+        self.check_dir().await?;
+        self.check_disk().await
+    }
+
+    /// Checks that the document root is a directory.
+    async fn check_dir(&self) -> Result<(), String> {
+        match tokio::fs::metadata(&self.dir).await {
+            Ok(meta) if meta.is_dir() => Ok(()),
+            _ => Err(format!("'{}' is not a directory", self.dir.display())),
+        }
+    }
+
+    /// Checks that the file system of the document root has at least the
+    /// minimum free space.
+    async fn check_disk(&self) -> Result<(), String> {
+        if self.min_free_disk > 0 {
+            let dir = self.dir.clone();
+            let free = tokio::task::spawn_blocking(move || free_disk(&dir))
+                .await
+                .map_err(|e| e.to_string())?
+                .map_err(|e| format!("failed to stat '{}': {}", self.dir.display(), e))?;
+            if free < self.min_free_disk {
+                return Err(format!(
+                    "{} bytes free, {} required",
+                    free, self.min_free_disk
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the space available to unprivileged users on the file system
+/// containing `path`.
+#[cfg(unix)]
+fn free_disk(path: &std::path::Path) -> std::io::Result<u64> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `path` is a valid C string and `stat` is only read on success.
+    let stat = unsafe {
+        if libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        stat.assume_init()
+    };
+    #[allow(clippy::unnecessary_cast, clippy::useless_conversion)]
+    Ok(u64::from(stat.f_bavail) * u64::from(stat.f_frsize))
+}
+
+#[cfg(not(unix))]
+fn free_disk(_path: &std::path::Path) -> std::io::Result<u64> {
+    Ok(u64::MAX)
+}
+
+/// Responds with `200 OK` if the site is ready, `503 Service Unavailable`
+/// otherwise.
+pub async fn ready(State(readiness): State<Arc<Readiness>>) -> impl IntoResponse {
+    let (status, body) = match readiness.check().await {
+        Ok(()) => (StatusCode::OK, "ready".to_string()),
+        Err(reason) => {
+            tracing::warn!("not ready: {}", reason);
+            (StatusCode::SERVICE_UNAVAILABLE, reason)
+        }
+    };
+    (status, Extension(Generated), body)
+}
+
+/// Responds with the result of each check as a JSON object, with `200 OK` if
+/// all of them pass, `503 Service Unavailable` otherwise.
+pub async fn deep(State(readiness): State<Arc<Readiness>>) -> impl IntoResponse {
+    let checks = [
+        ("docroot", readiness.check_dir().await),
+        ("disk", readiness.check_disk().await),
+    ];
+    let healthy = checks.iter().all(|(_, result)| result.is_ok());
+    let checks = checks
+        .into_iter()
+        .map(|(name, result)| {
+            let check = match result {
+                Ok(()) => serde_json::json!({ "healthy": true }),
+                Err(reason) => {
+                    tracing::warn!("{} unhealthy: {}", name, reason);
+                    serde_json::json!({ "healthy": false, "reason": reason })
+                }
+            };
+            (name.to_string(), check)
+        })
+        .collect::<serde_json::Map<_, _>>();
+    let status = if healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let body = Json(serde_json::json!({ "healthy": healthy, "checks": checks }));
+    (status, Extension(Generated), body)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::to_bytes;
+
+    use super::*;
+    use crate::temp_dir::TempDir;
+
+    async fn check(readiness: Readiness) -> (StatusCode, String) {
+        let response = ready(State(Arc::new(readiness))).await.into_response();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn ready_checks_the_docroot_and_free_disk() {
+        let dir = TempDir::new(&[("index.html", "")]);
+        assert_eq!(
+            check(Readiness::new(dir.to_path_buf(), 1)).await,
+            (StatusCode::OK, "ready".to_string())
+        );
+
+        let (status, reason) = check(Readiness::new(dir.join("index.html"), 0)).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(reason.ends_with("is not a directory"), "{reason}");
+
+        #[cfg(unix)]
+        {
+            let (status, reason) = check(Readiness::new(dir.to_path_buf(), u64::MAX)).await;
+            assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+            assert!(
+                reason.ends_with(&format!("{} required", u64::MAX)),
+                "{reason}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn deep_reports_each_check() {
+        let dir = TempDir::new(&[("index.html", "")]);
+        let report = |readiness: Readiness| async move {
+            let response = deep(State(Arc::new(readiness))).await.into_response();
+            let status = response.status();
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            (
+                status,
+                serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+            )
+        };
+
+        let (status, body) = report(Readiness::new(dir.to_path_buf(), 0)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "healthy": true,
+                "checks": { "docroot": { "healthy": true }, "disk": { "healthy": true } },
+            })
+        );
+
+        let (status, body) = report(Readiness::new(dir.join("missing"), 0)).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["healthy"], false);
+        assert_eq!(body["checks"]["docroot"]["healthy"], false);
+        assert!(body["checks"]["docroot"]["reason"]
+            .as_str()
+            .unwrap()
+            .ends_with("is not a directory"));
+    }
+}
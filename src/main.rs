@@ -1,15 +1,48 @@
 #![allow(clippy::multiple_crate_versions)]
 
+mod access_log;
+#[cfg(feature = "bundle")]
+mod bundle;
+#[cfg(test)]
+mod captured;
+mod compression;
+mod debug;
+mod dir_config;
+mod docroot;
+mod download;
+mod etag;
+mod frame;
+mod glob;
+mod health;
+mod middleware;
+mod open_files;
+mod precompressed;
+mod prerender;
+mod rewrite;
+mod save_data;
+mod slow_fs;
+#[cfg(test)]
+mod temp_dir;
+mod trace_context;
+mod well_known;
+
 use std::{
-    net::{IpAddr, SocketAddr},
+    net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6},
     path::Path,
     str::FromStr,
-    sync::LazyLock,
+    sync::{Arc, LazyLock},
     time::Duration,
 };
 
-use axum::Router;
-use tokio::{net::TcpListener, signal};
+use axum::{
+    handler::HandlerWithoutStateExt,
+    http::{header, HeaderValue, StatusCode},
+    response::{Html, IntoResponse},
+    routing::{get, get_service},
+    serve::ListenerExt,
+    Extension, Router,
+};
+use tokio::{net::TcpListener, signal, sync::watch};
 use tower_http::{
     services::{ServeDir, ServeFile},
     timeout::TimeoutLayer,
@@ -17,7 +50,11 @@ use tower_http::{
 };
 
 #[cfg(feature = "metrics")]
-use axum::{extract::Request, middleware::Next, response::IntoResponse};
+use axum::{extract::Request, middleware::Next};
+#[cfg(feature = "metrics")]
+use tower_http::compression::CompressionLayer;
+
+use crate::middleware::{Environment, HttpsPolicy, RangeAction};
 
 static ENV_PREFIX: LazyLock<String> = LazyLock::new(|| env!("CARGO_CRATE_NAME").to_uppercase());
 static SERVER_LOG: LazyLock<String> = LazyLock::new(|| format!("{}_LOG", &*ENV_PREFIX));
@@ -26,15 +63,204 @@ static SERVER_PORT: LazyLock<String> = LazyLock::new(|| format!("{}_PORT", &*ENV
 static SERVER_DIR: LazyLock<String> = LazyLock::new(|| format!("{}_DIR", &*ENV_PREFIX));
 static SERVER_404: LazyLock<String> = LazyLock::new(|| format!("{}_404", &*ENV_PREFIX));
 static SERVER_TIMEOUT: LazyLock<String> = LazyLock::new(|| format!("{}_TIMEOUT", &*ENV_PREFIX));
+static SERVER_TRACEPARENT: LazyLock<String> =
+    LazyLock::new(|| format!("{}_TRACEPARENT", &*ENV_PREFIX));
+static SERVER_BIND_RETRIES: LazyLock<String> =
+    LazyLock::new(|| format!("{}_BIND_RETRIES", &*ENV_PREFIX));
+static SERVER_BIND_RETRY_DELAY: LazyLock<String> =
+    LazyLock::new(|| format!("{}_BIND_RETRY_DELAY", &*ENV_PREFIX));
+static SERVER_REQUIRE_HTTPS: LazyLock<String> =
+    LazyLock::new(|| format!("{}_REQUIRE_HTTPS", &*ENV_PREFIX));
+static SERVER_BUFFER_SMALL: LazyLock<String> =
+    LazyLock::new(|| format!("{}_BUFFER_SMALL", &*ENV_PREFIX));
+static SERVER_VALIDATE_HOST: LazyLock<String> =
+    LazyLock::new(|| format!("{}_VALIDATE_HOST", &*ENV_PREFIX));
+static SERVER_VALIDATE_TRANSFER_ENCODING: LazyLock<String> =
+    LazyLock::new(|| format!("{}_VALIDATE_TRANSFER_ENCODING", &*ENV_PREFIX));
+static SERVER_MAX_PATH_DEPTH: LazyLock<String> =
+    LazyLock::new(|| format!("{}_MAX_PATH_DEPTH", &*ENV_PREFIX));
+static SERVER_DIR_RESOLVE: LazyLock<String> =
+    LazyLock::new(|| format!("{}_DIR_RESOLVE", &*ENV_PREFIX));
+static SERVER_LOG_FIELDS: LazyLock<String> =
+    LazyLock::new(|| format!("{}_LOG_FIELDS", &*ENV_PREFIX));
+static SERVER_REJECT_EXPECT: LazyLock<String> =
+    LazyLock::new(|| format!("{}_REJECT_EXPECT", &*ENV_PREFIX));
+static SERVER_ETAG: LazyLock<String> = LazyLock::new(|| format!("{}_ETAG", &*ENV_PREFIX));
+static SERVER_BASE_PATH: LazyLock<String> = LazyLock::new(|| format!("{}_BASE_PATH", &*ENV_PREFIX));
+static SERVER_READY_PATH: LazyLock<String> =
+    LazyLock::new(|| format!("{}_READY_PATH", &*ENV_PREFIX));
+static SERVER_MIN_FREE_DISK: LazyLock<String> =
+    LazyLock::new(|| format!("{}_MIN_FREE_DISK", &*ENV_PREFIX));
+static SERVER_CACHE_TTL: LazyLock<String> = LazyLock::new(|| format!("{}_CACHE_TTL", &*ENV_PREFIX));
+static SERVER_HEAD_WARMS_CACHE: LazyLock<String> =
+    LazyLock::new(|| format!("{}_HEAD_WARMS_CACHE", &*ENV_PREFIX));
+static SERVER_SW_ALLOWED: LazyLock<String> =
+    LazyLock::new(|| format!("{}_SW_ALLOWED", &*ENV_PREFIX));
+static SERVER_SW_PATHS: LazyLock<String> = LazyLock::new(|| format!("{}_SW_PATHS", &*ENV_PREFIX));
+static SERVER_COMPRESSION: LazyLock<String> =
+    LazyLock::new(|| format!("{}_COMPRESSION", &*ENV_PREFIX));
+static SERVER_MAX_RANGES: LazyLock<String> =
+    LazyLock::new(|| format!("{}_MAX_RANGES", &*ENV_PREFIX));
+static SERVER_MAX_RANGES_ACTION: LazyLock<String> =
+    LazyLock::new(|| format!("{}_MAX_RANGES_ACTION", &*ENV_PREFIX));
 
+static SERVER_DEV_NOCACHE: LazyLock<String> =
+    LazyLock::new(|| format!("{}_DEV_NOCACHE", &*ENV_PREFIX));
+static SERVER_JSON_PRETTY: LazyLock<String> =
+    LazyLock::new(|| format!("{}_JSON_PRETTY", &*ENV_PREFIX));
+static SERVER_JSON_PRETTY_MAX_SIZE: LazyLock<String> =
+    LazyLock::new(|| format!("{}_JSON_PRETTY_MAX_SIZE", &*ENV_PREFIX));
+static SERVER_REJECT_BODIES: LazyLock<String> =
+    LazyLock::new(|| format!("{}_REJECT_BODIES", &*ENV_PREFIX));
+static SERVER_DEBUG: LazyLock<String> = LazyLock::new(|| format!("{}_DEBUG", &*ENV_PREFIX));
+static SERVER_MAX_RESPONSE_TIME: LazyLock<String> =
+    LazyLock::new(|| format!("{}_MAX_RESPONSE_TIME", &*ENV_PREFIX));
+static SERVER_DOWNLOAD_EXTENSIONS: LazyLock<String> =
+    LazyLock::new(|| format!("{}_DOWNLOAD_EXTENSIONS", &*ENV_PREFIX));
+static SERVER_DOWNLOAD_NAME_TEMPLATE: LazyLock<String> =
+    LazyLock::new(|| format!("{}_DOWNLOAD_NAME_TEMPLATE", &*ENV_PREFIX));
+static SERVER_SAVE_DATA: LazyLock<String> = LazyLock::new(|| format!("{}_SAVE_DATA", &*ENV_PREFIX));
+static SERVER_COMPRESS_SKIP_PATHS: LazyLock<String> =
+    LazyLock::new(|| format!("{}_COMPRESS_SKIP_PATHS", &*ENV_PREFIX));
+static SERVER_PORT_FILE: LazyLock<String> = LazyLock::new(|| format!("{}_PORT_FILE", &*ENV_PREFIX));
+static SERVER_STRICT_ACCEPT: LazyLock<String> =
+    LazyLock::new(|| format!("{}_STRICT_ACCEPT", &*ENV_PREFIX));
+static SERVER_FRAME_OPTIONS: LazyLock<String> =
+    LazyLock::new(|| format!("{}_FRAME_OPTIONS", &*ENV_PREFIX));
+static SERVER_FRAME_OVERRIDES: LazyLock<String> =
+    LazyLock::new(|| format!("{}_FRAME_OVERRIDES", &*ENV_PREFIX));
+static SERVER_SERVE_DELAY_MS: LazyLock<String> =
+    LazyLock::new(|| format!("{}_SERVE_DELAY_MS", &*ENV_PREFIX));
+static SERVER_COMPRESS_LOAD_THRESHOLD: LazyLock<String> =
+    LazyLock::new(|| format!("{}_COMPRESS_LOAD_THRESHOLD", &*ENV_PREFIX));
+static SERVER_CACHE_STATUS_HEADER: LazyLock<String> =
+    LazyLock::new(|| format!("{}_CACHE_STATUS_HEADER", &*ENV_PREFIX));
+static SERVER_PRECOMPRESSED: LazyLock<String> =
+    LazyLock::new(|| format!("{}_PRECOMPRESSED", &*ENV_PREFIX));
+static SERVER_PRECOMPRESSED_VALIDATE: LazyLock<String> =
+    LazyLock::new(|| format!("{}_PRECOMPRESSED_VALIDATE", &*ENV_PREFIX));
+static SERVER_ENV: LazyLock<String> = LazyLock::new(|| format!("{}_ENV", &*ENV_PREFIX));
+static SERVER_HEAD_ETAG_PARITY: LazyLock<String> =
+    LazyLock::new(|| format!("{}_HEAD_ETAG_PARITY", &*ENV_PREFIX));
+static SERVER_BR_FALLBACK: LazyLock<String> =
+    LazyLock::new(|| format!("{}_BR_FALLBACK", &*ENV_PREFIX));
+static SERVER_DIR_CONFIG: LazyLock<String> =
+    LazyLock::new(|| format!("{}_DIR_CONFIG", &*ENV_PREFIX));
+static SERVER_MAX_INFLIGHT_BYTES: LazyLock<String> =
+    LazyLock::new(|| format!("{}_MAX_INFLIGHT_BYTES", &*ENV_PREFIX));
+static SERVER_MAX_INFLIGHT_BYTES_ACTION: LazyLock<String> =
+    LazyLock::new(|| format!("{}_MAX_INFLIGHT_BYTES_ACTION", &*ENV_PREFIX));
+static SERVER_REWRITES: LazyLock<String> = LazyLock::new(|| format!("{}_REWRITES", &*ENV_PREFIX));
+static SERVER_ETAG_ALGO: LazyLock<String> = LazyLock::new(|| format!("{}_ETAG_ALGO", &*ENV_PREFIX));
+static SERVER_DEEP_HEALTH_PATH: LazyLock<String> =
+    LazyLock::new(|| format!("{}_DEEP_HEALTH_PATH", &*ENV_PREFIX));
+static SERVER_SLOW_FS_MS: LazyLock<String> =
+    LazyLock::new(|| format!("{}_SLOW_FS_MS", &*ENV_PREFIX));
+static SERVER_ACCEPT_CH: LazyLock<String> = LazyLock::new(|| format!("{}_ACCEPT_CH", &*ENV_PREFIX));
+static SERVER_CRITICAL_CH: LazyLock<String> =
+    LazyLock::new(|| format!("{}_CRITICAL_CH", &*ENV_PREFIX));
+static SERVER_MAX_OPEN_FILES: LazyLock<String> =
+    LazyLock::new(|| format!("{}_MAX_OPEN_FILES", &*ENV_PREFIX));
+static SERVER_MAX_OPEN_FILES_WAIT_MS: LazyLock<String> =
+    LazyLock::new(|| format!("{}_MAX_OPEN_FILES_WAIT_MS", &*ENV_PREFIX));
+static SERVER_CLEAR_SITE_DATA_PATHS: LazyLock<String> =
+    LazyLock::new(|| format!("{}_CLEAR_SITE_DATA_PATHS", &*ENV_PREFIX));
+static SERVER_CLEAR_SITE_DATA: LazyLock<String> =
+    LazyLock::new(|| format!("{}_CLEAR_SITE_DATA", &*ENV_PREFIX));
+static SERVER_STRICT_NAMES: LazyLock<String> =
+    LazyLock::new(|| format!("{}_STRICT_NAMES", &*ENV_PREFIX));
+static SERVER_SPIKE_THRESHOLD: LazyLock<String> =
+    LazyLock::new(|| format!("{}_SPIKE_THRESHOLD", &*ENV_PREFIX));
+static SERVER_CSP_NONCE: LazyLock<String> = LazyLock::new(|| format!("{}_CSP_NONCE", &*ENV_PREFIX));
+static SERVER_WELL_KNOWN: LazyLock<String> =
+    LazyLock::new(|| format!("{}_WELL_KNOWN", &*ENV_PREFIX));
+static SERVER_STATUS_REDIRECTS: LazyLock<String> =
+    LazyLock::new(|| format!("{}_STATUS_REDIRECTS", &*ENV_PREFIX));
+static SERVER_MAX_HEADER_COUNT: LazyLock<String> =
+    LazyLock::new(|| format!("{}_MAX_HEADER_COUNT", &*ENV_PREFIX));
+static SERVER_ACME_WEBROOT: LazyLock<String> =
+    LazyLock::new(|| format!("{}_ACME_WEBROOT", &*ENV_PREFIX));
+static SERVER_PRERENDER: LazyLock<String> = LazyLock::new(|| format!("{}_PRERENDER", &*ENV_PREFIX));
+static SERVER_PRERENDER_DIR: LazyLock<String> =
+    LazyLock::new(|| format!("{}_PRERENDER_DIR", &*ENV_PREFIX));
+static SERVER_TEMPLATE_MAX_SIZE: LazyLock<String> =
+    LazyLock::new(|| format!("{}_TEMPLATE_MAX_SIZE", &*ENV_PREFIX));
+static SERVER_DRAIN_REJECTED: LazyLock<String> =
+    LazyLock::new(|| format!("{}_DRAIN_REJECTED", &*ENV_PREFIX));
+static SERVER_COMPRESSION_LEVEL: LazyLock<String> =
+    LazyLock::new(|| format!("{}_COMPRESSION_LEVEL", &*ENV_PREFIX));
+static SERVER_COMPRESSION_ZSTD: LazyLock<String> =
+    LazyLock::new(|| format!("{}_COMPRESSION_ZSTD", &*ENV_PREFIX));
+static SERVER_BR_DICTIONARY: LazyLock<String> =
+    LazyLock::new(|| format!("{}_BR_DICTIONARY", &*ENV_PREFIX));
 const DEFAULT_DIR: &str = "public";
 const DEFAULT_404: &str = "404.html";
+const DEFAULT_404_BODY: &str = "<!DOCTYPE html>\n<html><head><title>404 Not Found</title></head>\
+    <body><h1>404 Not Found</h1></body></html>\n";
 const DEFAULT_TIMEOUT: &str = "0"; // no timeout
+const DEFAULT_TRACEPARENT: &str = "false";
+const DEFAULT_BIND_RETRIES: &str = "0"; // fail on first error
+const DEFAULT_BIND_RETRY_DELAY: &str = "500"; // milliseconds
+const DEFAULT_BUFFER_SMALL: &str = "0"; // no buffering
+const DEFAULT_VALIDATE_HOST: &str = "true";
+const DEFAULT_VALIDATE_TRANSFER_ENCODING: &str = "true";
+const DEFAULT_MAX_PATH_DEPTH: &str = "32";
+const DEFAULT_DIR_RESOLVE: &str = "startup";
+const DEFAULT_REJECT_EXPECT: &str = "false";
+const DEFAULT_ETAG: &str = "false";
+const DEFAULT_MIN_FREE_DISK: &str = "0"; // no check
+const DEFAULT_CACHE_TTL: &str = "0"; // no expiry
+const DEFAULT_HEAD_WARMS_CACHE: &str = "true";
+const DEFAULT_COMPRESSION: &str = "false";
+const DEFAULT_MAX_RANGES: &str = "0"; // no limit
+const DEFAULT_MAX_RANGES_ACTION: &str = "reject";
+const DEFAULT_DEV_NOCACHE: &str = "false";
+const DEFAULT_JSON_PRETTY: &str = "false";
+const DEFAULT_JSON_PRETTY_MAX_SIZE: &str = "1048576";
+const DEFAULT_REJECT_BODIES: &str = "true";
+const DEFAULT_DEBUG: &str = "false";
+const DEFAULT_MAX_RESPONSE_TIME: &str = "0"; // no limit
+const DEFAULT_DOWNLOAD_NAME_TEMPLATE: &str = "{basename}.{ext}";
+const DEFAULT_SAVE_DATA: &str = "false";
+const DEFAULT_STRICT_ACCEPT: &str = "false";
+const DEFAULT_SERVE_DELAY_MS: &str = "0"; // no delay
+const DEFAULT_COMPRESS_LOAD_THRESHOLD: &str = "0"; // no threshold
+const DEFAULT_CACHE_STATUS_HEADER: &str = "false";
+const DEFAULT_PRECOMPRESSED: &str = "false";
+const DEFAULT_PRECOMPRESSED_VALIDATE: &str = "false";
+const DEFAULT_ENV: &str = "production";
+const DEFAULT_HEAD_ETAG_PARITY: &str = "true";
+const DEFAULT_BR_FALLBACK: &str = "false";
+const DEFAULT_MAX_INFLIGHT_BYTES: &str = "0"; // no budget
+const DEFAULT_MAX_INFLIGHT_BYTES_ACTION: &str = "identity";
+const DEFAULT_ETAG_ALGO: &str = "sha256";
+const DEFAULT_SLOW_FS_MS: &str = "0"; // no logging
+const DEFAULT_MAX_OPEN_FILES: &str = "0"; // no limit
+const DEFAULT_MAX_OPEN_FILES_WAIT_MS: &str = "100";
+const DEFAULT_CLEAR_SITE_DATA: &str = "\"cache\", \"cookies\", \"storage\"";
+const DEFAULT_STRICT_NAMES: &str = "false";
+const DEFAULT_SPIKE_THRESHOLD: &str = "0"; // no detection
+const DEFAULT_CSP_NONCE: &str = "false";
+const DEFAULT_MAX_HEADER_COUNT: &str = "0"; // no limit
+const DEFAULT_PRERENDER_DIR: &str = "prerendered";
+const DEFAULT_TEMPLATE_MAX_SIZE: &str = "1048576";
+const DEFAULT_DRAIN_REJECTED: &str = "0"; // no draining
+const DEFAULT_COMPRESSION_LEVEL: &str = "default";
+const DEFAULT_COMPRESSION_ZSTD: &str = "true";
 
 #[cfg(feature = "metrics")]
 static METRICS_ADDR: LazyLock<String> = LazyLock::new(|| "METRICS_ADDR".to_string());
 #[cfg(feature = "metrics")]
 static METRICS_PORT: LazyLock<String> = LazyLock::new(|| "METRICS_PORT".to_string());
+#[cfg(feature = "metrics")]
+static METRICS_PATH: LazyLock<String> = LazyLock::new(|| "METRICS_PATH".to_string());
+#[cfg(feature = "metrics")]
+static METRICS_COMPRESSION: LazyLock<String> = LazyLock::new(|| "METRICS_COMPRESSION".to_string());
+
+#[cfg(feature = "metrics")]
+const DEFAULT_METRICS_PATH: &str = "/metrics";
+#[cfg(feature = "metrics")]
+const DEFAULT_METRICS_COMPRESSION: &str = "true";
 
 #[tokio::main]
 async fn main() {
@@ -50,42 +276,727 @@ async fn main() {
         .with_target(false)
         .init();
 
+    let mut args = std::env::args().skip(1);
+    if args.next().as_deref() == Some("--benchmark-compression") {
+        let Some(sample) = args.next() else {
+            tracing::error!("usage: webserver --benchmark-compression <file>");
+            std::process::exit(2);
+        };
+        if let Err(e) = compression::benchmark(Path::new(&sample)).await {
+            tracing::error!("failed to benchmark '{}': {}", sample, e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let shutdown = install_shutdown_signals();
+
     #[cfg(not(feature = "metrics"))]
     {
-        start_site_server().await;
+        start_site_server(shutdown).await;
     }
 
     #[cfg(feature = "metrics")]
     {
-        let (_site, _metrics) = tokio::join!(start_site_server(), start_metrics_server());
+        let (_site, _metrics) = tokio::join!(
+            start_site_server(shutdown.clone()),
+            start_metrics_server(shutdown)
+        );
     }
 }
 
-#[allow(clippy::cognitive_complexity)]
-fn site_app() -> Result<Router, Error> {
+#[allow(clippy::cognitive_complexity, clippy::too_many_lines)]
+fn site_app(vars: Option<Arc<debug::Vars>>) -> Result<Router, Error> {
     let timeout = std::env::var(&*SERVER_TIMEOUT)
         .unwrap_or_else(|_| DEFAULT_TIMEOUT.into())
         .parse::<u64>()
         .map_err(Error::Timeout)?;
     let timeout = Duration::from_millis(timeout);
+    let max_response_time = std::env::var(&*SERVER_MAX_RESPONSE_TIME)
+        .unwrap_or_else(|_| DEFAULT_MAX_RESPONSE_TIME.into())
+        .parse::<u64>()
+        .map_err(Error::MaxResponseTime)?;
+    let max_response_time = Duration::from_millis(max_response_time);
+    let spike_threshold = std::env::var(&*SERVER_SPIKE_THRESHOLD)
+        .unwrap_or_else(|_| DEFAULT_SPIKE_THRESHOLD.into())
+        .parse::<u64>()
+        .map_err(Error::SpikeThreshold)?;
+    let require_https = match std::env::var(&*SERVER_REQUIRE_HTTPS) {
+        Ok(policy) if !policy.is_empty() && policy != "off" => {
+            Some(policy.parse::<HttpsPolicy>().map_err(Error::RequireHttps)?)
+        }
+        _ => None,
+    };
+    let buffer_small = std::env::var(&*SERVER_BUFFER_SMALL)
+        .unwrap_or_else(|_| DEFAULT_BUFFER_SMALL.into())
+        .parse::<usize>()
+        .map_err(Error::BufferSmall)?;
+    let max_inflight_bytes = std::env::var(&*SERVER_MAX_INFLIGHT_BYTES)
+        .unwrap_or_else(|_| DEFAULT_MAX_INFLIGHT_BYTES.into())
+        .parse::<u64>()
+        .map_err(Error::MaxInflightBytes)?;
+    let max_inflight_bytes_action = std::env::var(&*SERVER_MAX_INFLIGHT_BYTES_ACTION)
+        .unwrap_or_else(|_| DEFAULT_MAX_INFLIGHT_BYTES_ACTION.into())
+        .parse::<compression::BudgetAction>()
+        .map_err(Error::MaxInflightBytesAction)?;
+    let drain_rejected = std::env::var(&*SERVER_DRAIN_REJECTED)
+        .unwrap_or_else(|_| DEFAULT_DRAIN_REJECTED.into())
+        .parse::<usize>()
+        .map_err(Error::DrainRejected)?;
+    let validate_host = std::env::var(&*SERVER_VALIDATE_HOST)
+        .unwrap_or_else(|_| DEFAULT_VALIDATE_HOST.into())
+        .parse::<bool>()
+        .map_err(Error::ValidateHost)?;
+    let validate_transfer_encoding = std::env::var(&*SERVER_VALIDATE_TRANSFER_ENCODING)
+        .unwrap_or_else(|_| DEFAULT_VALIDATE_TRANSFER_ENCODING.into())
+        .parse::<bool>()
+        .map_err(Error::ValidateTransferEncoding)?;
+    let reject_bodies = std::env::var(&*SERVER_REJECT_BODIES)
+        .unwrap_or_else(|_| DEFAULT_REJECT_BODIES.into())
+        .parse::<bool>()
+        .map_err(Error::RejectBodies)?;
+    let max_path_depth = std::env::var(&*SERVER_MAX_PATH_DEPTH)
+        .unwrap_or_else(|_| DEFAULT_MAX_PATH_DEPTH.into())
+        .parse::<usize>()
+        .map_err(Error::MaxPathDepth)?;
+    let acme_webroot = std::env::var(&*SERVER_ACME_WEBROOT)
+        .ok()
+        .filter(|webroot| !webroot.is_empty());
+    let max_header_count = std::env::var(&*SERVER_MAX_HEADER_COUNT)
+        .unwrap_or_else(|_| DEFAULT_MAX_HEADER_COUNT.into())
+        .parse::<usize>()
+        .map_err(Error::MaxHeaderCount)?;
+    let strict_names = std::env::var(&*SERVER_STRICT_NAMES)
+        .unwrap_or_else(|_| DEFAULT_STRICT_NAMES.into())
+        .parse::<bool>()
+        .map_err(Error::StrictNames)?;
+    let reject_expect = std::env::var(&*SERVER_REJECT_EXPECT)
+        .unwrap_or_else(|_| DEFAULT_REJECT_EXPECT.into())
+        .parse::<bool>()
+        .map_err(Error::RejectExpect)?;
+    let max_ranges = std::env::var(&*SERVER_MAX_RANGES)
+        .unwrap_or_else(|_| DEFAULT_MAX_RANGES.into())
+        .parse::<usize>()
+        .map_err(Error::MaxRanges)?;
+    let max_ranges_action = std::env::var(&*SERVER_MAX_RANGES_ACTION)
+        .unwrap_or_else(|_| DEFAULT_MAX_RANGES_ACTION.into())
+        .parse::<RangeAction>()
+        .map_err(Error::MaxRangesAction)?;
+    let sw_allowed = std::env::var(&*SERVER_SW_ALLOWED)
+        .ok()
+        .filter(|scope| !scope.is_empty())
+        .map(|scope| HeaderValue::from_str(&scope).map_err(|_| Error::SwAllowed(scope)))
+        .transpose()?;
+    let accept_ch = std::env::var(&*SERVER_ACCEPT_CH)
+        .ok()
+        .filter(|hints| !hints.is_empty())
+        .map(|hints| HeaderValue::from_str(&hints).map_err(|_| Error::AcceptCh(hints)))
+        .transpose()?;
+    let critical_ch = std::env::var(&*SERVER_CRITICAL_CH)
+        .ok()
+        .filter(|hints| !hints.is_empty())
+        .map(|hints| HeaderValue::from_str(&hints).map_err(|_| Error::CriticalCh(hints)))
+        .transpose()?;
+    let clear_site_data_paths =
+        glob::parse_list(&std::env::var(&*SERVER_CLEAR_SITE_DATA_PATHS).unwrap_or_default())
+            .map_err(Error::ClearSiteDataPaths)?;
+    let clear_site_data =
+        std::env::var(&*SERVER_CLEAR_SITE_DATA).unwrap_or_else(|_| DEFAULT_CLEAR_SITE_DATA.into());
+    let clear_site_data = HeaderValue::from_str(&clear_site_data)
+        .map_err(|_| Error::ClearSiteData(clear_site_data))?;
+    let sw_paths: Arc<[String]> = std::env::var(&*SERVER_SW_PATHS)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|path| !path.is_empty())
+        .map(ToString::to_string)
+        .collect();
+    let compression = std::env::var(&*SERVER_COMPRESSION)
+        .unwrap_or_else(|_| DEFAULT_COMPRESSION.into())
+        .parse::<bool>()
+        .map_err(Error::Compression)?;
+    let compression_level = compression::parse_level(
+        &std::env::var(&*SERVER_COMPRESSION_LEVEL)
+            .unwrap_or_else(|_| DEFAULT_COMPRESSION_LEVEL.into()),
+    )
+    .map_err(Error::CompressionLevel)?;
+    let compression_zstd = std::env::var(&*SERVER_COMPRESSION_ZSTD)
+        .unwrap_or_else(|_| DEFAULT_COMPRESSION_ZSTD.into())
+        .parse::<bool>()
+        .map_err(Error::CompressionZstd)?;
+    let br_dictionary = match std::env::var(&*SERVER_BR_DICTIONARY) {
+        Ok(path) if !path.is_empty() => Some(Arc::new(compression::Dictionary::new(
+            read_dictionary(Path::new(&path)).map_err(Error::BrDictionary)?,
+            compression_level,
+        ))),
+        _ => None,
+    };
+    let dev_nocache = std::env::var(&*SERVER_DEV_NOCACHE)
+        .unwrap_or_else(|_| DEFAULT_DEV_NOCACHE.into())
+        .parse::<bool>()
+        .map_err(Error::DevNocache)?;
+    let csp_nonce = std::env::var(&*SERVER_CSP_NONCE)
+        .unwrap_or_else(|_| DEFAULT_CSP_NONCE.into())
+        .parse::<bool>()
+        .map_err(Error::CspNonce)?;
+    let template_max_size = std::env::var(&*SERVER_TEMPLATE_MAX_SIZE)
+        .unwrap_or_else(|_| DEFAULT_TEMPLATE_MAX_SIZE.into())
+        .parse::<u64>()
+        .map_err(Error::TemplateMaxSize)?;
+    let json_pretty = std::env::var(&*SERVER_JSON_PRETTY)
+        .unwrap_or_else(|_| DEFAULT_JSON_PRETTY.into())
+        .parse::<bool>()
+        .map_err(Error::JsonPretty)?;
+    let json_pretty_max_size = std::env::var(&*SERVER_JSON_PRETTY_MAX_SIZE)
+        .unwrap_or_else(|_| DEFAULT_JSON_PRETTY_MAX_SIZE.into())
+        .parse::<usize>()
+        .map_err(Error::JsonPrettyMaxSize)?;
+    let download_extensions: Arc<[String]> = std::env::var(&*SERVER_DOWNLOAD_EXTENSIONS)
+        .unwrap_or_default()
+        .split(',')
+        .map(|ext| ext.trim().trim_start_matches('.'))
+        .filter(|ext| !ext.is_empty())
+        .map(ToString::to_string)
+        .collect();
+    let download_name_template = std::env::var(&*SERVER_DOWNLOAD_NAME_TEMPLATE)
+        .unwrap_or_else(|_| DEFAULT_DOWNLOAD_NAME_TEMPLATE.into());
+    let download_name_template = download_name_template
+        .parse::<download::Template>()
+        .map_err(Error::DownloadNameTemplate)?;
+    let compress_skip_paths =
+        glob::parse_list(&std::env::var(&*SERVER_COMPRESS_SKIP_PATHS).unwrap_or_default())
+            .map_err(Error::CompressSkipPaths)?;
+    let strict_accept = std::env::var(&*SERVER_STRICT_ACCEPT)
+        .unwrap_or_else(|_| DEFAULT_STRICT_ACCEPT.into())
+        .parse::<bool>()
+        .map_err(Error::StrictAccept)?;
+    let frame_options = std::env::var(&*SERVER_FRAME_OPTIONS)
+        .ok()
+        .filter(|policy| !policy.is_empty())
+        .map(|policy| policy.parse::<frame::FramePolicy>())
+        .transpose()
+        .map_err(Error::FrameOptions)?;
+    let frame_overrides =
+        frame::parse_overrides(&std::env::var(&*SERVER_FRAME_OVERRIDES).unwrap_or_default())
+            .map_err(Error::FrameOverrides)?;
+    let serve_delay = std::env::var(&*SERVER_SERVE_DELAY_MS)
+        .unwrap_or_else(|_| DEFAULT_SERVE_DELAY_MS.into())
+        .parse::<u64>()
+        .map_err(Error::ServeDelay)?;
+    let compress_load_threshold = std::env::var(&*SERVER_COMPRESS_LOAD_THRESHOLD)
+        .unwrap_or_else(|_| DEFAULT_COMPRESS_LOAD_THRESHOLD.into())
+        .parse::<usize>()
+        .map_err(Error::CompressLoadThreshold)?;
+    let precompressed = std::env::var(&*SERVER_PRECOMPRESSED)
+        .unwrap_or_else(|_| DEFAULT_PRECOMPRESSED.into())
+        .parse::<bool>()
+        .map_err(Error::Precompressed)?;
+    let precompressed_validate = std::env::var(&*SERVER_PRECOMPRESSED_VALIDATE)
+        .unwrap_or_else(|_| DEFAULT_PRECOMPRESSED_VALIDATE.into())
+        .parse::<bool>()
+        .map_err(Error::PrecompressedValidate)?;
+    let dir_config = std::env::var(&*SERVER_DIR_CONFIG).unwrap_or_default();
+    let slow_fs = std::env::var(&*SERVER_SLOW_FS_MS)
+        .unwrap_or_else(|_| DEFAULT_SLOW_FS_MS.into())
+        .parse::<u64>()
+        .map_err(Error::SlowFs)?;
+    let prerender = prerender::Prerender::new(
+        &std::env::var(&*SERVER_PRERENDER).unwrap_or_default(),
+        &std::env::var(&*SERVER_PRERENDER_DIR).unwrap_or_else(|_| DEFAULT_PRERENDER_DIR.into()),
+    );
+    let max_open_files = std::env::var(&*SERVER_MAX_OPEN_FILES)
+        .unwrap_or_else(|_| DEFAULT_MAX_OPEN_FILES.into())
+        .parse::<usize>()
+        .map_err(Error::MaxOpenFiles)?;
+    let max_open_files_wait = std::env::var(&*SERVER_MAX_OPEN_FILES_WAIT_MS)
+        .unwrap_or_else(|_| DEFAULT_MAX_OPEN_FILES_WAIT_MS.into())
+        .parse::<u64>()
+        .map_err(Error::MaxOpenFilesWait)?;
+    let br_fallback = std::env::var(&*SERVER_BR_FALLBACK)
+        .unwrap_or_else(|_| DEFAULT_BR_FALLBACK.into())
+        .parse::<bool>()
+        .map_err(Error::BrFallback)?;
+    let env = std::env::var(&*SERVER_ENV).unwrap_or_else(|_| DEFAULT_ENV.into());
+    let env = env.parse::<Environment>().map_err(Error::Env)?;
+    let save_data = std::env::var(&*SERVER_SAVE_DATA)
+        .unwrap_or_else(|_| DEFAULT_SAVE_DATA.into())
+        .parse::<bool>()
+        .map_err(Error::SaveData)?;
+    let log_fields =
+        access_log::parse_fields(&std::env::var(&*SERVER_LOG_FIELDS).unwrap_or_default())
+            .map_err(Error::LogFields)?;
     let dir = std::env::var(&*SERVER_DIR).unwrap_or_else(|_| DEFAULT_DIR.into());
-    let service = ServeDir::new(&dir).append_index_html_on_directories(true);
     let file_404 = std::env::var(&*SERVER_404).unwrap_or_else(|_| DEFAULT_404.into());
-    let file_404 = Path::new(&dir).join(file_404);
-    let file_index = Path::new(&dir).join("index.html");
-    let service = service.not_found_service(ServeFile::new(&file_404));
+    let resolve_dir =
+        std::env::var(&*SERVER_DIR_RESOLVE).unwrap_or_else(|_| DEFAULT_DIR_RESOLVE.into());
 
     tracing::info!("serving '{}'", dir);
-    tracing::info!("serving 404 from '{}'", file_404.display());
-    tracing::info!("serving index from '{}'", file_index.display());
 
-    let app = Router::new().route_service("/", ServeFile::new(&file_index));
+    let etag = std::env::var(&*SERVER_ETAG)
+        .unwrap_or_else(|_| DEFAULT_ETAG.into())
+        .parse::<bool>()
+        .map_err(Error::Etag)?;
+    let rewrites = rewrite::parse_rewrites(&std::env::var(&*SERVER_REWRITES).unwrap_or_default())
+        .map_err(Error::Rewrites)?;
+    let base_path = std::env::var(&*SERVER_BASE_PATH).unwrap_or_default();
+    let base_path = base_path.trim_matches('/');
+    let base_path: Option<Arc<str>> =
+        (!base_path.is_empty()).then(|| format!("/{base_path}").into());
+    let well_known = well_known::parse(&std::env::var(&*SERVER_WELL_KNOWN).unwrap_or_default())
+        .map_err(Error::WellKnown)?;
+    let status_redirects = middleware::parse_status_redirects(
+        &std::env::var(&*SERVER_STATUS_REDIRECTS).unwrap_or_default(),
+    )
+    .map_err(Error::StatusRedirects)?;
+    let ready_path = std::env::var(&*SERVER_READY_PATH)
+        .ok()
+        .filter(|path| !path.is_empty());
+    let deep_health_path = std::env::var(&*SERVER_DEEP_HEALTH_PATH)
+        .ok()
+        .filter(|path| !path.is_empty());
+    let min_free_disk = std::env::var(&*SERVER_MIN_FREE_DISK)
+        .unwrap_or_else(|_| DEFAULT_MIN_FREE_DISK.into())
+        .parse::<u64>()
+        .map_err(Error::MinFreeDisk)?;
+    let cache_ttl = std::env::var(&*SERVER_CACHE_TTL)
+        .unwrap_or_else(|_| DEFAULT_CACHE_TTL.into())
+        .parse::<u64>()
+        .map_err(Error::CacheTtl)?;
+    let cache_ttl = (cache_ttl > 0).then(|| Duration::from_secs(cache_ttl));
+    let etag_algo = std::env::var(&*SERVER_ETAG_ALGO)
+        .unwrap_or_else(|_| DEFAULT_ETAG_ALGO.into())
+        .parse::<etag::Algo>()
+        .map_err(Error::EtagAlgo)?;
+    let head_warms_cache = std::env::var(&*SERVER_HEAD_WARMS_CACHE)
+        .unwrap_or_else(|_| DEFAULT_HEAD_WARMS_CACHE.into())
+        .parse::<bool>()
+        .map_err(Error::HeadWarmsCache)?;
+    let head_etag_parity = std::env::var(&*SERVER_HEAD_ETAG_PARITY)
+        .unwrap_or_else(|_| DEFAULT_HEAD_ETAG_PARITY.into())
+        .parse::<bool>()
+        .map_err(Error::HeadEtagParity)?;
+    let readiness = Arc::new(health::Readiness::new(dir.clone().into(), min_free_disk));
+    let cache_status_header = std::env::var(&*SERVER_CACHE_STATUS_HEADER)
+        .unwrap_or_else(|_| DEFAULT_CACHE_STATUS_HEADER.into())
+        .parse::<bool>()
+        .map_err(Error::CacheStatusHeader)?;
+    let etags = etag.then(|| {
+        Arc::new(etag::Etags::new(
+            cache_ttl,
+            etag_algo,
+            head_warms_cache,
+            head_etag_parity,
+            cache_status_header,
+        ))
+    });
+    let site = Site {
+        file_404,
+        etags: etags.clone(),
+        save_data,
+        serve_delay: Duration::from_millis(serve_delay),
+        precompressed,
+        sidecars: (precompressed && precompressed_validate).then(Arc::default),
+        br_fallback: precompressed && br_fallback,
+        slow_fs: (slow_fs > 0).then(|| Duration::from_millis(slow_fs)),
+        dir_configs: (!dir_config.is_empty())
+            .then(|| Arc::new(dir_config::DirConfigs::new(dir_config))),
+        open_files: (max_open_files > 0).then(|| {
+            tracing::info!("opening up to {} files at the same time", max_open_files);
+            Arc::new(open_files::OpenFiles::new(
+                max_open_files,
+                Duration::from_millis(max_open_files_wait),
+            ))
+        }),
+        byte_budget: (max_inflight_bytes > 0).then(|| {
+            tracing::info!(
+                "serving up to {} bytes in flight, then {:?}",
+                max_inflight_bytes,
+                max_inflight_bytes_action
+            );
+            Arc::new(compression::ByteBudget::new(
+                max_inflight_bytes,
+                max_inflight_bytes_action,
+            ))
+        }),
+        prerender: (!prerender.is_empty()).then(|| Arc::new(prerender)),
+    };
+
+    let app = match resolve_dir.as_str() {
+        "startup" => site_routes(Path::new(&dir), &site),
+        "request" => Router::new().fallback_service(docroot::Resolving::new(&dir, move |root| {
+            site_routes(root, &site)
+        })),
+        "snapshot" => {
+            let snapshot = docroot::snapshot(Path::new(&dir))?;
+            tracing::info!("serving a snapshot of '{}'", dir);
+            site_routes(&snapshot, &site)
+        }
+        _ => return Err(Error::DirResolve(resolve_dir)),
+    };
+    let app = if rewrites.is_empty() {
+        app
+    } else {
+        tracing::info!("rewriting {} paths", rewrites.len());
+        // Rewriting in an outer router routes the rewritten path, e.g. "/"
+        Router::new()
+            .fallback_service(app)
+            .layer(axum::middleware::from_fn_with_state(
+                rewrites,
+                rewrite::rewrite,
+            ))
+    };
+    let app = if let Some(prefix) = base_path {
+        tracing::info!("serving below '{}'", prefix);
+        // Stripping in an outer router routes the stripped path, e.g. "/"
+        Router::new()
+            .fallback_service(app)
+            .layer(axum::middleware::from_fn_with_state(
+                prefix,
+                middleware::base_path,
+            ))
+    } else {
+        app
+    };
+    let app = if status_redirects.is_empty() {
+        app
+    } else {
+        tracing::info!("redirecting statuses: {:?}", status_redirects);
+        app.layer(axum::middleware::from_fn_with_state(
+            status_redirects,
+            middleware::status_redirect,
+        ))
+    };
+    let app = if well_known.is_empty() {
+        app
+    } else {
+        let mut router = Router::new();
+        for (path, document) in well_known {
+            tracing::info!("serving well-known document '{}'", path);
+            router = router.route(&path, get(well_known::serve).with_state(document));
+        }
+        router.fallback_service(app)
+    };
+    let app = if let Some(path) = ready_path {
+        let path = if path.starts_with('/') {
+            path
+        } else {
+            format!("/{path}")
+        };
+        tracing::info!("serving readiness at '{}'", path);
+        Router::new()
+            .route(&path, get(health::ready).with_state(readiness.clone()))
+            .fallback_service(app)
+    } else {
+        app
+    };
+    let app = if let Some(path) = deep_health_path {
+        let path = if path.starts_with('/') {
+            path
+        } else {
+            format!("/{path}")
+        };
+        tracing::info!("serving deep health at '{}'", path);
+        Router::new()
+            .route(&path, get(health::deep).with_state(readiness))
+            .fallback_service(app)
+    } else {
+        app
+    };
+    let app = if let Some(vars) = &vars {
+        tracing::info!("serving debug counters at '/debug/vars'");
+        Router::new()
+            .route(
+                "/debug/vars",
+                get(debug::vars).with_state((vars.clone(), etags)),
+            )
+            .fallback_service(app)
+    } else {
+        app
+    };
+    let app = if env == Environment::Production {
+        app
+    } else {
+        tracing::info!("keeping crawlers away from {:?}", env);
+        app.layer(axum::middleware::from_fn(middleware::no_robots))
+    };
+    let app = app.layer(axum::middleware::from_fn(middleware::generated_ranges));
+    let app = if strict_accept {
+        app.layer(axum::middleware::from_fn(middleware::strict_accept))
+    } else {
+        app
+    };
+    let app = if frame_options.is_some() || !frame_overrides.is_empty() {
+        tracing::info!(
+            "frame policy: {:?}, overrides: {:?}",
+            frame_options,
+            frame_overrides
+        );
+        let frames = frame::Frames {
+            default: frame_options,
+            overrides: frame_overrides,
+        };
+        app.layer(axum::middleware::from_fn_with_state(
+            Arc::new(frames),
+            frame::frame_options,
+        ))
+    } else {
+        app
+    };
+    let app = if let Some(accept_ch) = accept_ch {
+        app.layer(axum::middleware::from_fn_with_state(
+            (accept_ch, critical_ch),
+            middleware::client_hints,
+        ))
+    } else {
+        app
+    };
+    let app = if clear_site_data_paths.is_empty() {
+        app
+    } else {
+        tracing::info!("clearing site data at {:?}", clear_site_data_paths);
+        app.layer(axum::middleware::from_fn_with_state(
+            (clear_site_data_paths, clear_site_data),
+            middleware::clear_site_data,
+        ))
+    };
+    let app = if let Some(scope) = sw_allowed {
+        app.layer(axum::middleware::from_fn_with_state(
+            scope,
+            middleware::service_worker_allowed,
+        ))
+    } else {
+        app
+    };
+    let app = if sw_paths.is_empty() {
+        app
+    } else {
+        tracing::info!("service workers: {:?}", sw_paths);
+        app.layer(axum::middleware::from_fn_with_state(
+            sw_paths,
+            middleware::service_worker_no_cache,
+        ))
+    };
+    let app = if download_extensions.is_empty() {
+        app
+    } else {
+        tracing::info!(
+            "downloading files with extensions {:?}",
+            download_extensions
+        );
+        let downloads = download::Downloads {
+            extensions: download_extensions,
+            template: download_name_template,
+        };
+        app.layer(axum::middleware::from_fn_with_state(
+            Arc::new(downloads),
+            download::attachment,
+        ))
+    };
+    let app = if json_pretty {
+        tracing::info!("pretty-printing json files");
+        app.layer(axum::middleware::from_fn_with_state(
+            json_pretty_max_size,
+            middleware::pretty_json,
+        ))
+    } else {
+        app
+    };
+    let app = if csp_nonce {
+        tracing::info!("injecting csp nonces into html");
+        app.layer(axum::middleware::from_fn_with_state(
+            template_max_size,
+            middleware::csp_nonce,
+        ))
+    } else {
+        app
+    };
+    let app = if compression || save_data {
+        tracing::info!("compressing responses");
+        let app = app
+            .layer(axum::middleware::from_fn(compression::restore_head))
+            .layer(axum::middleware::from_fn(compression::record_length));
+        let app = if let Some(dictionary) = br_dictionary {
+            tracing::info!("compressing with a shared brotli dictionary");
+            app.layer(axum::middleware::from_fn_with_state(
+                dictionary,
+                compression::dictionary,
+            ))
+        } else {
+            app
+        };
+        app.layer(compression::layer(compression_level, compression_zstd))
+            .layer(axum::middleware::from_fn(compression::weaken_etag))
+            .layer(axum::middleware::from_fn(compression::identity_for_ranges))
+            .layer(axum::middleware::from_fn(compression::head_as_get))
+    } else if precompressed {
+        app.layer(axum::middleware::from_fn(compression::weaken_etag))
+            .layer(axum::middleware::from_fn(compression::identity_for_ranges))
+    } else {
+        app
+    };
+    let app = if (compression || save_data) && !compress_skip_paths.is_empty() {
+        tracing::info!("not compressing {:?}", compress_skip_paths);
+        app.layer(axum::middleware::from_fn_with_state(
+            compress_skip_paths,
+            compression::skip_paths,
+        ))
+    } else {
+        app
+    };
+    let app = if (compression || save_data) && compress_load_threshold > 0 {
+        tracing::info!(
+            "not compressing above {} requests in flight",
+            compress_load_threshold
+        );
+        app.layer(axum::middleware::from_fn_with_state(
+            (Arc::default(), compress_load_threshold),
+            compression::shed_under_load,
+        ))
+    } else {
+        app
+    };
+    let app = if save_data {
+        tracing::info!("reducing data for clients sending 'Save-Data: on'");
+        app.layer(axum::middleware::from_fn_with_state(
+            !compression,
+            save_data::compress,
+        ))
+    } else {
+        app
+    };
+    let app = if dev_nocache {
+        tracing::warn!("caching disabled for development, do not use in production");
+        app.layer(axum::middleware::from_fn(middleware::dev_no_cache))
+    } else {
+        app
+    };
+    let app = if buffer_small > 0 {
+        tracing::info!("buffering responses up to {} bytes", buffer_small);
+        app.layer(axum::middleware::from_fn_with_state(
+            buffer_small,
+            middleware::buffer_small,
+        ))
+    } else {
+        app
+    };
+    let app = if let Some(policy) = require_https {
+        tracing::info!("requiring https: {:?}", policy);
+        app.layer(axum::middleware::from_fn_with_state(
+            policy,
+            middleware::require_https,
+        ))
+    } else {
+        app
+    };
+    let app = if let Some(webroot) = acme_webroot {
+        // Challenges are answered over plain http before any https redirect
+        tracing::info!("serving acme challenges from '{}'", webroot);
+        acme_routes(Path::new(&webroot), app)
+    } else {
+        app
+    };
+    let app = if max_ranges > 0 {
+        app.layer(axum::middleware::from_fn_with_state(
+            (max_ranges, max_ranges_action),
+            middleware::max_ranges,
+        ))
+    } else {
+        app
+    };
+    let app = app.layer(axum::middleware::from_fn(middleware::range_unit));
+    let app = app.layer(axum::middleware::from_fn_with_state(
+        reject_expect,
+        middleware::expect,
+    ));
+    let app = if strict_names {
+        app.layer(axum::middleware::from_fn(middleware::strict_names))
+    } else {
+        app
+    };
+    let app = app.layer(axum::middleware::from_fn(middleware::percent_decoding));
+    let app = app.layer(axum::middleware::from_fn(middleware::request_target));
+    let app = if max_header_count > 0 {
+        app.layer(axum::middleware::from_fn_with_state(
+            max_header_count,
+            middleware::max_header_count,
+        ))
+    } else {
+        app
+    };
+    let app = if max_path_depth > 0 {
+        app.layer(axum::middleware::from_fn_with_state(
+            max_path_depth,
+            middleware::max_path_depth,
+        ))
+    } else {
+        app
+    };
+    let app = if reject_bodies {
+        app.layer(axum::middleware::from_fn(middleware::reject_bodies))
+    } else {
+        app
+    };
+    let app = if validate_transfer_encoding {
+        app.layer(axum::middleware::from_fn(middleware::transfer_encoding))
+    } else {
+        app
+    };
+    let app = if validate_host {
+        app.layer(axum::middleware::from_fn(middleware::validate_host))
+    } else {
+        app
+    };
+    let app = if drain_rejected > 0 {
+        tracing::info!("draining up to {} bytes of rejected bodies", drain_rejected);
+        app.layer(axum::middleware::from_fn_with_state(
+            drain_rejected,
+            middleware::drain_rejected,
+        ))
+    } else {
+        app
+    };
     #[cfg(feature = "metrics")]
-    let app = app.route_layer(axum::middleware::from_fn(track_metrics));
-    let app = app.fallback_service(service);
+    let app = app.layer(axum::middleware::from_fn(track_rejections));
     let app = if timeout > Duration::default() {
         tracing::info!("timeout: {} ms", timeout.as_millis());
-        app.layer(TimeoutLayer::new(timeout))
+        app.layer(TimeoutLayer::with_status_code(
+            StatusCode::REQUEST_TIMEOUT,
+            timeout,
+        ))
+    } else {
+        app
+    };
+    let app = if max_response_time > Duration::default() {
+        tracing::info!(
+            "maximum response time: {} ms",
+            max_response_time.as_millis()
+        );
+        app.layer(axum::middleware::from_fn_with_state(
+            max_response_time,
+            middleware::max_response_time,
+        ))
+    } else {
+        app
+    };
+    let app = if spike_threshold > 0 {
+        tracing::info!("detecting spikes above {} requests/s", spike_threshold);
+        app.layer(axum::middleware::from_fn_with_state(
+            Arc::new(middleware::SpikeDetector::new(spike_threshold)),
+            middleware::detect_spikes,
+        ))
+    } else {
+        app
+    };
+    let app = if log_fields.is_empty() {
+        app
+    } else {
+        tracing::info!("access log fields: {:?}", log_fields);
+        app.layer(axum::middleware::from_fn_with_state(
+            log_fields,
+            access_log::log,
+        ))
+    };
+    let app = if let Some(vars) = vars {
+        app.layer(axum::middleware::from_fn_with_state(vars, debug::count))
     } else {
         app
     };
@@ -93,36 +1004,356 @@ fn site_app() -> Result<Router, Error> {
     Ok(app)
 }
 
-async fn start_site_server() {
-    if let Err(e) = serve_site().await {
+/// Settings of the routes serving a document root.
+struct Site {
+    file_404: String,
+    etags: Option<Arc<etag::Etags>>,
+    save_data: bool,
+    serve_delay: Duration,
+    precompressed: bool,
+    sidecars: Option<Arc<precompressed::Sidecars>>,
+    br_fallback: bool,
+    slow_fs: Option<Duration>,
+    dir_configs: Option<Arc<dir_config::DirConfigs>>,
+    open_files: Option<Arc<open_files::OpenFiles>>,
+    byte_budget: Option<Arc<compression::ByteBudget>>,
+    prerender: Option<Arc<prerender::Prerender>>,
+}
+
+/// Routes serving the index `file_index` at "/", or 404 if it is a directory,
+/// which `ServeDir` would fail to serve.
+fn index_routes(file_index: &Path, file_404: &Path, index: ServeFile) -> Router {
+    if file_index.is_dir() {
+        tracing::warn!(
+            "index '{}' is a directory, serving 404 for '/'",
+            file_index.display()
+        );
+        let index = if file_404.is_file() {
+            get_service(ServeFile::new(file_404))
+                .layer(axum::middleware::map_response(not_found_status))
+        } else {
+            get(not_found)
+        };
+        Router::new().route("/", index)
+    } else {
+        tracing::info!("serving index from '{}'", file_index.display());
+        Router::new().route_service("/", index)
+    }
+}
+
+/// Routes serving the document root `dir`.
+#[allow(clippy::too_many_lines)]
+fn site_routes(dir: &Path, site: &Site) -> Router {
+    let service = ServeDir::new(dir).append_index_html_on_directories(true);
+    let file_404 = dir.join(&site.file_404);
+    let file_index = dir.join("index.html");
+    let index = ServeFile::new(&file_index);
+    let (service, index) = if site.precompressed {
+        (
+            service
+                .precompressed_gzip()
+                .precompressed_br()
+                .precompressed_zstd(),
+            index
+                .precompressed_gzip()
+                .precompressed_br()
+                .precompressed_zstd(),
+        )
+    } else {
+        (service, index)
+    };
+
+    let app = index_routes(&file_index, &file_404, index);
+    #[cfg(feature = "bundle")]
+    let app = {
+        let bundles = Arc::new(bundle::Bundles::new(dir));
+        app.route(
+            "/bundle.js",
+            get(bundle::bundle).with_state(bundles.clone()),
+        )
+        .route("/bundle.css", get(bundle::bundle).with_state(bundles))
+    };
+    #[cfg(feature = "metrics")]
+    let app = app.route_layer(axum::middleware::from_fn(track_metrics));
+    let app = if file_404.is_file() {
+        tracing::info!("serving 404 from '{}'", file_404.display());
+        app.fallback_service(service.not_found_service(ServeFile::new(&file_404)))
+    } else {
+        tracing::warn!(
+            "404 file '{}' not found, serving default 404 page",
+            file_404.display()
+        );
+        app.fallback_service(service.not_found_service(not_found.into_service()))
+    };
+    let app = if let Some(threshold) = site.slow_fs {
+        app.layer(axum::middleware::from_fn_with_state(
+            (threshold, Arc::from(dir)),
+            slow_fs::log_slow,
+        ))
+    } else {
+        app
+    };
+    let app = if let Some(etags) = &site.etags {
+        app.layer(axum::middleware::from_fn_with_state(
+            (etags.clone(), Arc::from(dir)),
+            etag::etag,
+        ))
+    } else {
+        app
+    };
+    let app = if site.serve_delay > Duration::default() {
+        app.layer(axum::middleware::from_fn_with_state(
+            (site.serve_delay, Arc::from(dir)),
+            middleware::withhold_fresh,
+        ))
+    } else {
+        app
+    };
+    let app = if site.br_fallback {
+        app.layer(axum::middleware::from_fn_with_state(
+            Arc::from(dir),
+            precompressed::br_fallback,
+        ))
+    } else {
+        app
+    };
+    let app = if let Some(sidecars) = &site.sidecars {
+        app.layer(axum::middleware::from_fn_with_state(
+            (sidecars.clone(), Arc::from(dir)),
+            precompressed::validate_sidecars,
+        ))
+    } else {
+        app
+    };
+    let app = if site.save_data {
+        app.layer(axum::middleware::from_fn_with_state(
+            Arc::from(dir),
+            save_data::variant,
+        ))
+    } else {
+        app
+    };
+    let app = app.layer(axum::middleware::from_fn_with_state(
+        Arc::from(dir),
+        middleware::options,
+    ));
+    let app = if let Some(configs) = &site.dir_configs {
+        app.layer(axum::middleware::from_fn_with_state(
+            (configs.clone(), Arc::from(dir)),
+            dir_config::apply,
+        ))
+    } else {
+        app
+    };
+    let app = if let Some(open_files) = &site.open_files {
+        app.layer(axum::middleware::from_fn_with_state(
+            (open_files.clone(), Arc::from(dir)),
+            open_files::limit,
+        ))
+    } else {
+        app
+    };
+    let app = if let Some(budget) = &site.byte_budget {
+        app.layer(axum::middleware::from_fn_with_state(
+            (budget.clone(), Arc::from(dir)),
+            compression::byte_budget,
+        ))
+    } else {
+        app
+    };
+    if let Some(prerender) = &site.prerender {
+        // Rewriting in an outer router routes "/" to the snapshot as well
+        Router::new()
+            .fallback_service(app)
+            .layer(axum::middleware::from_fn_with_state(
+                (prerender.clone(), Arc::from(dir)),
+                prerender::serve,
+            ))
+    } else {
+        app
+    }
+}
+
+/// Routes serving the ACME http-01 challenges below `webroot`, e.g.
+/// "/.well-known/acme-challenge/<token>", and everything else from `app`.
+fn acme_routes(webroot: &Path, app: Router) -> Router {
+    let challenges =
+        get_service(ServeDir::new(webroot)).layer(axum::middleware::map_response(plain_text));
+    Router::new()
+        .route("/.well-known/acme-challenge/{token}", challenges)
+        .fallback_service(app)
+}
+
+async fn plain_text(mut response: axum::response::Response) -> axum::response::Response {
+    if response.status().is_success() {
+        response
+            .headers_mut()
+            .insert(header::CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+    }
+    response
+}
+
+async fn not_found_status(mut response: axum::response::Response) -> axum::response::Response {
+    *response.status_mut() = StatusCode::NOT_FOUND;
+    response
+}
+
+async fn not_found() -> impl IntoResponse {
+    (
+        StatusCode::NOT_FOUND,
+        Extension(middleware::Generated),
+        Html(DEFAULT_404_BODY),
+    )
+}
+
+async fn start_site_server(shutdown: watch::Receiver<bool>) {
+    if let Err(e) = serve_site(shutdown).await {
         tracing::error!("{}", e);
     }
 }
 
-async fn serve_site() -> Result<(), Error> {
-    let addr =
-        IpAddr::from_str(&std::env::var(&*SERVER_ADDR).unwrap_or_else(|_| "0.0.0.0".into()))?;
+async fn serve_site(shutdown: watch::Receiver<bool>) -> Result<(), Error> {
+    let addr = std::env::var(&*SERVER_ADDR).unwrap_or_else(|_| "0.0.0.0".into());
     let port = std::env::var(&*SERVER_PORT)
         .unwrap_or_else(|_| "8080".into())
         .parse::<u16>()
         .map_err(Error::Port)?;
-    let addr = SocketAddr::from((addr, port));
-    let listener = TcpListener::bind(addr).await?;
-    let app = site_app()?.layer(TraceLayer::new_for_http());
+    let retries = std::env::var(&*SERVER_BIND_RETRIES)
+        .unwrap_or_else(|_| DEFAULT_BIND_RETRIES.into())
+        .parse::<u32>()
+        .map_err(Error::BindRetries)?;
+    let delay = std::env::var(&*SERVER_BIND_RETRY_DELAY)
+        .unwrap_or_else(|_| DEFAULT_BIND_RETRY_DELAY.into())
+        .parse::<u64>()
+        .map_err(Error::BindRetryDelay)?;
+    let addr = socket_addr(&addr, port)?;
+    let delay = Duration::from_millis(delay);
+    let Some(listener) = bind_until_shutdown(addr, retries, delay, shutdown.clone()).await? else {
+        tracing::info!("shutdown signaled while binding the site, exiting");
+        return Ok(());
+    };
+    let traceparent = std::env::var(&*SERVER_TRACEPARENT)
+        .unwrap_or_else(|_| DEFAULT_TRACEPARENT.into())
+        .parse::<bool>()
+        .map_err(Error::Traceparent)?;
+    let debug = std::env::var(&*SERVER_DEBUG)
+        .unwrap_or_else(|_| DEFAULT_DEBUG.into())
+        .parse::<bool>()
+        .map_err(Error::Debug)?;
+    let vars = debug.then(|| Arc::new(debug::Vars::new()));
+    let app = if traceparent {
+        site_app(vars.clone())?
+            .layer(TraceLayer::new_for_http().make_span_with(trace_context::make_span))
+            .layer(axum::middleware::from_fn(trace_context::propagate))
+    } else {
+        site_app(vars.clone())?.layer(TraceLayer::new_for_http())
+    };
 
-    tracing::info!("site listening on {}", listener.local_addr().unwrap());
-    axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal())
-        .await
-        .unwrap();
+    let local_addr = listener.local_addr()?;
+    tracing::info!("site listening on {}", local_addr);
+    if let Some(port_file) = std::env::var_os(&*SERVER_PORT_FILE).filter(|f| !f.is_empty()) {
+        write_port_file(&port_file, &listener).await?;
+    }
+    let app = app.into_make_service_with_connect_info::<SocketAddr>();
+    if let Some(vars) = vars {
+        // Tapping the listener provides the peer address as connect info
+        let listener = debug::Listener::new(listener, vars).tap_io(|_| {});
+        axum::serve(listener, app)
+            .with_graceful_shutdown(shutdown_signal(shutdown))
+            .await
+            .unwrap();
+    } else {
+        axum::serve(listener, app)
+            .with_graceful_shutdown(shutdown_signal(shutdown))
+            .await
+            .unwrap();
+    }
     Ok(())
 }
 
-#[cfg(feature = "metrics")]
-fn metrics_app() -> Router {
-    use std::future::ready;
+/// Parses the address to listen on, which may be a link-local IPv6 address
+/// scoped to a network interface by name or index, e.g. `fe80::1%eth0`.
+fn socket_addr(addr: &str, port: u16) -> Result<SocketAddr, Error> {
+    let Some((ip, zone)) = addr.split_once('%') else {
+        return Ok(SocketAddr::from((IpAddr::from_str(addr)?, port)));
+    };
+    let ip = Ipv6Addr::from_str(ip)?;
+    let scope_id = match zone.parse::<u32>() {
+        Ok(index) => index,
+        Err(_) => interface_index(zone).ok_or_else(|| Error::Zone(zone.to_string()))?,
+    };
+    Ok(SocketAddr::V6(SocketAddrV6::new(ip, port, 0, scope_id)))
+}
 
-    use axum::routing::get;
+#[cfg(unix)]
+fn interface_index(name: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(name).ok()?;
+    // SAFETY: `name` is a valid C string.
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    (index != 0).then_some(index)
+}
+
+#[cfg(not(unix))]
+const fn interface_index(_name: &str) -> Option<u32> {
+    None
+}
+
+/// Writes the port `listener` is bound to, followed by a newline, to
+/// `port_file`.
+///
+/// The port is read from the bound listener, so it is reported even if it was
+/// chosen by the OS for port "0".
+async fn write_port_file(
+    port_file: &std::ffi::OsStr,
+    listener: &TcpListener,
+) -> std::io::Result<()> {
+    let port = listener.local_addr()?.port();
+    tokio::fs::write(port_file, format!("{port}\n")).await
+}
+
+/// Binds the listener, retrying up to `retries` times if the address is still
+/// in use, e.g. by a previous instance during a rolling restart.
+async fn bind_with_retry(
+    addr: SocketAddr,
+    retries: u32,
+    delay: Duration,
+) -> Result<TcpListener, Error> {
+    let mut attempt = 0;
+    loop {
+        match TcpListener::bind(addr).await {
+            Ok(listener) => return Ok(listener),
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse && attempt < retries => {
+                attempt += 1;
+                tracing::warn!(
+                    "{} is in use, retrying in {} ms ({}/{})",
+                    addr,
+                    delay.as_millis(),
+                    attempt,
+                    retries
+                );
+                tokio::time::sleep(delay).await;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Binds `addr` like [`bind_with_retry`], or gives up without a listener once
+/// the shutdown is signaled, e.g. while the address is still in use.
+async fn bind_until_shutdown(
+    addr: SocketAddr,
+    retries: u32,
+    delay: Duration,
+    shutdown: watch::Receiver<bool>,
+) -> Result<Option<TcpListener>, Error> {
+    tokio::select! {
+        listener = bind_with_retry(addr, retries, delay) => listener.map(Some),
+        () = shutdown_signal(shutdown) => Ok(None),
+    }
+}
+
+#[cfg(feature = "metrics")]
+fn metrics_app() -> Result<Router, Error> {
     use metrics_exporter_prometheus::{Matcher, PrometheusBuilder};
 
     const EXPONENTIAL_SECONDS: &[f64] = &[
@@ -134,34 +1365,70 @@ fn metrics_app() -> Router {
             Matcher::Full("http_requests_duration_seconds".to_string()),
             EXPONENTIAL_SECONDS,
         )
-        .unwrap()
-        .install_recorder()
-        .unwrap();
+        .and_then(PrometheusBuilder::install_recorder)
+        .map_err(Error::MetricsRecorder)?;
+
+    let path = std::env::var(&*METRICS_PATH).unwrap_or_else(|_| DEFAULT_METRICS_PATH.into());
+    let compression = std::env::var(&*METRICS_COMPRESSION)
+        .unwrap_or_else(|_| DEFAULT_METRICS_COMPRESSION.into())
+        .parse::<bool>()
+        .map_err(Error::MetricsCompression)?;
 
-    Router::new().route("/metrics", get(move || ready(recorder_handle.render())))
+    Ok(metrics_routes(&path, compression, recorder_handle))
 }
 
+/// Serves the metrics rendered by `handle` at `path`, with a leading slash
+/// added if missing.
 #[cfg(feature = "metrics")]
-async fn start_metrics_server() {
-    if let Err(e) = serve_metrics().await {
-        tracing::error!("{}", e);
+fn metrics_routes(
+    path: &str,
+    compression: bool,
+    handle: metrics_exporter_prometheus::PrometheusHandle,
+) -> Router {
+    use std::future::ready;
+
+    let path = if path.starts_with('/') {
+        path.to_string()
+    } else {
+        format!("/{path}")
+    };
+    tracing::info!("serving metrics at '{}'", path);
+    let app = Router::new().route(&path, get(move || ready(handle.render())));
+    if compression {
+        app.layer(CompressionLayer::new())
+    } else {
+        app
+    }
+}
+
+#[cfg(feature = "metrics")]
+async fn start_metrics_server(shutdown: watch::Receiver<bool>) {
+    match serve_metrics(shutdown).await {
+        // The site is still served, only without metrics
+        Err(e @ Error::MetricsRecorder(_)) => tracing::warn!("metrics disabled: {}", e),
+        Err(e) => tracing::error!("{}", e),
+        Ok(()) => {}
     }
 }
 
 #[cfg(feature = "metrics")]
-async fn serve_metrics() -> Result<(), Error> {
-    let addr =
-        IpAddr::from_str(&std::env::var(&*METRICS_ADDR).unwrap_or_else(|_| "0.0.0.0".into()))?;
+async fn serve_metrics(shutdown: watch::Receiver<bool>) -> Result<(), Error> {
+    let addr = std::env::var(&*METRICS_ADDR).unwrap_or_else(|_| "0.0.0.0".into());
     let port = std::env::var(&*METRICS_PORT)
         .unwrap_or_else(|_| "8081".into())
         .parse::<u16>()
         .map_err(Error::Port)?;
-    let addr = SocketAddr::from((addr, port));
-    let listener = TcpListener::bind(addr).await?;
+    let addr = socket_addr(&addr, port)?;
+    let app = metrics_app()?;
+    let Some(listener) = bind_until_shutdown(addr, 0, Duration::ZERO, shutdown.clone()).await?
+    else {
+        tracing::info!("shutdown signaled while binding the metrics, exiting");
+        return Ok(());
+    };
 
     tracing::info!("metrics listening on {}", listener.local_addr().unwrap());
-    axum::serve(listener, metrics_app())
-        .with_graceful_shutdown(shutdown_signal())
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_signal(shutdown))
         .await
         .unwrap();
     Ok(())
@@ -197,29 +1464,100 @@ async fn track_metrics(req: Request, next: Next) -> impl IntoResponse {
     response
 }
 
-#[allow(clippy::redundant_pub_crate)]
-async fn shutdown_signal() {
-    let ctrl_c = async {
-        signal::ctrl_c()
-            .await
-            .expect("failed to install Ctrl+C handler");
+/// Counts requests rejected with `405 Method Not Allowed` or `400 Bad Request`
+/// by the attempted method, e.g. to alert on probes.
+#[cfg(feature = "metrics")]
+async fn track_rejections(req: Request, next: Next) -> impl IntoResponse {
+    use axum::http::Method;
+
+    const KNOWN: &[Method] = &[
+        Method::GET,
+        Method::HEAD,
+        Method::POST,
+        Method::PUT,
+        Method::DELETE,
+        Method::CONNECT,
+        Method::OPTIONS,
+        Method::TRACE,
+        Method::PATCH,
+    ];
+
+    // Unknown methods are grouped to bound the number of label values
+    let method = if KNOWN.contains(req.method()) {
+        req.method().to_string()
+    } else {
+        "other".to_string()
     };
 
+    let response = next.run(req).await;
+
+    let labels = [("method", method)];
+    match response.status() {
+        StatusCode::METHOD_NOT_ALLOWED => {
+            metrics::counter!("http_method_not_allowed_total", &labels).increment(1);
+        }
+        StatusCode::BAD_REQUEST => {
+            metrics::counter!("http_bad_request_total", &labels).increment(1);
+        }
+        _ => {}
+    }
+
+    response
+}
+
+/// Installs the handlers of Ctrl+C and, on unix, SIGTERM, which signal the
+/// shutdown. They are installed at the start of the process, so the returned
+/// receiver sees a signal received during startup.
+#[allow(clippy::redundant_pub_crate)]
+fn install_shutdown_signals() -> watch::Receiver<bool> {
+    let (sender, shutdown) = watch::channel(false);
     #[cfg(unix)]
-    let terminate = async {
-        signal::unix::signal(signal::unix::SignalKind::terminate())
-            .expect("failed to install signal handler")
-            .recv()
-            .await;
-    };
+    {
+        use signal::unix::SignalKind;
+
+        let mut interrupt = signal::unix::signal(SignalKind::interrupt())
+            .expect("failed to install Ctrl+C handler");
+        let mut terminate = signal::unix::signal(SignalKind::terminate())
+            .expect("failed to install signal handler");
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = interrupt.recv() => {},
+                _ = terminate.recv() => {},
+            }
+            sender.send_replace(true);
+        });
+    }
 
     #[cfg(not(unix))]
-    let terminate = std::future::pending::<()>();
+    tokio::spawn(async move {
+        signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl+C handler");
+        sender.send_replace(true);
+    });
 
-    tokio::select! {
-        () = ctrl_c => {},
-        () = terminate => {},
+    shutdown
+}
+
+/// Reads the shared Brotli dictionary, at most
+/// [`compression::DICTIONARY_MAX_SIZE`] bytes.
+fn read_dictionary(path: &Path) -> std::io::Result<Vec<u8>> {
+    if std::fs::metadata(path)?.len() > compression::DICTIONARY_MAX_SIZE {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "'{}' is larger than {} bytes",
+                path.display(),
+                compression::DICTIONARY_MAX_SIZE
+            ),
+        ));
     }
+    std::fs::read(path)
+}
+
+/// Waits until the shutdown is signaled, or returns at once if it already was.
+async fn shutdown_signal(mut shutdown: watch::Receiver<bool>) {
+    let _ = shutdown.wait_for(|&shutdown| shutdown).await;
 }
 
 #[derive(Debug)]
@@ -228,6 +1566,72 @@ enum Error {
     IpAddr(std::net::AddrParseError),
     Port(std::num::ParseIntError),
     Timeout(std::num::ParseIntError),
+    BindRetries(std::num::ParseIntError),
+    BindRetryDelay(std::num::ParseIntError),
+    Traceparent(std::str::ParseBoolError),
+    RequireHttps(String),
+    DirResolve(String),
+    LogFields(String),
+    BufferSmall(std::num::ParseIntError),
+    ValidateHost(std::str::ParseBoolError),
+    ValidateTransferEncoding(std::str::ParseBoolError),
+    MaxPathDepth(std::num::ParseIntError),
+    RejectExpect(std::str::ParseBoolError),
+    Etag(std::str::ParseBoolError),
+    Compression(std::str::ParseBoolError),
+    HeadWarmsCache(std::str::ParseBoolError),
+    MaxRanges(std::num::ParseIntError),
+    CacheTtl(std::num::ParseIntError),
+    MinFreeDisk(std::num::ParseIntError),
+    MaxRangesAction(String),
+    SwAllowed(String),
+    DevNocache(std::str::ParseBoolError),
+    JsonPretty(std::str::ParseBoolError),
+    JsonPrettyMaxSize(std::num::ParseIntError),
+    RejectBodies(std::str::ParseBoolError),
+    Debug(std::str::ParseBoolError),
+    MaxResponseTime(std::num::ParseIntError),
+    DownloadNameTemplate(String),
+    SaveData(std::str::ParseBoolError),
+    CompressSkipPaths(String),
+    StrictAccept(std::str::ParseBoolError),
+    FrameOptions(String),
+    FrameOverrides(String),
+    ServeDelay(std::num::ParseIntError),
+    CompressLoadThreshold(std::num::ParseIntError),
+    CacheStatusHeader(std::str::ParseBoolError),
+    Precompressed(std::str::ParseBoolError),
+    PrecompressedValidate(std::str::ParseBoolError),
+    Env(String),
+    HeadEtagParity(std::str::ParseBoolError),
+    BrFallback(std::str::ParseBoolError),
+    MaxInflightBytes(std::num::ParseIntError),
+    MaxInflightBytesAction(String),
+    Rewrites(String),
+    EtagAlgo(String),
+    Zone(String),
+    SlowFs(std::num::ParseIntError),
+    AcceptCh(String),
+    CriticalCh(String),
+    MaxOpenFiles(std::num::ParseIntError),
+    MaxOpenFilesWait(std::num::ParseIntError),
+    ClearSiteDataPaths(String),
+    ClearSiteData(String),
+    StrictNames(std::str::ParseBoolError),
+    SpikeThreshold(std::num::ParseIntError),
+    CspNonce(std::str::ParseBoolError),
+    WellKnown(String),
+    StatusRedirects(String),
+    MaxHeaderCount(std::num::ParseIntError),
+    TemplateMaxSize(std::num::ParseIntError),
+    DrainRejected(std::num::ParseIntError),
+    CompressionLevel(String),
+    CompressionZstd(std::str::ParseBoolError),
+    BrDictionary(std::io::Error),
+    #[cfg(feature = "metrics")]
+    MetricsCompression(std::str::ParseBoolError),
+    #[cfg(feature = "metrics")]
+    MetricsRecorder(metrics_exporter_prometheus::BuildError),
 }
 
 impl From<std::net::AddrParseError> for Error {
@@ -243,12 +1647,153 @@ impl From<std::io::Error> for Error {
 }
 
 impl std::fmt::Display for Error {
+    #[allow(clippy::too_many_lines)]
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::Io(e) => e.fmt(f),
             Self::IpAddr(e) => e.fmt(f),
             Self::Port(_) => write!(f, "port must be a positive integer (u16)"),
             Self::Timeout(_) => write!(f, "timeout must be a positive integer (u64)"),
+            Self::BindRetries(_) => write!(f, "bind retries must be a positive integer (u32)"),
+            Self::BindRetryDelay(_) => {
+                write!(f, "bind retry delay must be a positive integer (u64)")
+            }
+            Self::BufferSmall(_) => {
+                write!(f, "buffer threshold must be a positive integer (usize)")
+            }
+            Self::MaxPathDepth(_) => {
+                write!(f, "maximum path depth must be a positive integer (usize)")
+            }
+            Self::ValidateHost(_) => write!(f, "host validation must be 'true' or 'false'"),
+            Self::ValidateTransferEncoding(_) => {
+                write!(f, "transfer encoding validation must be 'true' or 'false'")
+            }
+            Self::MaxRanges(_) => {
+                write!(f, "maximum ranges must be a positive integer (usize)")
+            }
+            Self::MinFreeDisk(_) => {
+                write!(f, "minimum free disk must be a positive integer (u64)")
+            }
+            Self::CacheTtl(_) => write!(f, "cache ttl must be a positive integer (u64)"),
+            Self::MaxRangesAction(action) => write!(
+                f,
+                "maximum ranges action must be 'reject' or 'full', got '{action}'"
+            ),
+            Self::SwAllowed(scope) => write!(f, "invalid service worker scope '{scope}'"),
+            Self::RejectExpect(_) => write!(f, "reject expect must be 'true' or 'false'"),
+            Self::Etag(_) => write!(f, "etag must be 'true' or 'false'"),
+            Self::Traceparent(_) => write!(f, "traceparent must be 'true' or 'false'"),
+            Self::Compression(_) => write!(f, "compression must be 'true' or 'false'"),
+            Self::HeadWarmsCache(_) => {
+                write!(f, "head warms cache must be 'true' or 'false'")
+            }
+            Self::DevNocache(_) => write!(f, "dev no-cache must be 'true' or 'false'"),
+            Self::JsonPretty(_) => write!(f, "json pretty must be 'true' or 'false'"),
+            Self::JsonPrettyMaxSize(_) => {
+                write!(
+                    f,
+                    "json pretty maximum size must be a positive integer (usize)"
+                )
+            }
+            Self::RejectBodies(_) => write!(f, "reject bodies must be 'true' or 'false'"),
+            Self::Debug(_) => write!(f, "debug must be 'true' or 'false'"),
+            Self::MaxResponseTime(_) => {
+                write!(f, "maximum response time must be a positive integer (u64)")
+            }
+            Self::DownloadNameTemplate(value) => {
+                write!(f, "invalid download name template '{value}'")
+            }
+            Self::SaveData(_) => write!(f, "save data must be 'true' or 'false'"),
+            Self::CompressSkipPaths(value) => {
+                write!(f, "compress skip path '{value}' must start with '/'")
+            }
+            Self::StrictAccept(_) => write!(f, "strict accept must be 'true' or 'false'"),
+            Self::FrameOptions(value) => write!(
+                f,
+                "frame policy must be 'DENY', 'SAMEORIGIN' or origins, got '{value}'"
+            ),
+            Self::FrameOverrides(value) => write!(f, "invalid frame override '{value}'"),
+            Self::ServeDelay(_) => write!(f, "serve delay must be a positive integer (u64)"),
+            Self::CompressLoadThreshold(_) => write!(
+                f,
+                "compress load threshold must be a positive integer (usize)"
+            ),
+            Self::CacheStatusHeader(_) => {
+                write!(f, "cache status header must be 'true' or 'false'")
+            }
+            Self::Precompressed(_) => write!(f, "precompressed must be 'true' or 'false'"),
+            Self::PrecompressedValidate(_) => {
+                write!(f, "precompressed validate must be 'true' or 'false'")
+            }
+            Self::Env(value) => write!(
+                f,
+                "environment must be 'production', 'staging' or 'development', got '{value}'"
+            ),
+            Self::HeadEtagParity(_) => write!(f, "head etag parity must be 'true' or 'false'"),
+            Self::BrFallback(_) => write!(f, "brotli fallback must be 'true' or 'false'"),
+            Self::MaxInflightBytes(_) => {
+                write!(f, "in-flight byte budget must be a positive integer (u64)")
+            }
+            Self::MaxInflightBytesAction(value) => write!(
+                f,
+                "in-flight byte budget action must be 'identity' or 'shed', got '{value}'"
+            ),
+            Self::Rewrites(value) => write!(f, "invalid rewrite '{value}'"),
+            Self::EtagAlgo(value) => write!(
+                f,
+                "etag algorithm must be 'sha256' or 'sha512', got '{value}'"
+            ),
+            Self::Zone(value) => write!(f, "unknown network interface '{value}'"),
+            Self::SlowFs(_) => write!(
+                f,
+                "slow file system threshold must be a positive integer (u64)"
+            ),
+            Self::AcceptCh(value) => write!(f, "invalid client hints '{value}'"),
+            Self::CriticalCh(value) => write!(f, "invalid critical client hints '{value}'"),
+            Self::MaxOpenFiles(_) => {
+                write!(f, "maximum open files must be a positive integer (usize)")
+            }
+            Self::MaxOpenFilesWait(_) => {
+                write!(f, "open files wait must be a positive integer (u64)")
+            }
+            Self::ClearSiteDataPaths(value) => {
+                write!(f, "clear site data path '{value}' must start with '/'")
+            }
+            Self::ClearSiteData(value) => write!(f, "invalid clear site data '{value}'"),
+            Self::StrictNames(_) => write!(f, "strict names must be 'true' or 'false'"),
+            Self::SpikeThreshold(_) => {
+                write!(f, "spike threshold must be a positive integer (u64)")
+            }
+            Self::CspNonce(_) => write!(f, "csp nonce must be 'true' or 'false'"),
+            Self::WellKnown(value) => write!(f, "invalid well-known document '{value}'"),
+            Self::StatusRedirects(value) => write!(f, "invalid status redirect '{value}'"),
+            Self::MaxHeaderCount(_) => {
+                write!(f, "maximum header count must be a positive integer (usize)")
+            }
+            Self::TemplateMaxSize(_) => {
+                write!(f, "template maximum size must be a positive integer (u64)")
+            }
+            Self::DrainRejected(_) => {
+                write!(f, "drain rejected must be a positive integer (usize)")
+            }
+            Self::CompressionLevel(value) => write!(f, "invalid compression level '{value}'"),
+            Self::CompressionZstd(_) => write!(f, "compression zstd must be 'true' or 'false'"),
+            Self::BrDictionary(e) => write!(f, "failed to read brotli dictionary: {e}"),
+            #[cfg(feature = "metrics")]
+            Self::MetricsRecorder(e) => write!(f, "failed to install metrics recorder: {e}"),
+            #[cfg(feature = "metrics")]
+            Self::MetricsCompression(_) => {
+                write!(f, "metrics compression must be 'true' or 'false'")
+            }
+            Self::DirResolve(mode) => write!(
+                f,
+                "directory resolution must be 'startup', 'request' or 'snapshot', got '{mode}'"
+            ),
+            Self::LogFields(field) => write!(f, "unknown access log field '{field}'"),
+            Self::RequireHttps(policy) => write!(
+                f,
+                "https policy must be 'off', '426' or 'redirect', got '{policy}'"
+            ),
         }
     }
 }
@@ -256,9 +1801,440 @@ impl std::fmt::Display for Error {
 impl std::error::Error for Error {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
-            Self::Io(e) => Some(e),
+            Self::Io(e) | Self::BrDictionary(e) => Some(e),
             Self::IpAddr(e) => Some(e),
-            Self::Port(e) | Self::Timeout(e) => Some(e),
+            Self::RequireHttps(_)
+            | Self::DirResolve(_)
+            | Self::LogFields(_)
+            | Self::MaxRangesAction(_)
+            | Self::SwAllowed(_)
+            | Self::DownloadNameTemplate(_)
+            | Self::CompressSkipPaths(_)
+            | Self::FrameOptions(_)
+            | Self::FrameOverrides(_)
+            | Self::Env(_)
+            | Self::MaxInflightBytesAction(_)
+            | Self::Rewrites(_)
+            | Self::EtagAlgo(_)
+            | Self::Zone(_)
+            | Self::AcceptCh(_)
+            | Self::CriticalCh(_)
+            | Self::ClearSiteDataPaths(_)
+            | Self::ClearSiteData(_)
+            | Self::WellKnown(_)
+            | Self::StatusRedirects(_)
+            | Self::CompressionLevel(_) => None,
+            Self::ValidateHost(e)
+            | Self::RejectExpect(e)
+            | Self::Etag(e)
+            | Self::Compression(e)
+            | Self::Traceparent(e)
+            | Self::HeadWarmsCache(e)
+            | Self::ValidateTransferEncoding(e)
+            | Self::DevNocache(e)
+            | Self::JsonPretty(e)
+            | Self::RejectBodies(e)
+            | Self::Debug(e)
+            | Self::SaveData(e)
+            | Self::StrictAccept(e)
+            | Self::CacheStatusHeader(e)
+            | Self::Precompressed(e)
+            | Self::PrecompressedValidate(e)
+            | Self::HeadEtagParity(e)
+            | Self::BrFallback(e)
+            | Self::StrictNames(e)
+            | Self::CspNonce(e)
+            | Self::CompressionZstd(e) => Some(e),
+            #[cfg(feature = "metrics")]
+            Self::MetricsCompression(e) => Some(e),
+            #[cfg(feature = "metrics")]
+            Self::MetricsRecorder(e) => Some(e),
+            Self::Port(e)
+            | Self::Timeout(e)
+            | Self::BindRetries(e)
+            | Self::BindRetryDelay(e)
+            | Self::BufferSmall(e)
+            | Self::MaxPathDepth(e)
+            | Self::MaxRanges(e)
+            | Self::CacheTtl(e)
+            | Self::MinFreeDisk(e)
+            | Self::MaxResponseTime(e)
+            | Self::ServeDelay(e)
+            | Self::CompressLoadThreshold(e)
+            | Self::MaxInflightBytes(e)
+            | Self::SlowFs(e)
+            | Self::MaxOpenFiles(e)
+            | Self::MaxOpenFilesWait(e)
+            | Self::SpikeThreshold(e)
+            | Self::MaxHeaderCount(e)
+            | Self::TemplateMaxSize(e)
+            | Self::JsonPrettyMaxSize(e)
+            | Self::DrainRejected(e) => Some(e),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn bind_with_retry_waits_for_the_address_to_be_released() {
+        let taken = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = taken.local_addr().unwrap();
+        let in_use = bind_with_retry(addr, 0, Duration::ZERO).await;
+        assert!(matches!(in_use, Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::AddrInUse));
+
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            drop(taken);
+        });
+        let listener = bind_with_retry(addr, 50, Duration::from_millis(20))
+            .await
+            .unwrap();
+        assert_eq!(listener.local_addr().unwrap(), addr);
+    }
+
+    #[cfg(feature = "metrics")]
+    async fn scrape(app: Router, uri: &str, accept: &str) -> axum::response::Response {
+        use tower::ServiceExt;
+
+        let req = Request::get(uri)
+            .header(header::ACCEPT_ENCODING, accept)
+            .body(axum::body::Body::empty())
+            .unwrap();
+        app.oneshot(req).await.unwrap()
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn metrics_are_served_at_the_configured_path() {
+        let recorder = metrics_exporter_prometheus::PrometheusBuilder::new().build_recorder();
+        for path in ["/internal/metrics", "internal/metrics"] {
+            let app = metrics_routes(path, false, recorder.handle());
+            let response = scrape(app.clone(), "/internal/metrics", "gzip").await;
+            assert_eq!(response.status(), StatusCode::OK, "{path}");
+            let response = scrape(app, "/metrics", "gzip").await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{path}");
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn metrics_are_compressed_for_scrapers_accepting_gzip() {
+        let recorder = metrics_exporter_prometheus::PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+        metrics::with_local_recorder(&recorder, || {
+            for i in 0..100 {
+                metrics::counter!("test_total", "label" => i.to_string()).increment(1);
+            }
+        });
+        let app = metrics_routes("/metrics", true, handle.clone());
+        let response = scrape(app.clone(), "/metrics", "gzip").await;
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+        let response = scrape(app, "/metrics", "identity").await;
+        assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+
+        let app = metrics_routes("/metrics", false, handle);
+        let response = scrape(app, "/metrics", "gzip").await;
+        assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+    }
+
+    /// The settings of a site without optional features.
+    fn site() -> Site {
+        Site {
+            file_404: DEFAULT_404.to_string(),
+            etags: None,
+            save_data: false,
+            serve_delay: Duration::default(),
+            precompressed: false,
+            sidecars: None,
+            br_fallback: false,
+            slow_fs: None,
+            dir_configs: None,
+            open_files: None,
+            byte_budget: None,
+            prerender: None,
+        }
+    }
+
+    async fn get_body(app: Router, uri: &str) -> (StatusCode, String) {
+        use http_body_util::BodyExt;
+        use tower::ServiceExt;
+
+        let req = axum::http::Request::get(uri)
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let response = app.oneshot(req).await.unwrap();
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn a_missing_404_file_serves_the_built_in_page() {
+        let dir = temp_dir::TempDir::new(&[("index.html", "index")]);
+        let app = site_routes(&dir, &site());
+        let (status, body) = get_body(app, "/missing.html").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body, DEFAULT_404_BODY);
+
+        dir.write(DEFAULT_404, "custom");
+        let app = site_routes(&dir, &site());
+        let (status, body) = get_body(app, "/missing.html").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body, "custom");
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn rejections_are_counted_by_method() {
+        use tower::ServiceExt;
+
+        let recorder = metrics_exporter_prometheus::PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+        let app = Router::new()
+            .route("/", get(|| async {}))
+            .route("/bad", get(|| async { StatusCode::BAD_REQUEST }))
+            .layer(axum::middleware::from_fn(track_rejections));
+        for (method, uri) in [("POST", "/"), ("PROBE", "/"), ("GET", "/bad"), ("GET", "/")] {
+            let req = Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(axum::body::Body::empty())
+                .unwrap();
+            app.clone().oneshot(req).await.unwrap();
+        }
+        let rendered = handle.render();
+        assert!(rendered.contains("http_method_not_allowed_total{method=\"POST\"} 1"));
+        assert!(rendered.contains("http_method_not_allowed_total{method=\"other\"} 1"));
+        assert!(rendered.contains("http_bad_request_total{method=\"GET\"} 1"));
+    }
+
+    #[tokio::test]
+    async fn the_port_file_reports_the_port_chosen_by_the_os() {
+        let dir = temp_dir::TempDir::new(&[]);
+        let port_file = dir.join("port");
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        write_port_file(port_file.as_os_str(), &listener)
+            .await
+            .unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert_ne!(port, 0);
+        assert_eq!(
+            std::fs::read_to_string(port_file).unwrap(),
+            format!("{port}\n")
+        );
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn a_second_metrics_recorder_is_reported() {
+        // The only test installing the global recorder
+        assert!(metrics_app().is_ok());
+        assert!(matches!(metrics_app(), Err(Error::MetricsRecorder(_))));
+
+        // The metrics server gives up without taking the site down
+        let (_sender, shutdown) = watch::channel(false);
+        tokio::time::timeout(Duration::from_secs(1), start_metrics_server(shutdown))
+            .await
+            .unwrap();
+        // The site is still served, recording into the installed recorder
+        let dir = temp_dir::TempDir::new(&[("index.html", "index")]);
+        let (status, body) = get_body(site_routes(&dir, &site()), "/index.html").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "index");
+    }
+
+    #[test]
+    fn socket_addr_accepts_zones_by_index_and_name() {
+        assert_eq!(
+            socket_addr("127.0.0.1", 80).unwrap(),
+            SocketAddr::from(([127, 0, 0, 1], 80))
+        );
+        let SocketAddr::V6(addr) = socket_addr("fe80::1%2", 80).unwrap() else {
+            panic!("not an IPv6 address");
+        };
+        assert_eq!((addr.ip().segments()[0], addr.scope_id()), (0xfe80, 2));
+        #[cfg(target_os = "linux")]
+        {
+            let SocketAddr::V6(addr) = socket_addr("fe80::1%lo", 80).unwrap() else {
+                panic!("not an IPv6 address");
+            };
+            assert_ne!(addr.scope_id(), 0);
+        }
+        assert!(matches!(
+            socket_addr("fe80::1%no-such-interface", 80),
+            Err(Error::Zone(zone)) if zone == "no-such-interface"
+        ));
+        assert!(socket_addr("127.0.0.1%1", 80).is_err());
+    }
+
+    #[tokio::test]
+    async fn an_index_directory_serves_404_for_the_root() {
+        let dir = temp_dir::TempDir::new(&[("index.html/page.txt", "page")]);
+        let (status, body) = get_body(site_routes(&dir, &site()), "/").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body, DEFAULT_404_BODY);
+
+        dir.write(DEFAULT_404, "custom");
+        let app = site_routes(&dir, &site());
+        let (status, body) = get_body(app.clone(), "/").await;
+        assert_eq!((status, body.as_str()), (StatusCode::NOT_FOUND, "custom"));
+        let (status, body) = get_body(app, "/index.html/page.txt").await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "page"));
+    }
+
+    #[tokio::test]
+    async fn acme_challenges_are_served_from_the_webroot() {
+        use tower::ServiceExt;
+
+        let webroot = temp_dir::TempDir::new(&[(".well-known/acme-challenge/token", "key")]);
+        let app = Router::new().fallback(|| async { "site" }).layer(
+            axum::middleware::from_fn_with_state(HttpsPolicy::Upgrade, middleware::require_https),
+        );
+        let app = acme_routes(&webroot, app);
+        let req = axum::http::Request::get("/.well-known/acme-challenge/token")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/plain");
+        let (status, body) = get_body(app.clone(), "/.well-known/acme-challenge/token").await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "key"));
+        let (status, _) = get_body(app.clone(), "/.well-known/acme-challenge/other").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = get_body(app, "/index.html").await;
+        assert_eq!(status, StatusCode::UPGRADE_REQUIRED);
+    }
+
+    #[tokio::test]
+    async fn a_shutdown_signaled_before_waiting_is_not_missed() {
+        let (sender, shutdown) = watch::channel(false);
+        let waiting = tokio::spawn(shutdown_signal(shutdown.clone()));
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+
+        sender.send_replace(true);
+        tokio::time::timeout(Duration::from_secs(1), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+        // A signal received during startup ends a later wait at once
+        tokio::time::timeout(Duration::from_secs(1), shutdown_signal(shutdown))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_shutdown_while_binding_exits_without_a_listener() {
+        let taken = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = taken.local_addr().unwrap();
+        let (sender, shutdown) = watch::channel(false);
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            sender.send_replace(true);
+        });
+        let bound = bind_until_shutdown(addr, u32::MAX, Duration::from_millis(10), shutdown);
+        let listener = tokio::time::timeout(Duration::from_secs(5), bound)
+            .await
+            .unwrap()
+            .unwrap();
+        // No listener is returned to serve the site with
+        assert!(listener.is_none());
+    }
+
+    #[tokio::test]
+    async fn ranges_of_a_changed_file_are_precondition_failed() {
+        use tower::ServiceExt;
+
+        let dir = temp_dir::TempDir::new(&[("a.txt", "0123456789")]);
+        let app = site_routes(&dir, &site());
+        let ranged = |since: &str| {
+            axum::http::Request::get("/a.txt")
+                .header(header::RANGE, "bytes=0-3")
+                .header(header::IF_UNMODIFIED_SINCE, since)
+                .body(axum::body::Body::empty())
+                .unwrap()
+        };
+        let response = app
+            .clone()
+            .oneshot(ranged("Mon, 01 Jan 2001 00:00:00 GMT"))
+            .await;
+        assert_eq!(response.unwrap().status(), StatusCode::PRECONDITION_FAILED);
+
+        let plain = axum::http::Request::get("/a.txt")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(plain).await.unwrap();
+        let modified = response.headers()[header::LAST_MODIFIED].to_str().unwrap();
+        let response = app.oneshot(ranged(modified)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes 0-3/10");
+    }
+
+    #[tokio::test]
+    async fn a_connection_close_request_ends_the_connection() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let dir = temp_dir::TempDir::new(&[("index.html", "index")]);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = site_routes(&dir, &site());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        // Reading to the end only returns once the server closed the connection
+        let mut response = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+            .await
+            .unwrap()
+            .unwrap();
+        let response = String::from_utf8(response).unwrap().to_ascii_lowercase();
+        assert!(response.starts_with("http/1.1 200 ok\r\n"), "{response}");
+        assert!(response.contains("\r\nconnection: close\r\n"), "{response}");
+        assert!(response.ends_with("\r\n\r\nindex"), "{response}");
+        assert_eq!(stream.read(&mut [0; 1]).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn the_byte_budget_applies_below_the_base_path() {
+        use tower::ServiceExt;
+
+        let text = "text ".repeat(20);
+        let dir = temp_dir::TempDir::new(&[("a.txt", &text), ("b.txt", &text)]);
+        let budget = Arc::new(compression::ByteBudget::new(
+            150,
+            compression::BudgetAction::Identity,
+        ));
+        let site = Site {
+            byte_budget: Some(budget),
+            ..site()
+        };
+        let app = Router::new()
+            .fallback_service(site_routes(&dir, &site))
+            .layer(axum::middleware::from_fn_with_state(
+                Arc::<str>::from("/docs"),
+                middleware::base_path,
+            ))
+            .layer(compression::layer(
+                tower_http::compression::CompressionLevel::Default,
+                true,
+            ));
+        let gzip = |uri: &str| {
+            axum::http::Request::get(uri)
+                .header(header::ACCEPT_ENCODING, "gzip")
+                .body(axum::body::Body::empty())
+                .unwrap()
+        };
+        let first = app.clone().oneshot(gzip("/docs/a.txt")).await.unwrap();
+        assert_eq!(first.headers()[header::CONTENT_ENCODING], "gzip");
+        let second = app.oneshot(gzip("/docs/b.txt")).await.unwrap();
+        assert_eq!(second.status(), StatusCode::OK);
+        assert!(!second.headers().contains_key(header::CONTENT_ENCODING));
+    }
+}
@@ -0,0 +1,2005 @@
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{
+        header,
+        uri::{Authority, PathAndQuery},
+        HeaderName, HeaderValue, Method, StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use bytes::{Bytes, BytesMut};
+use futures_util::StreamExt;
+use http_body_util::BodyExt;
+
+use crate::{etag, glob::Glob};
+
+/// How plaintext requests are answered when HTTPS is required.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HttpsPolicy {
+    /// Respond with `426 Upgrade Required`.
+    Upgrade,
+    /// Redirect permanently to the `https` URL of the request.
+    Redirect,
+}
+
+impl std::str::FromStr for HttpsPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "426" | "upgrade" => Ok(Self::Upgrade),
+            "redirect" => Ok(Self::Redirect),
+            _ => Err(s.to_string()),
+        }
+    }
+}
+
+/// Applies the [`HttpsPolicy`] to requests that did not arrive over TLS.
+///
+/// The server itself does not terminate TLS, so a request is considered secure
+/// if the reverse proxy marked it with `X-Forwarded-Proto: https`.
+pub async fn require_https(
+    State(policy): State<HttpsPolicy>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let secure = req
+        .headers()
+        .get("x-forwarded-proto")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.eq_ignore_ascii_case("https"));
+    if secure {
+        return next.run(req).await;
+    }
+
+    match policy {
+        HttpsPolicy::Upgrade => (
+            StatusCode::UPGRADE_REQUIRED,
+            [
+                (
+                    header::UPGRADE,
+                    HeaderValue::from_static("TLS/1.2, HTTP/1.1"),
+                ),
+                (header::CONNECTION, HeaderValue::from_static("Upgrade")),
+            ],
+        )
+            .into_response(),
+        HttpsPolicy::Redirect => {
+            // The port of a plaintext request is not the port of the HTTPS
+            // endpoint, so only the host is kept.
+            let host = req
+                .headers()
+                .get(header::HOST)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<Authority>().ok())
+                .or_else(|| req.uri().authority().cloned());
+            let target = req.uri().path_and_query().map_or("/", PathAndQuery::as_str);
+            host.and_then(|host| {
+                HeaderValue::from_str(&format!("https://{}{target}", host.host())).ok()
+            })
+            .map_or_else(
+                || StatusCode::BAD_REQUEST.into_response(),
+                |location| {
+                    (
+                        StatusCode::PERMANENT_REDIRECT,
+                        [(header::LOCATION, location)],
+                    )
+                        .into_response()
+                },
+            )
+        }
+    }
+}
+
+/// Buffers response bodies of at most `threshold` bytes so they are sent with a
+/// `Content-Length` instead of chunked encoding.
+///
+/// Bodies exceeding the threshold are streamed, starting with the bytes already
+/// read.
+pub async fn buffer_small(State(threshold): State<usize>, req: Request, next: Next) -> Response {
+    let head = req.method() == Method::HEAD;
+    let response = next.run(req).await;
+    let status = response.status();
+    if head
+        || status.is_informational()
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::NOT_MODIFIED
+        || response.headers().contains_key(header::CONTENT_LENGTH)
+    {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    match buffer_up_to(body, threshold).await {
+        Ok(Buffered::Complete(bytes)) => {
+            parts.headers.remove(header::TRANSFER_ENCODING);
+            parts
+                .headers
+                .insert(header::CONTENT_LENGTH, HeaderValue::from(bytes.len()));
+            Response::from_parts(parts, Body::from(bytes))
+        }
+        Ok(Buffered::Exceeded(body)) => Response::from_parts(parts, body),
+        Err(e) => {
+            tracing::error!("failed to buffer response: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// A response body read up to a limit.
+enum Buffered {
+    /// The complete body of at most the limit.
+    Complete(Bytes),
+    /// The body exceeding the limit, starting with the bytes already read.
+    Exceeded(Body),
+}
+
+/// Reads `body` until it ends or exceeds `limit` bytes.
+async fn buffer_up_to(mut body: Body, limit: usize) -> Result<Buffered, axum::Error> {
+    let mut buffer = BytesMut::new();
+    while let Some(frame) = body.frame().await {
+        if let Ok(data) = frame?.into_data() {
+            buffer.extend_from_slice(&data);
+        }
+        if buffer.len() > limit {
+            let head = futures_util::stream::once(async move { Ok(buffer.freeze()) });
+            return Ok(Buffered::Exceeded(Body::from_stream(
+                head.chain(body.into_data_stream()),
+            )));
+        }
+    }
+    Ok(Buffered::Complete(buffer.freeze()))
+}
+
+/// Rejects requests with more than one `Host` header or a syntactically invalid
+/// host with `400 Bad Request`, as both are used for request smuggling.
+///
+/// HTTP/1.1 requests must carry a `Host` header, later versions may use the
+/// authority of the request target instead.
+pub async fn validate_host(req: Request, next: Next) -> Response {
+    let mut hosts = req.headers().get_all(header::HOST).iter();
+    let valid = match (hosts.next(), hosts.next()) {
+        (Some(host), None) => host.to_str().is_ok_and(is_valid_host),
+        (None, None) => req.version() != axum::http::Version::HTTP_11,
+        _ => false,
+    };
+    if valid {
+        next.run(req).await
+    } else {
+        StatusCode::BAD_REQUEST.into_response()
+    }
+}
+
+fn is_valid_host(value: &str) -> bool {
+    let Ok(authority) = value.parse::<Authority>() else {
+        return false;
+    };
+    if value.contains('@') {
+        return false;
+    }
+    let host = authority.host();
+    if let Some(ip) = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+        return ip.parse::<std::net::Ipv6Addr>().is_ok();
+    }
+    // A fully qualified name may end with the dot of the root label
+    let host = host.strip_suffix('.').unwrap_or(host);
+    !host.is_empty()
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        })
+}
+
+/// Rejects requests whose path has more than `max_depth` segments with
+/// `400 Bad Request` before the file system is touched.
+pub async fn max_path_depth(State(max_depth): State<usize>, req: Request, next: Next) -> Response {
+    let depth = req
+        .uri()
+        .path()
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .count();
+    if depth > max_depth {
+        StatusCode::BAD_REQUEST.into_response()
+    } else {
+        next.run(req).await
+    }
+}
+
+/// Rejects requests with more than `max_count` headers with
+/// `431 Request Header Fields Too Large` and logs the header counts of the
+/// others and their responses at level "debug".
+///
+/// An abnormal number of headers may indicate abuse or a misconfigured client.
+/// The counts are recorded as `http_request_headers` and
+/// `http_response_headers` if the "metrics" feature is enabled.
+pub async fn max_header_count(
+    State(max_count): State<usize>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let count = req.headers().len();
+    #[cfg(feature = "metrics")]
+    #[allow(clippy::cast_precision_loss)]
+    metrics::histogram!("http_request_headers").record(count as f64);
+    if count > max_count {
+        tracing::warn!(
+            "rejecting '{}' with {} headers, more than {}",
+            req.uri().path(),
+            count,
+            max_count
+        );
+        return StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE.into_response();
+    }
+    let path = req.uri().path().to_string();
+    let response = next.run(req).await;
+    #[cfg(feature = "metrics")]
+    #[allow(clippy::cast_precision_loss)]
+    metrics::histogram!("http_response_headers").record(response.headers().len() as f64);
+    tracing::debug!(
+        "'{}': {} request headers, {} response headers",
+        path,
+        count,
+        response.headers().len()
+    );
+    response
+}
+
+/// Rejects request targets other than the origin-form `/path?query` with
+/// `400 Bad Request`.
+///
+/// The absolute-form `http://host/path` must be accepted from clients and
+/// `OPTIONS *` is answered with `200 OK` and the methods of the server in
+/// `Allow`, but the asterisk-form of any other method and the authority-form
+/// `host:port` of any method but `CONNECT` are rejected.
+pub async fn request_target(req: Request, next: Next) -> Response {
+    let uri = req.uri();
+    let asterisk = uri == "*";
+    if asterisk && req.method() == Method::OPTIONS {
+        return allowed_methods();
+    }
+    let authority = uri.scheme().is_none() && uri.authority().is_some();
+    let valid = !asterisk && (!authority || req.method() == Method::CONNECT);
+    if valid {
+        next.run(req).await
+    } else {
+        StatusCode::BAD_REQUEST.into_response()
+    }
+}
+
+/// Rejects requests whose path is not validly percent-encoded with
+/// `400 Bad Request`.
+///
+/// A valid path only contains complete `%XX` escapes that decode to UTF-8
+/// without a NUL byte, so e.g. "/my%20file.txt" serves "my file.txt" while
+/// "/%ZZ" or "/%C3%28" are rejected instead of being looked up.
+pub async fn percent_decoding(req: Request, next: Next) -> Response {
+    let path = req.uri().path();
+    let complete = path.split('%').skip(1).all(|escape| {
+        escape
+            .as_bytes()
+            .get(..2)
+            .is_some_and(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+    });
+    let valid = complete
+        && percent_encoding::percent_decode_str(path)
+            .decode_utf8()
+            .is_ok_and(|decoded| !decoded.contains('\0'));
+    if valid {
+        next.run(req).await
+    } else {
+        StatusCode::BAD_REQUEST.into_response()
+    }
+}
+
+/// The names reserved for devices on Windows, with or without an extension.
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Rejects requests whose decoded path has a segment that is a reserved
+/// Windows device name like "CON" or "nul.txt", or that ends with a dot or a
+/// space, with `400 Bad Request`.
+///
+/// Such names may refer to a device or to another file on Windows or
+/// case-insensitive file systems, which strip trailing dots and spaces.
+pub async fn strict_names(req: Request, next: Next) -> Response {
+    let path = percent_encoding::percent_decode_str(req.uri().path()).decode_utf8_lossy();
+    let ambiguous = path
+        .split('/')
+        .filter(|segment| !segment.is_empty() && *segment != "." && *segment != "..")
+        .any(|segment| {
+            let stem = segment.split('.').next().unwrap_or_default().trim_end();
+            segment.ends_with(['.', ' '])
+                || RESERVED_NAMES
+                    .iter()
+                    .any(|name| name.eq_ignore_ascii_case(stem))
+        });
+    if ambiguous {
+        StatusCode::BAD_REQUEST.into_response()
+    } else {
+        next.run(req).await
+    }
+}
+
+/// Answers requests carrying an `Expect` header without waiting for a body.
+///
+/// Request bodies are never read, so `Expect: 100-continue` is answered with
+/// the final response right away, or with `417 Expectation Failed` if `reject`
+/// is set. Any other expectation cannot be met and is rejected.
+pub async fn expect(State(reject): State<bool>, req: Request, next: Next) -> Response {
+    let Some(expect) = req.headers().get(header::EXPECT) else {
+        return next.run(req).await;
+    };
+    let continue_100 = expect
+        .to_str()
+        .is_ok_and(|v| v.trim().eq_ignore_ascii_case("100-continue"));
+    if continue_100 && !reject {
+        next.run(req).await
+    } else {
+        StatusCode::EXPECTATION_FAILED.into_response()
+    }
+}
+
+/// What happens to requests exceeding the maximum number of ranges.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RangeAction {
+    /// Respond with `416 Range Not Satisfiable`.
+    Reject,
+    /// Ignore the `Range` header and serve the full body.
+    Full,
+}
+
+impl std::str::FromStr for RangeAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "reject" | "416" => Ok(Self::Reject),
+            "full" => Ok(Self::Full),
+            _ => Err(s.to_string()),
+        }
+    }
+}
+
+/// Limits the number of ranges in a single `Range` header, as overlapping
+/// ranges can be used to amplify the work done per request.
+pub async fn max_ranges(
+    State((max, action)): State<(usize, RangeAction)>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let ranges = req
+        .headers()
+        .get(header::RANGE)
+        .map_or(0, |v| v.as_bytes().split(|&b| b == b',').count());
+    if ranges <= max {
+        return next.run(req).await;
+    }
+
+    match action {
+        RangeAction::Reject => StatusCode::RANGE_NOT_SATISFIABLE.into_response(),
+        RangeAction::Full => {
+            req.headers_mut().remove(header::RANGE);
+            next.run(req).await
+        }
+    }
+}
+
+/// Ignores `Range` headers of units other than `bytes`, which are not
+/// supported, so the full body is served instead of `416 Range Not
+/// Satisfiable`. The unit is case-insensitive, so e.g. `Bytes` is accepted.
+pub async fn range_unit(mut req: Request, next: Next) -> Response {
+    let Some(range) = req.headers().get(header::RANGE) else {
+        return next.run(req).await;
+    };
+    let (unit, ranges) = range
+        .to_str()
+        .ok()
+        .and_then(|range| range.split_once('='))
+        .unwrap_or_default();
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        req.headers_mut().remove(header::RANGE);
+    } else if unit != "bytes" {
+        match HeaderValue::from_str(&format!("bytes={ranges}")) {
+            Ok(range) => req.headers_mut().insert(header::RANGE, range),
+            Err(_) => req.headers_mut().remove(header::RANGE),
+        };
+    }
+    next.run(req).await
+}
+
+/// Sets `Service-Worker-Allowed` on JavaScript responses, allowing service
+/// workers to control a scope beyond their own directory.
+pub async fn service_worker_allowed(
+    State(scope): State<HeaderValue>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let mut response = next.run(req).await;
+    let javascript = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("javascript"));
+    if javascript {
+        response
+            .headers_mut()
+            .insert("service-worker-allowed", scope);
+    }
+    response
+}
+
+/// Asks clients to send the client hints `accept_ch` with subsequent requests
+/// and to retry the request with the `critical_ch` among them.
+pub async fn client_hints(
+    State((accept_ch, critical_ch)): State<(HeaderValue, Option<HeaderValue>)>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let mut response = next.run(req).await;
+    let headers = response.headers_mut();
+    headers.insert(HeaderName::from_static("accept-ch"), accept_ch);
+    if let Some(critical_ch) = critical_ch {
+        headers.insert(HeaderName::from_static("critical-ch"), critical_ch);
+    }
+    response
+}
+
+/// The window over which the request rate is measured.
+const SPIKE_WINDOW: Duration = Duration::from_secs(1);
+
+/// The requests in the current window, detecting a spike once they exceed
+/// `threshold`.
+pub struct SpikeDetector {
+    threshold: u64,
+    window: Mutex<(Instant, u64)>,
+}
+
+impl SpikeDetector {
+    pub fn new(threshold: u64) -> Self {
+        Self {
+            threshold,
+            window: Mutex::new((Instant::now(), 0)),
+        }
+    }
+
+    /// Counts a request, returning whether it is the first to exceed the
+    /// threshold in the current window.
+    fn count(&self) -> bool {
+        let mut window = self.window.lock().unwrap();
+        if window.0.elapsed() >= SPIKE_WINDOW {
+            *window = (Instant::now(), 0);
+        }
+        window.1 += 1;
+        let spike = window.1 == self.threshold + 1;
+        drop(window);
+        spike
+    }
+}
+
+/// Logs a warning when more requests than the threshold of `detector` arrive
+/// within a second, e.g. from scraping or an attack.
+///
+/// A spike is logged once per second and counted as `http_rate_spike_total`
+/// if the "metrics" feature is enabled.
+pub async fn detect_spikes(
+    State(detector): State<Arc<SpikeDetector>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if detector.count() {
+        tracing::warn!(
+            "request rate spike: more than {} requests/s",
+            detector.threshold
+        );
+        #[cfg(feature = "metrics")]
+        metrics::counter!("http_rate_spike_total").increment(1);
+    }
+    next.run(req).await
+}
+
+/// Answers `OPTIONS` requests for files below `root` with `200 OK` and the
+/// methods they can be requested with in `Allow`, instead of
+/// `405 Method Not Allowed`.
+pub async fn options(State(root): State<Arc<Path>>, req: Request, next: Next) -> Response {
+    if req.method() != Method::OPTIONS {
+        return next.run(req).await;
+    }
+    let Some(path) = etag::resolve(&root, req.uri().path()) else {
+        return next.run(req).await;
+    };
+    if !tokio::fs::metadata(&path)
+        .await
+        .is_ok_and(|meta| meta.is_file())
+    {
+        return next.run(req).await;
+    }
+    allowed_methods()
+}
+
+/// The methods the server answers requests with, for `OPTIONS` requests.
+fn allowed_methods() -> Response {
+    (
+        [
+            (
+                header::ALLOW,
+                HeaderValue::from_static("GET, HEAD, OPTIONS"),
+            ),
+            (header::CONTENT_LENGTH, HeaderValue::from_static("0")),
+        ],
+        Body::empty(),
+    )
+        .into_response()
+}
+
+/// Parses a comma-separated list of `status=location` redirects, e.g. "404=/".
+pub fn parse_status_redirects(s: &str) -> Result<Arc<[(StatusCode, HeaderValue)]>, String> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (status, location) = entry.split_once('=').ok_or_else(|| entry.to_string())?;
+            let status = status
+                .trim()
+                .parse::<StatusCode>()
+                .ok()
+                .filter(|status| status.is_client_error() || status.is_server_error())
+                .ok_or_else(|| entry.to_string())?;
+            let location = HeaderValue::from_str(location.trim())
+                .ok()
+                .filter(|location| !location.is_empty())
+                .ok_or_else(|| entry.to_string())?;
+            Ok((status, location))
+        })
+        .collect()
+}
+
+/// Redirects `GET` and `HEAD` requests answered with one of the statuses of
+/// `redirects` to its location with `302 Found` instead of serving the error.
+///
+/// Requests for the location itself are answered with the error, so a missing
+/// location does not redirect to itself in a loop.
+pub async fn status_redirect(
+    State(redirects): State<Arc<[(StatusCode, HeaderValue)]>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return next.run(req).await;
+    }
+    let path = req.uri().path().to_string();
+    let response = next.run(req).await;
+    let Some((_, location)) = redirects
+        .iter()
+        .find(|(status, _)| *status == response.status())
+    else {
+        return response;
+    };
+    let target = location
+        .to_str()
+        .unwrap_or_default()
+        .split(['?', '#'])
+        .next()
+        .unwrap_or_default();
+    if target == path {
+        return response;
+    }
+    (StatusCode::FOUND, [(header::LOCATION, location.clone())]).into_response()
+}
+
+/// Asks clients to clear the site data `value`, e.g. `"cookies"`, on responses
+/// to the logout paths matching `paths`.
+pub async fn clear_site_data(
+    State((paths, value)): State<(Arc<[Glob]>, HeaderValue)>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let clear = paths.iter().any(|glob| glob.matches(req.uri().path()));
+    let mut response = next.run(req).await;
+    if clear {
+        response
+            .headers_mut()
+            .insert(HeaderName::from_static("clear-site-data"), value);
+    }
+    response
+}
+
+/// Always sends `Cache-Control: no-cache` for the service worker scripts at
+/// `paths`, so browsers revalidate them and pick up new versions.
+pub async fn service_worker_no_cache(
+    State(paths): State<Arc<[String]>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let service_worker = paths.iter().any(|path| path == req.uri().path());
+    let mut response = next.run(req).await;
+    if service_worker {
+        response
+            .headers_mut()
+            .insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+    }
+    response
+}
+
+/// Serves the site below the path `prefix`, which is stripped before the file
+/// is resolved.
+///
+/// The prefix itself is redirected to the prefix with a trailing slash, paths
+/// outside the prefix are not found. Redirects to absolute paths issued by the
+/// site, e.g. for directories, are prefixed accordingly.
+pub async fn base_path(State(prefix): State<Arc<str>>, mut req: Request, next: Next) -> Response {
+    let path = req.uri().path();
+    let query = req
+        .uri()
+        .query()
+        .map(|q| format!("?{q}"))
+        .unwrap_or_default();
+    if path == &*prefix {
+        return HeaderValue::from_str(&format!("{prefix}/{query}")).map_or_else(
+            |_| StatusCode::BAD_REQUEST.into_response(),
+            |location| {
+                (
+                    StatusCode::PERMANENT_REDIRECT,
+                    [(header::LOCATION, location)],
+                )
+                    .into_response()
+            },
+        );
+    }
+    let Some(stripped) = path
+        .strip_prefix(&*prefix)
+        .filter(|rest| rest.starts_with('/'))
+    else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let mut parts = req.uri().clone().into_parts();
+    parts.path_and_query = match format!("{stripped}{query}").parse::<PathAndQuery>() {
+        Ok(path_and_query) => Some(path_and_query),
+        Err(_) => return StatusCode::BAD_REQUEST.into_response(),
+    };
+    match axum::http::Uri::from_parts(parts) {
+        Ok(uri) => *req.uri_mut() = uri,
+        Err(_) => return StatusCode::BAD_REQUEST.into_response(),
+    }
+
+    let mut response = next.run(req).await;
+    let location = response
+        .headers()
+        .get(header::LOCATION)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| v.starts_with('/') && !v.starts_with("//"))
+        .and_then(|v| HeaderValue::from_str(&format!("{prefix}{v}")).ok());
+    if let Some(location) = location {
+        response.headers_mut().insert(header::LOCATION, location);
+    }
+    response
+}
+
+/// Rejects requests whose framing is ambiguous or unsupported.
+///
+/// A request with both `Content-Length` and `Transfer-Encoding` is a classic
+/// request smuggling attempt and rejected with `400 Bad Request`. Transfer
+/// codings other than `chunked` are rejected with `501 Not Implemented`.
+pub async fn transfer_encoding(req: Request, next: Next) -> Response {
+    let mut codings = req
+        .headers()
+        .get_all(header::TRANSFER_ENCODING)
+        .iter()
+        .peekable();
+    if codings.peek().is_none() {
+        return next.run(req).await;
+    }
+    if req.headers().contains_key(header::CONTENT_LENGTH) {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+    let supported = codings.all(|v| {
+        v.to_str().is_ok_and(|v| {
+            v.split(',')
+                .all(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+        })
+    });
+    if supported {
+        next.run(req).await
+    } else {
+        StatusCode::NOT_IMPLEMENTED.into_response()
+    }
+}
+
+/// Disables caching of every response for development.
+///
+/// Validators are removed from both the request and the response, so the
+/// browser neither revalidates nor reuses a response and always gets the
+/// current file from disk.
+pub async fn dev_no_cache(mut req: Request, next: Next) -> Response {
+    req.headers_mut().remove(header::IF_NONE_MATCH);
+    req.headers_mut().remove(header::IF_MODIFIED_SINCE);
+    let mut response = next.run(req).await;
+    let headers = response.headers_mut();
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    headers.remove(header::ETAG);
+    headers.remove(header::LAST_MODIFIED);
+    headers.remove(header::EXPIRES);
+    response
+}
+
+/// The placeholder in HTML replaced by the nonce of the response.
+const CSP_NONCE_PLACEHOLDER: &str = "{{csp_nonce}}";
+
+/// Replaces the `{{csp_nonce}}` placeholders in HTML pages by a random nonce
+/// per response and allows the scripts carrying it with
+/// `Content-Security-Policy: script-src 'nonce-...'`.
+///
+/// As each response differs, pages are sent with `Cache-Control: no-store`
+/// and without validators or `Range` support, and conditional and range
+/// requests for them are served in full. Pages larger than `max_size` are
+/// served unchanged without a nonce to bound the memory of buffering them,
+/// pages of unknown length like the built-in 404 page are read up to it.
+pub async fn csp_nonce(State(max_size): State<u64>, mut req: Request, next: Next) -> Response {
+    let path = req.uri().path();
+    let html_path = path.ends_with('/')
+        || std::path::Path::new(path)
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("html") || ext.eq_ignore_ascii_case("htm"));
+    if html_path {
+        let headers = req.headers_mut();
+        headers.remove(header::RANGE);
+        headers.remove(header::IF_NONE_MATCH);
+        headers.remove(header::IF_MODIFIED_SINCE);
+        // Precompressed pages could not be rewritten
+        headers.remove(header::ACCEPT_ENCODING);
+    }
+    let head = req.method() == Method::HEAD;
+    let response = next.run(req).await;
+    let is_html = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .is_some_and(|v| v.trim().eq_ignore_ascii_case("text/html"));
+    let status = response.status();
+    if !is_html
+        || (status != StatusCode::OK && status != StatusCode::NOT_FOUND)
+        || response.headers().contains_key(header::CONTENT_ENCODING)
+    {
+        return response;
+    }
+    let len = response
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if let Some(len) = len.filter(|&len| len > max_size) {
+        tracing::warn!(
+            "serving html of {} bytes without csp nonce, larger than {} bytes",
+            len,
+            max_size
+        );
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    // Pages without a length, like the built-in 404 page, are read up to the
+    // limit
+    let bytes = if head {
+        None
+    } else {
+        match buffer_up_to(body, usize::try_from(max_size).unwrap_or(usize::MAX)).await {
+            Ok(Buffered::Complete(bytes)) => Some(bytes),
+            Ok(Buffered::Exceeded(body)) => {
+                tracing::warn!(
+                    "serving html larger than {} bytes without csp nonce",
+                    max_size
+                );
+                return Response::from_parts(parts, body);
+            }
+            Err(e) => {
+                tracing::error!("failed to read html response: {}", e);
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        }
+    };
+
+    let nonce = format!("{:032x}", rand::random::<u128>());
+    let headers = &mut parts.headers;
+    headers.append(
+        header::CONTENT_SECURITY_POLICY,
+        HeaderValue::from_str(&format!("script-src 'nonce-{nonce}'"))
+            .expect("hex is a valid header value"),
+    );
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("none"));
+    headers.remove(header::ETAG);
+    headers.remove(header::LAST_MODIFIED);
+    let Some(bytes) = bytes else {
+        headers.remove(header::CONTENT_LENGTH);
+        return Response::from_parts(parts, Body::empty());
+    };
+
+    let bytes = match std::str::from_utf8(&bytes) {
+        Ok(html) if html.contains(CSP_NONCE_PLACEHOLDER) => {
+            Bytes::from(html.replace(CSP_NONCE_PLACEHOLDER, &nonce))
+        }
+        _ => bytes,
+    };
+    parts
+        .headers
+        .insert(header::CONTENT_LENGTH, HeaderValue::from(bytes.len()));
+    Response::from_parts(parts, Body::from(bytes))
+}
+
+/// Pretty-prints JSON files, e.g. to serve mock API responses readably.
+///
+/// Ranges of `.json` files are ignored, as they would refer to the file rather
+/// than the pretty-printed response. Files that are not valid JSON or larger
+/// than `max_size` bytes are served unchanged.
+pub async fn pretty_json(State(max_size): State<usize>, mut req: Request, next: Next) -> Response {
+    let json_path = std::path::Path::new(req.uri().path())
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("json"));
+    if json_path {
+        req.headers_mut().remove(header::RANGE);
+    }
+    let head = req.method() == Method::HEAD;
+    let mut response = next.run(req).await;
+    let is_json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .is_some_and(|v| v.trim().eq_ignore_ascii_case("application/json"));
+    if !json_path || !is_json || response.status() != StatusCode::OK {
+        return response;
+    }
+    response
+        .headers_mut()
+        .insert(header::ACCEPT_RANGES, HeaderValue::from_static("none"));
+    if head {
+        response.headers_mut().remove(header::CONTENT_LENGTH);
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let bytes = match buffer_up_to(body, max_size).await {
+        Ok(Buffered::Complete(bytes)) => bytes,
+        Ok(Buffered::Exceeded(body)) => {
+            tracing::warn!("serving json larger than {} bytes unchanged", max_size);
+            return Response::from_parts(parts, body);
+        }
+        Err(e) => {
+            tracing::error!("failed to read json response: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let pretty = serde_json::from_slice::<serde_json::Value>(&bytes)
+        .and_then(|value| serde_json::to_vec_pretty(&value));
+    let bytes = match pretty {
+        Ok(mut pretty) => {
+            pretty.push(b'\n');
+            Bytes::from(pretty)
+        }
+        Err(e) => {
+            tracing::warn!("serving invalid json unchanged: {}", e);
+            bytes
+        }
+    };
+    parts
+        .headers
+        .insert(header::CONTENT_LENGTH, HeaderValue::from(bytes.len()));
+    Response::from_parts(parts, Body::from(bytes))
+}
+
+/// Rejects requests with a body with `413 Payload Too Large` before it is read.
+///
+/// Static files are only ever requested without a body, so any body is wasted
+/// bandwidth and memory.
+pub async fn reject_bodies(req: Request, next: Next) -> Response {
+    let has_body = req.headers().contains_key(header::TRANSFER_ENCODING)
+        || req
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .is_some_and(|len| len != "0");
+    if has_body {
+        StatusCode::PAYLOAD_TOO_LARGE.into_response()
+    } else {
+        next.run(req).await
+    }
+}
+
+/// The statuses of requests rejected without reading their body.
+const DRAIN_STATUSES: [StatusCode; 3] = [
+    StatusCode::METHOD_NOT_ALLOWED,
+    StatusCode::PAYLOAD_TOO_LARGE,
+    StatusCode::EXPECTATION_FAILED,
+];
+
+/// The time a rejected body is drained at most.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Reads up to `max` bytes of the body of requests rejected before their body
+/// was read, i.e. with `405`, `413` or `417`, so clients still sending it do
+/// not see the connection reset.
+///
+/// Bodies whose `Content-Length` exceeds `max` are not drained, as the
+/// connection is reset anyway, and draining stops after [`DRAIN_TIMEOUT`].
+/// Requests expecting `100 Continue` are not drained, as reading the body
+/// would ask the client to send it.
+pub async fn drain_rejected(State(max): State<usize>, req: Request, next: Next) -> Response {
+    let (parts, body) = req.into_parts();
+    let continues = parts
+        .headers
+        .get(header::EXPECT)
+        .is_some_and(|expect| expect.as_bytes().eq_ignore_ascii_case(b"100-continue"));
+    let len = parts
+        .headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    let response = next.run(Request::from_parts(parts, Body::empty())).await;
+    if DRAIN_STATUSES.contains(&response.status())
+        && !continues
+        && len.is_none_or(|len| len > 0 && len <= max as u64)
+    {
+        let drain = http_body_util::Limited::new(body, max).collect();
+        match tokio::time::timeout(DRAIN_TIMEOUT, drain).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => tracing::debug!("not draining rejected body: {}", e),
+            Err(_) => tracing::debug!("draining rejected body timed out"),
+        }
+    }
+    response
+}
+
+/// Ends the body of a response once the request has taken `max` in total.
+///
+/// Unlike the request timeout, which fails requests whose response is not
+/// ready in time, the data sent so far is kept, so a client can resume the
+/// download with a range request.
+pub async fn max_response_time(State(max): State<Duration>, req: Request, next: Next) -> Response {
+    let deadline = tokio::time::Instant::now() + max;
+    let path = req.uri().path().to_string();
+    let (parts, body) = next.run(req).await.into_parts();
+    let stream = futures_util::stream::unfold(body.into_data_stream(), move |mut stream| {
+        let path = path.clone();
+        async move {
+            match tokio::time::timeout_at(deadline, stream.next()).await {
+                Ok(Some(data)) => Some((data, stream)),
+                Ok(None) => None,
+                Err(_) => {
+                    tracing::warn!("response to '{}' exceeded {} ms", path, max.as_millis());
+                    None
+                }
+            }
+        }
+    });
+    Response::from_parts(parts, Body::from_stream(stream))
+}
+
+/// Marks a response generated by the server rather than served from a file.
+#[derive(Debug, Clone, Copy)]
+pub struct Generated;
+
+/// Sends [`Generated`] responses with `Accept-Ranges: none`.
+///
+/// Generated responses have no stable representation to take ranges of, so a
+/// `Range` header is ignored and the full body is served.
+pub async fn generated_ranges(req: Request, next: Next) -> Response {
+    let mut response = next.run(req).await;
+    if response.extensions().get::<Generated>().is_some() {
+        response
+            .headers_mut()
+            .insert(header::ACCEPT_RANGES, HeaderValue::from_static("none"));
+    }
+    response
+}
+
+/// Answers requests with `406 Not Acceptable` if the `Accept` header excludes
+/// the type of a [`Generated`] response.
+///
+/// Files are always served as they are, since they have no other
+/// representation.
+pub async fn strict_accept(req: Request, next: Next) -> Response {
+    let accept = req.headers().get(header::ACCEPT).cloned();
+    let response = next.run(req).await;
+    let Some(accept) = accept.as_ref().and_then(|v| v.to_str().ok()) else {
+        return response;
+    };
+    if response.extensions().get::<Generated>().is_none() {
+        return response;
+    }
+    let content_type = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("application/octet-stream");
+    if accepts(accept, content_type) {
+        response
+    } else {
+        StatusCode::NOT_ACCEPTABLE.into_response()
+    }
+}
+
+/// Returns whether `content_type` is acceptable, i.e. the most specific media
+/// range of `accept` matching it has a non-zero quality.
+fn accepts(accept: &str, content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or("").trim();
+    let (kind, subtype) = essence.split_once('/').unwrap_or((essence, ""));
+    accept
+        .split(',')
+        .filter_map(|range| {
+            let mut params = range.split(';');
+            let (range_kind, range_subtype) = params.next()?.trim().split_once('/')?;
+            let specificity = match (range_kind, range_subtype) {
+                ("*", "*") => 0,
+                (range_kind, "*") if range_kind.eq_ignore_ascii_case(kind) => 1,
+                (range_kind, range_subtype)
+                    if range_kind.eq_ignore_ascii_case(kind)
+                        && range_subtype.eq_ignore_ascii_case(subtype) =>
+                {
+                    2
+                }
+                _ => return None,
+            };
+            let quality = params
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .and_then(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some((specificity, quality))
+        })
+        .max_by_key(|&(specificity, _)| specificity)
+        .is_some_and(|(_, quality)| quality > 0.0)
+}
+
+/// Answers requests for files below `root` modified less than `delay` ago
+/// with `503 Service Unavailable`, so a file that is still being written by a
+/// non-atomic deploy is not served partially. Requests for directories are
+/// passed through.
+pub async fn withhold_fresh(
+    State((delay, root)): State<(Duration, Arc<Path>)>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Some(path) = crate::etag::resolve(&root, req.uri().path()) else {
+        return next.run(req).await;
+    };
+    // Directories are redirected or listed, their mtime changes with any file
+    let age = tokio::fs::metadata(&path)
+        .await
+        .ok()
+        .filter(std::fs::Metadata::is_file)
+        .and_then(|meta| meta.modified().ok())
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok());
+    match age {
+        Some(age) if age < delay => {
+            tracing::info!(
+                "withholding '{}' modified {} ms ago",
+                path.display(),
+                age.as_millis()
+            );
+            let retry_after = delay.saturating_sub(age).as_secs() + 1;
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                [(header::RETRY_AFTER, HeaderValue::from(retry_after))],
+            )
+                .into_response()
+        }
+        _ => next.run(req).await,
+    }
+}
+
+/// The environment the site is deployed to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Environment {
+    Production,
+    Staging,
+    Development,
+}
+
+impl std::str::FromStr for Environment {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "production" | "prod" => Ok(Self::Production),
+            "staging" => Ok(Self::Staging),
+            "development" | "dev" => Ok(Self::Development),
+            _ => Err(s.to_string()),
+        }
+    }
+}
+
+const ROBOTS_DISALLOW_ALL: &str = "User-agent: *\nDisallow: /\n";
+
+/// Keeps crawlers away from a non-production site.
+///
+/// Every response is sent with `X-Robots-Tag: noindex` and a missing
+/// `/robots.txt` is answered with one disallowing everything. A `robots.txt`
+/// in the document root is still served.
+pub async fn no_robots(req: Request, next: Next) -> Response {
+    let robots = req.uri().path() == "/robots.txt";
+    let response = next.run(req).await;
+    let mut response = if robots && response.status() == StatusCode::NOT_FOUND {
+        (
+            [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+            Extension(Generated),
+            ROBOTS_DISALLOW_ALL,
+        )
+            .into_response()
+    } else {
+        response
+    };
+    response.headers_mut().insert(
+        HeaderName::from_static("x-robots-tag"),
+        HeaderValue::from_static("noindex"),
+    );
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{routing::get, Json, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn app() -> Router {
+        Router::new().route("/", get(|| async { "ok" }))
+    }
+
+    async fn send(app: Router, req: Request) -> Response {
+        app.oneshot(req).await.unwrap()
+    }
+
+    fn get_with_host(host: &str) -> Request {
+        Request::get("/")
+            .header(header::HOST, host)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn validate_host_accepts_a_trailing_dot() {
+        let app = app().layer(axum::middleware::from_fn(validate_host));
+        for host in ["localhost", "localhost.", "example.com.:8080", "[::1]"] {
+            let response = send(app.clone(), get_with_host(host)).await;
+            assert_eq!(response.status(), StatusCode::OK, "{host}");
+        }
+        for host in [".", "localhost..", "a..b", "user@localhost", "bad host"] {
+            let response = send(app.clone(), get_with_host(host)).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{host}");
+        }
+    }
+
+    fn request(method: Method, uri: &str) -> Request {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn request_target_answers_options_asterisk() {
+        let app = app().layer(axum::middleware::from_fn(request_target));
+        let response = send(app.clone(), request(Method::OPTIONS, "*")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::ALLOW], "GET, HEAD, OPTIONS");
+        let response = send(app.clone(), request(Method::GET, "*")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = send(app.clone(), request(Method::GET, "example.com:80")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = send(app, request(Method::GET, "http://example.com/")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    fn streamed(html: &'static str) -> Response {
+        let stream = futures_util::stream::iter(
+            html.as_bytes()
+                .chunks(8)
+                .map(|chunk| Ok::<_, axum::Error>(Bytes::from_static(chunk))),
+        );
+        (
+            [(header::CONTENT_TYPE, "text/html")],
+            Body::from_stream(stream),
+        )
+            .into_response()
+    }
+
+    async fn body(response: Response) -> Bytes {
+        response.into_body().collect().await.unwrap().to_bytes()
+    }
+
+    const PAGE: &str = "<script nonce=\"{{csp_nonce}}\"></script>";
+
+    #[tokio::test]
+    async fn buffer_small_sets_the_length_of_small_bodies() {
+        let app = Router::new()
+            .route("/small", get(|| async { streamed("tiny") }))
+            .route("/large", get(|| async { streamed(PAGE) }))
+            .layer(axum::middleware::from_fn_with_state(16, buffer_small));
+        let response = send(app.clone(), request(Method::GET, "/small")).await;
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], "4");
+        assert_eq!(body(response).await, "tiny");
+        let response = send(app, request(Method::GET, "/large")).await;
+        assert!(!response.headers().contains_key(header::CONTENT_LENGTH));
+        assert_eq!(body(response).await, PAGE);
+    }
+
+    #[tokio::test]
+    async fn csp_nonce_is_injected_into_pages_of_unknown_length() {
+        let app = Router::new()
+            .route("/page.html", get(|| async { streamed(PAGE) }))
+            .layer(axum::middleware::from_fn_with_state(1024, csp_nonce));
+        let response = send(app, request(Method::GET, "/page.html")).await;
+        let csp = response.headers()[header::CONTENT_SECURITY_POLICY]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let nonce = csp
+            .strip_prefix("script-src 'nonce-")
+            .and_then(|csp| csp.strip_suffix('\''))
+            .unwrap()
+            .to_string();
+        assert_eq!(response.headers()[header::CACHE_CONTROL], "no-store");
+        let html = body(response).await;
+        assert_eq!(html, format!("<script nonce=\"{nonce}\"></script>"));
+    }
+
+    #[tokio::test]
+    async fn csp_nonce_skips_pages_over_the_limit() {
+        let app = Router::new()
+            .route("/streamed.html", get(|| async { streamed(PAGE) }))
+            .route(
+                "/sized.html",
+                get(|| async {
+                    (
+                        [
+                            (header::CONTENT_TYPE, "text/html"),
+                            (header::CONTENT_LENGTH, "39"),
+                        ],
+                        PAGE,
+                    )
+                }),
+            )
+            .layer(axum::middleware::from_fn_with_state(16, csp_nonce));
+        for uri in ["/streamed.html", "/sized.html"] {
+            let response = send(app.clone(), request(Method::GET, uri)).await;
+            assert!(!response
+                .headers()
+                .contains_key(header::CONTENT_SECURITY_POLICY));
+            assert_eq!(body(response).await, PAGE, "{uri}");
+        }
+    }
+
+    #[tokio::test]
+    async fn csp_nonce_is_injected_into_not_found_pages() {
+        let app = Router::new()
+            .fallback(|| async {
+                (
+                    StatusCode::NOT_FOUND,
+                    axum::response::Html("<p>{{csp_nonce}}</p>"),
+                )
+            })
+            .layer(axum::middleware::from_fn_with_state(1024, csp_nonce));
+        let response = send(app, request(Method::GET, "/missing")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(response
+            .headers()
+            .contains_key(header::CONTENT_SECURITY_POLICY));
+        assert!(!body(response).await.starts_with(b"<p>{{"));
+    }
+
+    /// A request whose body records whether it was read.
+    fn tracked(
+        method: Method,
+        uri: &str,
+        headers: &[(HeaderName, &str)],
+    ) -> (Request, Arc<std::sync::atomic::AtomicBool>) {
+        let read = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let flag = read.clone();
+        let body = Body::from_stream(futures_util::stream::once(async move {
+            flag.store(true, std::sync::atomic::Ordering::Relaxed);
+            Ok::<_, axum::Error>(Bytes::from_static(b"body"))
+        }));
+        let mut req = Request::builder().method(method).uri(uri);
+        for (name, value) in headers {
+            req = req.header(name, *value);
+        }
+        (req.body(body).unwrap(), read)
+    }
+
+    #[tokio::test]
+    async fn drain_rejected_reads_bodies_rejected_before_reading() {
+        let app = app()
+            .route(
+                "/upload",
+                axum::routing::put(|| async { StatusCode::PAYLOAD_TOO_LARGE }),
+            )
+            .fallback(|| async { StatusCode::NOT_FOUND })
+            .layer(axum::middleware::from_fn_with_state(16, drain_rejected));
+        let cases = [
+            (Method::POST, "/", vec![(header::CONTENT_LENGTH, "4")], true),
+            (Method::POST, "/", vec![], true),
+            (
+                Method::PUT,
+                "/upload",
+                vec![(header::CONTENT_LENGTH, "4")],
+                true,
+            ),
+            (
+                Method::PUT,
+                "/upload",
+                vec![(header::CONTENT_LENGTH, "100")],
+                false,
+            ),
+            (
+                Method::PUT,
+                "/upload",
+                vec![
+                    (header::CONTENT_LENGTH, "4"),
+                    (header::EXPECT, "100-continue"),
+                ],
+                false,
+            ),
+            (
+                Method::GET,
+                "/missing",
+                vec![(header::CONTENT_LENGTH, "4")],
+                false,
+            ),
+        ];
+        for (method, uri, headers, drained) in cases {
+            let (req, read) = tracked(method.clone(), uri, &headers);
+            let response = send(app.clone(), req).await;
+            assert!(response.status().is_client_error());
+            assert_eq!(
+                read.load(std::sync::atomic::Ordering::Relaxed),
+                drained,
+                "{method} {uri} {headers:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn require_https_upgrades_or_redirects_plaintext_requests() {
+        let secure = || {
+            Request::get("/")
+                .header("x-forwarded-proto", "HTTPS")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let upgrade = app().layer(axum::middleware::from_fn_with_state(
+            HttpsPolicy::Upgrade,
+            require_https,
+        ));
+        let response = send(upgrade.clone(), get_with_host("example.com")).await;
+        assert_eq!(response.status(), StatusCode::UPGRADE_REQUIRED);
+        assert_eq!(response.headers()[header::UPGRADE], "TLS/1.2, HTTP/1.1");
+        assert_eq!(send(upgrade, secure()).await.status(), StatusCode::OK);
+
+        let redirect =
+            Router::new()
+                .fallback(|| async { "ok" })
+                .layer(axum::middleware::from_fn_with_state(
+                    HttpsPolicy::Redirect,
+                    require_https,
+                ));
+        let req = Request::get("/a?b=c")
+            .header(header::HOST, "example.com:8080")
+            .body(Body::empty())
+            .unwrap();
+        let response = send(redirect.clone(), req).await;
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(
+            response.headers()[header::LOCATION],
+            "https://example.com/a?b=c"
+        );
+        let response = send(redirect.clone(), request(Method::GET, "/")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(send(redirect, secure()).await.status(), StatusCode::OK);
+        assert_eq!("426".parse(), Ok(HttpsPolicy::Upgrade));
+        assert!("off".parse::<HttpsPolicy>().is_err());
+    }
+
+    #[tokio::test]
+    async fn validate_host_rejects_duplicate_and_missing_hosts() {
+        let app = app().layer(axum::middleware::from_fn(validate_host));
+        let duplicate = Request::get("/")
+            .header(header::HOST, "example.com")
+            .header(header::HOST, "example.com")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(
+            send(app.clone(), duplicate).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+        let missing = send(app.clone(), request(Method::GET, "/")).await;
+        assert_eq!(missing.status(), StatusCode::BAD_REQUEST);
+        let http2 = Request::get("/")
+            .version(axum::http::Version::HTTP_2)
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(send(app, http2).await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn max_path_depth_counts_non_empty_segments() {
+        let app = Router::new()
+            .fallback(|| async { "ok" })
+            .layer(axum::middleware::from_fn_with_state(2, max_path_depth));
+        for (uri, status) in [
+            ("/a/b", StatusCode::OK),
+            ("//a//b/", StatusCode::OK),
+            ("/a/b/c", StatusCode::BAD_REQUEST),
+        ] {
+            let response = send(app.clone(), request(Method::GET, uri)).await;
+            assert_eq!(response.status(), status, "{uri}");
+        }
+    }
+
+    #[tokio::test]
+    async fn expect_answers_without_reading_the_body() {
+        for (reject, value, status) in [
+            (false, "100-Continue", StatusCode::OK),
+            (true, "100-continue", StatusCode::EXPECTATION_FAILED),
+            (false, "something-else", StatusCode::EXPECTATION_FAILED),
+        ] {
+            let app = app().layer(axum::middleware::from_fn_with_state(reject, expect));
+            let (req, read) = tracked(Method::GET, "/", &[(header::EXPECT, value)]);
+            let response = send(app, req).await;
+            assert_eq!(response.status(), status, "{reject} {value}");
+            assert!(!read.load(std::sync::atomic::Ordering::Relaxed));
+        }
+        let app = app().layer(axum::middleware::from_fn_with_state(true, expect));
+        let response = send(app, request(Method::GET, "/")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    /// A router answering with the `Range` header it received, or "none".
+    fn echo_range() -> Router {
+        Router::new().fallback(|headers: axum::http::HeaderMap| async move {
+            headers
+                .get(header::RANGE)
+                .map_or_else(|| "none".to_string(), |v| v.to_str().unwrap().to_string())
+        })
+    }
+
+    fn get_range(range: &str) -> Request {
+        Request::get("/")
+            .header(header::RANGE, range)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn max_ranges_rejects_or_ignores_too_many_ranges() {
+        let reject = echo_range().layer(axum::middleware::from_fn_with_state(
+            (2, RangeAction::Reject),
+            max_ranges,
+        ));
+        let response = send(reject.clone(), get_range("bytes=0-1,2-3")).await;
+        assert_eq!(body(response).await, "bytes=0-1,2-3");
+        let response = send(reject, get_range("bytes=0-1,2-3,4-5")).await;
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+
+        let full = echo_range().layer(axum::middleware::from_fn_with_state(
+            (2, RangeAction::Full),
+            max_ranges,
+        ));
+        let response = send(full, get_range("bytes=0-1,2-3,4-5")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body(response).await, "none");
+    }
+
+    #[tokio::test]
+    async fn service_worker_allowed_is_sent_with_scripts() {
+        let dir = crate::temp_dir::TempDir::new(&[
+            ("sw.js", "self.skipWaiting()"),
+            ("app.webmanifest", "{}"),
+            ("index.html", "<p></p>"),
+        ]);
+        let app = Router::new()
+            .fallback_service(tower_http::services::ServeDir::new(&*dir))
+            .layer(axum::middleware::from_fn_with_state(
+                HeaderValue::from_static("/"),
+                service_worker_allowed,
+            ));
+        let response = send(app.clone(), request(Method::GET, "/sw.js")).await;
+        assert_eq!(response.headers()["service-worker-allowed"], "/");
+        let response = send(app.clone(), request(Method::GET, "/index.html")).await;
+        assert!(!response.headers().contains_key("service-worker-allowed"));
+        let response = send(app, request(Method::GET, "/app.webmanifest")).await;
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/manifest+json"
+        );
+    }
+
+    #[tokio::test]
+    async fn service_worker_no_cache_marks_the_configured_paths() {
+        let app = Router::new()
+            .fallback(|| async { ([(header::CACHE_CONTROL, "max-age=3600")], "ok") })
+            .layer(axum::middleware::from_fn_with_state(
+                Arc::from(["/sw.js".to_string()]),
+                service_worker_no_cache,
+            ));
+        let response = send(app.clone(), request(Method::GET, "/sw.js")).await;
+        assert_eq!(response.headers()[header::CACHE_CONTROL], "no-cache");
+        let response = send(app, request(Method::GET, "/app/sw.js")).await;
+        assert_eq!(response.headers()[header::CACHE_CONTROL], "max-age=3600");
+    }
+
+    #[tokio::test]
+    async fn base_path_serves_the_site_below_the_prefix() {
+        let site = Router::new()
+            .route("/", get(|| async { "index" }))
+            .route(
+                "/page",
+                get(|uri: axum::http::Uri| async move { uri.to_string() }),
+            )
+            .route(
+                "/dir",
+                get(|| async { axum::response::Redirect::permanent("/dir/") }),
+            );
+        let app = Router::new()
+            .fallback_service(site)
+            .layer(axum::middleware::from_fn_with_state(
+                Arc::from("/docs"),
+                base_path,
+            ));
+        let response = send(app.clone(), request(Method::GET, "/docs?a=1")).await;
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(response.headers()[header::LOCATION], "/docs/?a=1");
+        let response = send(app.clone(), request(Method::GET, "/docs/")).await;
+        assert_eq!(body(response).await, "index");
+        let response = send(app.clone(), request(Method::GET, "/docs/page?b=2")).await;
+        assert_eq!(body(response).await, "/page?b=2");
+        let response = send(app.clone(), request(Method::GET, "/docs/dir")).await;
+        assert_eq!(response.headers()[header::LOCATION], "/docs/dir/");
+        for uri in ["/page", "/docsx/page"] {
+            let response = send(app.clone(), request(Method::GET, uri)).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{uri}");
+        }
+    }
+
+    #[tokio::test]
+    async fn transfer_encoding_rejects_ambiguous_and_unknown_codings() {
+        let app = app().layer(axum::middleware::from_fn(transfer_encoding));
+        let get_with = |headers: &[(HeaderName, &str)]| {
+            let mut req = Request::get("/");
+            for (name, value) in headers {
+                req = req.header(name, *value);
+            }
+            send(app.clone(), req.body(Body::empty()).unwrap())
+        };
+        let response = get_with(&[(header::TRANSFER_ENCODING, "Chunked")]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = get_with(&[(header::CONTENT_LENGTH, "0")]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = get_with(&[
+            (header::TRANSFER_ENCODING, "chunked"),
+            (header::CONTENT_LENGTH, "0"),
+        ])
+        .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        for coding in ["gzip, chunked", "identity"] {
+            let response = get_with(&[(header::TRANSFER_ENCODING, coding)]).await;
+            assert_eq!(response.status(), StatusCode::NOT_IMPLEMENTED, "{coding}");
+        }
+    }
+
+    #[tokio::test]
+    async fn dev_no_cache_removes_the_validators() {
+        let app = Router::new()
+            .fallback(|headers: axum::http::HeaderMap| async move {
+                let conditional = headers.contains_key(header::IF_NONE_MATCH)
+                    || headers.contains_key(header::IF_MODIFIED_SINCE);
+                (
+                    [
+                        (header::ETAG, "\"a\""),
+                        (header::LAST_MODIFIED, "Thu, 01 Jan 1970 00:00:00 GMT"),
+                        (header::CACHE_CONTROL, "max-age=60"),
+                    ],
+                    conditional.to_string(),
+                )
+            })
+            .layer(axum::middleware::from_fn(dev_no_cache));
+        let req = Request::get("/")
+            .header(header::IF_NONE_MATCH, "\"a\"")
+            .header(header::IF_MODIFIED_SINCE, "Thu, 01 Jan 1970 00:00:00 GMT")
+            .body(Body::empty())
+            .unwrap();
+        let response = send(app, req).await;
+        assert_eq!(response.headers()[header::CACHE_CONTROL], "no-store");
+        assert!(!response.headers().contains_key(header::ETAG));
+        assert!(!response.headers().contains_key(header::LAST_MODIFIED));
+        assert_eq!(body(response).await, "false");
+    }
+
+    #[tokio::test]
+    async fn pretty_json_formats_json_files() {
+        let json = |body: &'static str| {
+            move || async move { ([(header::CONTENT_TYPE, "application/json")], body) }
+        };
+        let app = Router::new()
+            .route("/a.json", get(json("{\"a\":[1]}")))
+            .route("/invalid.json", get(json("{")))
+            .route("/api", get(json("{\"a\":1}")))
+            .layer(axum::middleware::from_fn_with_state(1024, pretty_json));
+        let response = send(app.clone(), request(Method::GET, "/a.json")).await;
+        assert_eq!(response.headers()[header::ACCEPT_RANGES], "none");
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], "23");
+        assert_eq!(body(response).await, "{\n  \"a\": [\n    1\n  ]\n}\n");
+        let response = send(app.clone(), request(Method::GET, "/invalid.json")).await;
+        assert_eq!(body(response).await, "{");
+        let response = send(app, request(Method::GET, "/api")).await;
+        assert_eq!(body(response).await, "{\"a\":1}");
+    }
+
+    #[tokio::test]
+    async fn pretty_json_serves_larger_files_unchanged() {
+        let app = Router::new()
+            .route(
+                "/a.json",
+                get(|| async {
+                    (
+                        [(header::CONTENT_TYPE, "application/json")],
+                        "{\"a\":[1,2]}",
+                    )
+                }),
+            )
+            .layer(axum::middleware::from_fn_with_state(8, pretty_json));
+        let response = send(app, request(Method::GET, "/a.json")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body(response).await, "{\"a\":[1,2]}");
+    }
+
+    #[tokio::test]
+    async fn reject_bodies_answers_before_reading_the_body() {
+        let app = Router::new()
+            .fallback(|| async { "ok" })
+            .layer(axum::middleware::from_fn(reject_bodies));
+        let with = |name: HeaderName, value: &str| {
+            Request::post("/")
+                .header(name, value)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let response = send(app.clone(), with(header::CONTENT_LENGTH, "0")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = send(app.clone(), with(header::CONTENT_LENGTH, "5")).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let response = send(app.clone(), with(header::TRANSFER_ENCODING, "chunked")).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let response = send(app, request(Method::GET, "/")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn max_response_time_ends_the_body_keeping_the_data_sent() {
+        let app = Router::new()
+            .fallback(|| async {
+                let stream = futures_util::stream::once(async {
+                    Ok::<_, axum::Error>(Bytes::from_static(b"sent"))
+                })
+                .chain(futures_util::stream::pending());
+                Body::from_stream(stream)
+            })
+            .layer(axum::middleware::from_fn_with_state(
+                Duration::from_millis(20),
+                max_response_time,
+            ));
+        let response = send(app, request(Method::GET, "/")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body(response).await, "sent");
+    }
+
+    #[tokio::test]
+    async fn percent_decoding_rejects_invalid_escapes() {
+        let app = Router::new()
+            .fallback(|| async { "ok" })
+            .layer(axum::middleware::from_fn(percent_decoding));
+        for uri in ["/my%20file.txt", "/caf%C3%A9", "/a?q=%ZZ"] {
+            let response = send(app.clone(), request(Method::GET, uri)).await;
+            assert_eq!(response.status(), StatusCode::OK, "{uri}");
+        }
+        for uri in ["/%ZZ", "/%2", "/%C3%28", "/a%00b"] {
+            let response = send(app.clone(), request(Method::GET, uri)).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{uri}");
+        }
+    }
+
+    #[test]
+    fn accepts_uses_the_most_specific_media_range() {
+        assert!(accepts("text/html, */*;q=0.1", "application/json"));
+        assert!(accepts("TEXT/*", "text/plain; charset=utf-8"));
+        assert!(!accepts("text/html", "application/json"));
+        assert!(!accepts("application/*;q=0, */*", "application/json"));
+        assert!(accepts(
+            "application/*;q=0, application/json",
+            "application/json"
+        ));
+    }
+
+    #[tokio::test]
+    async fn strict_accept_only_applies_to_generated_responses() {
+        let app = Router::new()
+            .route("/file", get(|| async { Json(1) }))
+            .route(
+                "/generated",
+                get(|| async { (Extension(Generated), Json(1)) }),
+            )
+            .layer(axum::middleware::from_fn(strict_accept));
+        let accepting = |uri: &str, accept: &str| {
+            Request::get(uri)
+                .header(header::ACCEPT, accept)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let response = send(app.clone(), accepting("/generated", "text/html")).await;
+        assert_eq!(response.status(), StatusCode::NOT_ACCEPTABLE);
+        let response = send(app.clone(), accepting("/generated", "application/json")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = send(app.clone(), accepting("/file", "text/html")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = send(app, request(Method::GET, "/generated")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn withhold_fresh_answers_files_still_being_written() {
+        let dir = crate::temp_dir::TempDir::new(&[("old.txt", "old"), ("new.txt", "new")]);
+        std::fs::File::options()
+            .write(true)
+            .open(dir.join("old.txt"))
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(90))
+            .unwrap();
+        let app =
+            Router::new()
+                .fallback(|| async { "ok" })
+                .layer(axum::middleware::from_fn_with_state(
+                    (Duration::from_secs(30), Arc::from(&*dir)),
+                    withhold_fresh,
+                ));
+        let response = send(app.clone(), request(Method::GET, "/new.txt")).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let retry_after = response.headers()[header::RETRY_AFTER].to_str().unwrap();
+        assert!((30..=31).contains(&retry_after.parse::<u64>().unwrap()));
+        for uri in ["/old.txt", "/missing.txt"] {
+            let response = send(app.clone(), request(Method::GET, uri)).await;
+            assert_eq!(response.status(), StatusCode::OK, "{uri}");
+        }
+    }
+
+    #[tokio::test]
+    async fn withhold_fresh_passes_directories_through() {
+        // The directory was just modified by creating the file in it
+        let dir = crate::temp_dir::TempDir::new(&[("docs/a.txt", "a")]);
+        let app =
+            Router::new()
+                .fallback(|| async { "ok" })
+                .layer(axum::middleware::from_fn_with_state(
+                    (Duration::from_secs(30), Arc::from(&*dir)),
+                    withhold_fresh,
+                ));
+        for uri in ["/docs", "/docs/"] {
+            let response = send(app.clone(), request(Method::GET, uri)).await;
+            assert_eq!(response.status(), StatusCode::OK, "{uri}");
+        }
+    }
+
+    #[tokio::test]
+    async fn generated_responses_refuse_ranges() {
+        let app = Router::new()
+            .route("/file", get(|| async { "file" }))
+            .route(
+                "/generated",
+                get(|| async { (Extension(Generated), "generated") }),
+            )
+            .layer(axum::middleware::from_fn(generated_ranges));
+        let response = send(app.clone(), request(Method::GET, "/generated")).await;
+        assert_eq!(response.headers()[header::ACCEPT_RANGES], "none");
+        let response = send(app, request(Method::GET, "/file")).await;
+        assert!(!response.headers().contains_key(header::ACCEPT_RANGES));
+    }
+
+    #[tokio::test]
+    async fn no_robots_disallows_crawling_unless_the_site_has_a_robots_txt() {
+        let app = Router::new()
+            .route("/", get(|| async { "index" }))
+            .layer(axum::middleware::from_fn(no_robots));
+        let response = send(app.clone(), request(Method::GET, "/")).await;
+        assert_eq!(response.headers()["x-robots-tag"], "noindex");
+        let response = send(app, request(Method::GET, "/robots.txt")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-robots-tag"], "noindex");
+        assert_eq!(body(response).await, ROBOTS_DISALLOW_ALL);
+
+        let app = Router::new()
+            .route("/robots.txt", get(|| async { "User-agent: *\n" }))
+            .layer(axum::middleware::from_fn(no_robots));
+        let response = send(app, request(Method::GET, "/robots.txt")).await;
+        assert_eq!(body(response).await, "User-agent: *\n");
+        assert_eq!("Dev".parse(), Ok(Environment::Development));
+        assert_eq!("qa".parse::<Environment>(), Err("qa".to_string()));
+    }
+
+    #[tokio::test]
+    async fn range_unit_only_keeps_byte_ranges() {
+        let app = echo_range().layer(axum::middleware::from_fn(range_unit));
+        for (range, expected) in [
+            ("bytes=0-1", "bytes=0-1"),
+            ("Bytes=0-1,5-", "bytes=0-1,5-"),
+            ("items=0-1", "none"),
+            ("bytes", "none"),
+        ] {
+            let response = send(app.clone(), get_range(range)).await;
+            assert_eq!(body(response).await, expected, "{range}");
+        }
+    }
+
+    #[tokio::test]
+    async fn client_hints_are_requested_with_every_response() {
+        let hints = |critical_ch: Option<&'static str>| {
+            app().layer(axum::middleware::from_fn_with_state(
+                (
+                    HeaderValue::from_static("DPR, Viewport-Width"),
+                    critical_ch.map(HeaderValue::from_static),
+                ),
+                client_hints,
+            ))
+        };
+        let response = send(hints(Some("DPR")), request(Method::GET, "/")).await;
+        assert_eq!(response.headers()["accept-ch"], "DPR, Viewport-Width");
+        assert_eq!(response.headers()["critical-ch"], "DPR");
+        let response = send(hints(None), request(Method::GET, "/")).await;
+        assert!(!response.headers().contains_key("critical-ch"));
+    }
+
+    #[tokio::test]
+    async fn clear_site_data_is_sent_on_the_logout_paths() {
+        let app =
+            Router::new()
+                .fallback(|| async { "ok" })
+                .layer(axum::middleware::from_fn_with_state(
+                    (
+                        crate::glob::parse_list("/logout,/account/*/logout").unwrap(),
+                        HeaderValue::from_static("\"cookies\", \"storage\""),
+                    ),
+                    clear_site_data,
+                ));
+        for uri in ["/logout", "/account/a/logout"] {
+            let response = send(app.clone(), request(Method::GET, uri)).await;
+            assert_eq!(
+                response.headers()["clear-site-data"],
+                "\"cookies\", \"storage\"",
+                "{uri}"
+            );
+        }
+        let response = send(app, request(Method::GET, "/login")).await;
+        assert!(!response.headers().contains_key("clear-site-data"));
+    }
+
+    #[tokio::test]
+    async fn strict_names_rejects_reserved_and_ambiguous_names() {
+        let app = Router::new()
+            .fallback(|| async { "ok" })
+            .layer(axum::middleware::from_fn(strict_names));
+        for uri in ["/console.txt", "/a/com10", "/./file", "/d/x%2Ey"] {
+            let response = send(app.clone(), request(Method::GET, uri)).await;
+            assert_eq!(response.status(), StatusCode::OK, "{uri}");
+        }
+        for uri in [
+            "/CON",
+            "/a/nul.txt",
+            "/Lpt1%20.log",
+            "/file.",
+            "/file%20",
+            "/dir./x",
+        ] {
+            let response = send(app.clone(), request(Method::GET, uri)).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{uri}");
+        }
+    }
+
+    #[tokio::test]
+    async fn detect_spikes_warns_once_per_window() {
+        let detector = Arc::new(SpikeDetector::new(2));
+        let app = app().layer(axum::middleware::from_fn_with_state(
+            detector.clone(),
+            detect_spikes,
+        ));
+        let (captured, _guard) = crate::captured::Captured::start();
+        for _ in 0..5 {
+            send(app.clone(), request(Method::GET, "/")).await;
+        }
+        assert_eq!(
+            captured.lines(),
+            ["request rate spike: more than 2 requests/s"]
+        );
+
+        // The next window counts from zero
+        detector.window.lock().unwrap().0 -= SPIKE_WINDOW;
+        assert_eq!([0; 3].map(|_| detector.count()), [false, false, true]);
+    }
+
+    #[tokio::test]
+    async fn options_lists_the_methods_of_files() {
+        let dir = crate::temp_dir::TempDir::new(&[("a.txt", "a"), ("dir/index.html", "")]);
+        let app = Router::new()
+            .fallback_service(tower_http::services::ServeDir::new(&*dir))
+            .layer(axum::middleware::from_fn_with_state(
+                Arc::from(&*dir),
+                options,
+            ));
+        for uri in ["/a.txt", "/dir/"] {
+            let response = send(app.clone(), request(Method::OPTIONS, uri)).await;
+            assert_eq!(response.status(), StatusCode::OK, "{uri}");
+            assert_eq!(response.headers()[header::ALLOW], "GET, HEAD, OPTIONS");
+        }
+        let response = send(app, request(Method::OPTIONS, "/missing.txt")).await;
+        assert_ne!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn status_redirect_sends_errors_to_their_location() {
+        assert!(parse_status_redirects("200=/").is_err());
+        assert!(parse_status_redirects("404=").is_err());
+        let redirects = parse_status_redirects("404=/?missing, 503=/maintenance.html").unwrap();
+        let app = Router::new()
+            .route("/", get(|| async { StatusCode::NOT_FOUND }))
+            .route("/error", get(|| async { StatusCode::SERVICE_UNAVAILABLE }))
+            .route(
+                "/post",
+                axum::routing::post(|| async { StatusCode::NOT_FOUND }),
+            )
+            .fallback(|| async { StatusCode::NOT_FOUND })
+            .layer(axum::middleware::from_fn_with_state(
+                redirects,
+                status_redirect,
+            ));
+        let response = send(app.clone(), request(Method::GET, "/missing")).await;
+        assert_eq!(response.status(), StatusCode::FOUND);
+        assert_eq!(response.headers()[header::LOCATION], "/?missing");
+        let response = send(app.clone(), request(Method::HEAD, "/error")).await;
+        assert_eq!(response.headers()[header::LOCATION], "/maintenance.html");
+        // The location itself and other methods are answered with the error
+        let response = send(app.clone(), request(Method::GET, "/")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = send(app, request(Method::POST, "/post")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn max_header_count_rejects_requests_with_too_many_headers() {
+        let app = app().layer(axum::middleware::from_fn_with_state(2, max_header_count));
+        let with_headers = |count: usize| {
+            let mut req = Request::get("/");
+            for i in 0..count {
+                req = req.header(format!("x-header-{i}"), "1");
+            }
+            req.body(Body::empty()).unwrap()
+        };
+        let response = send(app.clone(), with_headers(2)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = send(app, with_headers(3)).await;
+        assert_eq!(
+            response.status(),
+            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+        );
+    }
+}
@@ -0,0 +1,111 @@
+use std::{path::Path, sync::Arc, time::Duration};
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use futures_util::StreamExt;
+use tokio::sync::Semaphore;
+
+use crate::etag;
+
+/// The slots of the files that can be open at the same time while serving.
+pub struct OpenFiles {
+    slots: Arc<Semaphore>,
+    max: usize,
+    wait: Duration,
+}
+
+impl OpenFiles {
+    pub fn new(max: usize, wait: Duration) -> Self {
+        Self {
+            slots: Arc::new(Semaphore::new(max)),
+            max,
+            wait,
+        }
+    }
+}
+
+/// Bounds the files below `root` open at the same time, so the process does
+/// not run out of file descriptors under heavy concurrency.
+///
+/// A request for a file waits up to the wait of `open_files` for a slot,
+/// otherwise it is answered with `503 Service Unavailable`. The slot is held
+/// until the body of the response is dropped, as the file is open while it is
+/// sent. Requests not served from a file do not take a slot.
+pub async fn limit(
+    State((open_files, root)): State<(Arc<OpenFiles>, Arc<Path>)>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Some(path) = etag::resolve(&root, req.uri().path()) else {
+        return next.run(req).await;
+    };
+    if !tokio::fs::metadata(&path)
+        .await
+        .is_ok_and(|meta| meta.is_file())
+    {
+        return next.run(req).await;
+    }
+    let slots = open_files.slots.clone();
+    let Ok(Ok(slot)) = tokio::time::timeout(open_files.wait, slots.acquire_owned()).await else {
+        tracing::warn!(
+            "all {} open file slots are in use, shedding '{}'",
+            open_files.max,
+            req.uri().path()
+        );
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, HeaderValue::from_static("1"))],
+        )
+            .into_response();
+    };
+
+    next.run(req).await.map(|body| {
+        // The slot is released once the body is dropped
+        let stream = body.into_data_stream().inspect(move |_| {
+            let _ = &slot;
+        });
+        Body::from_stream(stream)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::Router;
+    use tower::ServiceExt;
+    use tower_http::services::ServeDir;
+
+    use super::*;
+    use crate::temp_dir::TempDir;
+
+    fn get(uri: &str) -> Request {
+        Request::get(uri).body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn limit_holds_a_slot_while_a_file_is_sent() {
+        let dir = TempDir::new(&[("a.txt", "a"), ("b.txt", "b")]);
+        let open_files = Arc::new(OpenFiles::new(1, Duration::from_millis(10)));
+        let app = Router::new().fallback_service(ServeDir::new(&*dir)).layer(
+            axum::middleware::from_fn_with_state((open_files.clone(), Arc::from(&*dir)), limit),
+        );
+
+        let first = app.clone().oneshot(get("/a.txt")).await.unwrap();
+        assert_eq!(first.status(), StatusCode::OK);
+        let shed = app.clone().oneshot(get("/b.txt")).await.unwrap();
+        assert_eq!(shed.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(shed.headers()[header::RETRY_AFTER], "1");
+        // Responses without a file take no slot
+        let missing = app.clone().oneshot(get("/missing.txt")).await.unwrap();
+        assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+
+        drop(first);
+        assert_eq!(open_files.slots.available_permits(), 1);
+        let second = app.oneshot(get("/b.txt")).await.unwrap();
+        assert_eq!(second.status(), StatusCode::OK);
+    }
+}
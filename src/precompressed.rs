@@ -0,0 +1,518 @@
+use std::{
+    collections::HashMap,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use http_body_util::BodyExt;
+
+use crate::etag;
+
+/// The encodings of precompressed sidecar files and their extensions.
+const ENCODINGS: &[(&str, &str)] = &[("gzip", "gz"), ("br", "br"), ("zstd", "zst")];
+
+/// Validation results of precompressed sidecar files.
+///
+/// A sidecar is validated once per modification time and length of both the
+/// sidecar and its original, as a Brotli stream can only be checked by
+/// decompressing it.
+#[derive(Default)]
+pub struct Sidecars {
+    validated: Mutex<HashMap<PathBuf, (Key, bool)>>,
+}
+
+type Key = (SystemTime, u64, SystemTime, u64);
+
+impl Sidecars {
+    /// Returns whether the sidecar of `original` with `encoding` is valid, or
+    /// `None` if it does not exist.
+    async fn is_valid(&self, original: &Path, encoding: &str, ext: &str) -> Option<bool> {
+        let mut sidecar = original.as_os_str().to_owned();
+        sidecar.push(".");
+        sidecar.push(ext);
+        let sidecar = PathBuf::from(sidecar);
+        let meta = tokio::fs::metadata(&sidecar).await.ok()?;
+        let original_meta = tokio::fs::metadata(original).await.ok()?;
+        let key = (
+            meta.modified().ok()?,
+            meta.len(),
+            original_meta.modified().ok()?,
+            original_meta.len(),
+        );
+        let cached = self
+            .validated
+            .lock()
+            .unwrap()
+            .get(&sidecar)
+            .filter(|(k, _)| *k == key)
+            .map(|(_, valid)| *valid);
+        if cached.is_some() {
+            return cached;
+        }
+
+        let path = sidecar.clone();
+        let encoding = encoding.to_string();
+        let len = original_meta.len();
+        let valid = tokio::task::spawn_blocking(move || validate(&path, &encoding, len))
+            .await
+            .is_ok_and(|valid| valid.unwrap_or(false));
+        if !valid {
+            tracing::warn!(
+                "precompressed '{}' is corrupt, serving the original",
+                sidecar.display()
+            );
+        }
+        self.validated.lock().unwrap().insert(sidecar, (key, valid));
+        Some(valid)
+    }
+}
+
+/// Checks that `path` is a complete stream with `encoding` of `len` bytes.
+///
+/// A gzip stream is checked by its header and the length in its trailer, a
+/// zstd stream by the headers of its frames and blocks, and a Brotli stream by
+/// decompressing it.
+fn validate(path: &Path, encoding: &str, len: u64) -> std::io::Result<bool> {
+    let mut file = std::fs::File::open(path)?;
+    if encoding == "zstd" {
+        is_zstd(&mut file, len)
+    } else if encoding == "gzip" {
+        let mut magic = [0; 3];
+        file.read_exact(&mut magic)?;
+        // The trailer holds the length of the original modulo 2^32
+        let mut size = [0; 4];
+        file.seek(SeekFrom::End(-4))?;
+        file.read_exact(&mut size)?;
+        Ok(magic == [0x1f, 0x8b, 0x08] && u64::from(u32::from_le_bytes(size)) == len & 0xffff_ffff)
+    } else {
+        let mut decompressor = brotli_decompressor::Decompressor::new(file, 64 * 1024);
+        let decompressed = std::io::copy(&mut decompressor, &mut std::io::sink())?;
+        Ok(decompressed == len)
+    }
+}
+
+/// The magic number of a zstd frame.
+const ZSTD_MAGIC: u32 = 0xfd2f_b528;
+
+/// The magic numbers of skippable frames, which carry no content.
+const ZSTD_SKIPPABLE_MAGIC: std::ops::RangeInclusive<u32> = 0x184d_2a50..=0x184d_2a5f;
+
+/// Checks that `file` is a sequence of zstd frames, possibly interleaved with
+/// skippable frames, whose blocks end with the file and whose content is `len`
+/// bytes, if every frame records its content size.
+fn is_zstd(file: &mut std::fs::File, len: u64) -> std::io::Result<bool> {
+    let file_len = file.metadata()?.len();
+    let mut content = Some(0);
+    let mut frames = 0;
+    let mut position = 0;
+    while position < file_len {
+        let mut magic = [0; 4];
+        file.read_exact(&mut magic)?;
+        let magic = u32::from_le_bytes(magic);
+        if magic == ZSTD_MAGIC {
+            if !is_zstd_frame(file, &mut content)? {
+                return Ok(false);
+            }
+            frames += 1;
+        } else if ZSTD_SKIPPABLE_MAGIC.contains(&magic) {
+            let mut size = [0; 4];
+            file.read_exact(&mut size)?;
+            file.seek(SeekFrom::Current(i64::from(u32::from_le_bytes(size))))?;
+        } else {
+            return Ok(false);
+        }
+        position = file.stream_position()?;
+    }
+    Ok(frames > 0 && position == file_len && content.is_none_or(|content| content == len))
+}
+
+/// Checks the frame following its magic number in `file` by the headers of its
+/// blocks, adding its content size to `content` or clearing it if the frame
+/// does not record it.
+fn is_zstd_frame(file: &mut std::fs::File, content: &mut Option<u64>) -> std::io::Result<bool> {
+    let mut descriptor = [0; 1];
+    file.read_exact(&mut descriptor)?;
+    let [descriptor] = descriptor;
+    let single_segment = descriptor & 0x20 != 0;
+    let checksum = descriptor & 0x04 != 0;
+    let window = i64::from(!single_segment);
+    let dictionary = [0, 1, 2, 4][usize::from(descriptor & 0x03)];
+    let size_len = match descriptor >> 6 {
+        0 => usize::from(single_segment),
+        1 => 2,
+        2 => 4,
+        _ => 8,
+    };
+    file.seek(SeekFrom::Current(window + dictionary))?;
+    if size_len > 0 {
+        let mut bytes = [0; 8];
+        file.read_exact(&mut bytes[..size_len])?;
+        let size = u64::from_le_bytes(bytes);
+        // A two byte size is stored minus 256
+        let size = if size_len == 2 { size + 256 } else { size };
+        *content = content.map(|content| content + size);
+    } else {
+        *content = None;
+    }
+
+    loop {
+        let mut block = [0; 4];
+        file.read_exact(&mut block[..3])?;
+        let block = u32::from_le_bytes(block);
+        let payload = match (block >> 1) & 0x03 {
+            // An RLE block repeats a single byte
+            1 => 1,
+            3 => return Ok(false),
+            _ => i64::from(block >> 3),
+        };
+        file.seek(SeekFrom::Current(payload))?;
+        if block & 0x01 != 0 {
+            break;
+        }
+    }
+    if checksum {
+        file.seek(SeekFrom::Current(4))?;
+    }
+    Ok(true)
+}
+
+/// Removes the encodings of corrupt precompressed sidecars of files below
+/// `root` from `Accept-Encoding`, so the original is served instead.
+pub async fn validate_sidecars(
+    State((sidecars, root)): State<(Arc<Sidecars>, Arc<Path>)>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return next.run(req).await;
+    }
+    let Some(accept) = req
+        .headers()
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .map(ToString::to_string)
+    else {
+        return next.run(req).await;
+    };
+    let Some(original) = etag::resolve(&root, req.uri().path()) else {
+        return next.run(req).await;
+    };
+
+    let mut corrupt = Vec::new();
+    for (encoding, ext) in ENCODINGS {
+        if accepts(&accept, encoding)
+            && sidecars.is_valid(&original, encoding, ext).await == Some(false)
+        {
+            corrupt.push(*encoding);
+        }
+    }
+    if !corrupt.is_empty() {
+        let accept = accept
+            .split(',')
+            .filter(|coding| !corrupt.iter().any(|encoding| is_coding(coding, encoding)))
+            .collect::<Vec<_>>()
+            .join(",");
+        match HeaderValue::from_str(&accept) {
+            Ok(accept) => req.headers_mut().insert(header::ACCEPT_ENCODING, accept),
+            Err(_) => req.headers_mut().remove(header::ACCEPT_ENCODING),
+        };
+    }
+    next.run(req).await
+}
+
+/// Serves files below `root` stored only as a Brotli sidecar to clients not
+/// accepting `br` by decompressing the sidecar.
+///
+/// The decompressed response is sent without `Range` support and may be
+/// compressed again by the compression layer.
+pub async fn br_fallback(State(root): State<Arc<Path>>, mut req: Request, next: Next) -> Response {
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return next.run(req).await;
+    }
+    let accept = req
+        .headers()
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    if accepts(accept, "br") {
+        return next.run(req).await;
+    }
+    let Some(original) = etag::resolve(&root, req.uri().path()) else {
+        return next.run(req).await;
+    };
+    let mut sidecar = original.as_os_str().to_owned();
+    sidecar.push(".br");
+    if tokio::fs::try_exists(&original).await.unwrap_or(true)
+        || !tokio::fs::try_exists(&sidecar).await.unwrap_or(false)
+    {
+        return next.run(req).await;
+    }
+
+    let headers = req.headers_mut();
+    headers.insert(header::ACCEPT_ENCODING, HeaderValue::from_static("br"));
+    headers.remove(header::RANGE);
+    let head = req.method() == Method::HEAD;
+    let response = next.run(req).await;
+    if response.headers().get(header::CONTENT_ENCODING) != Some(&HeaderValue::from_static("br")) {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    parts.headers.remove(header::CONTENT_ENCODING);
+    parts
+        .headers
+        .append(header::VARY, HeaderValue::from_static("accept-encoding"));
+    parts
+        .headers
+        .insert(header::ACCEPT_RANGES, HeaderValue::from_static("none"));
+    if head {
+        parts.headers.remove(header::CONTENT_LENGTH);
+        return Response::from_parts(parts, body);
+    }
+
+    let decompressed = match body.collect().await {
+        Ok(collected) => {
+            let compressed = collected.to_bytes();
+            tokio::task::spawn_blocking(move || {
+                let mut decompressed = Vec::new();
+                brotli_decompressor::Decompressor::new(&compressed[..], 64 * 1024)
+                    .read_to_end(&mut decompressed)
+                    .map(|_| decompressed)
+            })
+            .await
+            .map_err(std::io::Error::other)
+            .flatten()
+        }
+        Err(e) => Err(std::io::Error::other(e)),
+    };
+    match decompressed {
+        Ok(decompressed) => {
+            parts.headers.insert(
+                header::CONTENT_LENGTH,
+                HeaderValue::from(decompressed.len()),
+            );
+            Response::from_parts(parts, Body::from(decompressed))
+        }
+        Err(e) => {
+            tracing::error!("failed to decompress '{}': {}", original.display(), e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+fn is_coding(coding: &str, encoding: &str) -> bool {
+    coding
+        .split(';')
+        .next()
+        .is_some_and(|name| name.trim().eq_ignore_ascii_case(encoding))
+}
+
+/// Returns whether the `Accept-Encoding` value `accept` accepts `encoding`.
+pub fn accepts(accept: &str, encoding: &str) -> bool {
+    accept.split(',').any(|coding| {
+        is_coding(coding, encoding)
+            && !coding
+                .split(';')
+                .skip(1)
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .any(|q| q.trim().parse::<f32>().is_ok_and(|q| q <= 0.0))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp_dir::TempDir;
+
+    /// A zstd frame holding `content` in a raw block, recording its size.
+    fn frame(content: &[u8]) -> Vec<u8> {
+        let len = u8::try_from(content.len()).unwrap();
+        let block = (u32::from(len) << 3 | 1).to_le_bytes();
+        let mut frame = vec![0x28, 0xb5, 0x2f, 0xfd, 0x20, len];
+        frame.extend_from_slice(&block[..3]);
+        frame.extend_from_slice(content);
+        frame
+    }
+
+    /// A skippable frame of `len` zero bytes.
+    fn skippable(len: u8) -> Vec<u8> {
+        let mut frame = vec![0x53, 0x2a, 0x4d, 0x18, len, 0, 0, 0];
+        frame.resize(frame.len() + usize::from(len), 0);
+        frame
+    }
+
+    fn is_valid_zstd(bytes: &[u8], len: u64) -> bool {
+        let dir = TempDir::new(&[]);
+        dir.write("file.zst", bytes);
+        validate(&dir.join("file.zst"), "zstd", len).unwrap_or(false)
+    }
+
+    #[test]
+    fn zstd_accepts_a_single_frame() {
+        assert!(is_valid_zstd(&frame(b"hello"), 5));
+        assert!(!is_valid_zstd(&frame(b"hello"), 6));
+    }
+
+    #[test]
+    fn zstd_accepts_a_compressed_frame_with_checksum() {
+        // "abc" repeated 30 times, compressed by the zstd CLI
+        let file = [
+            0x28, 0xb5, 0x2f, 0xfd, 0x24, 0x5a, 0x55, 0x00, 0x00, 0x20, 0x61, 0x62, 0x63, 0x61,
+            0x01, 0x00, 0xe3, 0xe4, 0x88, 0x4a, 0x81, 0x69, 0x4c,
+        ];
+        assert!(is_valid_zstd(&file, 90));
+        assert!(!is_valid_zstd(&file, 89));
+        assert!(!is_valid_zstd(&file[..19], 90));
+    }
+
+    #[test]
+    fn zstd_accepts_multiple_frames() {
+        let file = [frame(b"hello"), frame(b" world")].concat();
+        assert!(is_valid_zstd(&file, 11));
+        assert!(!is_valid_zstd(&file, 5));
+    }
+
+    #[test]
+    fn zstd_accepts_skippable_frames() {
+        let file = [skippable(8), frame(b"hello"), skippable(0)].concat();
+        assert!(is_valid_zstd(&file, 5));
+        assert!(!is_valid_zstd(&skippable(8), 0));
+    }
+
+    #[test]
+    fn zstd_rejects_truncated_files() {
+        let file = [frame(b"hello"), frame(b" world")].concat();
+        for len in [0, 3, 7, file.len() - 1] {
+            assert!(!is_valid_zstd(&file[..len], 11), "{len}");
+        }
+        assert!(!is_valid_zstd(&skippable(8)[..10], 0));
+    }
+
+    #[test]
+    fn zstd_rejects_trailing_garbage() {
+        let file = [frame(b"hello"), vec![0; 4]].concat();
+        assert!(!is_valid_zstd(&file, 5));
+    }
+
+    async fn encoding_of(app: &axum::Router, accept: &str) -> Option<HeaderValue> {
+        use tower::ServiceExt;
+
+        let req = Request::get("/app.js")
+            .header(header::ACCEPT_ENCODING, accept)
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        response.headers().get(header::CONTENT_ENCODING).cloned()
+    }
+
+    fn sidecar_app(dir: &Path) -> axum::Router {
+        axum::Router::new()
+            .fallback_service(tower_http::services::ServeDir::new(dir).precompressed_zstd())
+            .layer(axum::middleware::from_fn_with_state(
+                (Arc::new(Sidecars::default()), Arc::from(dir)),
+                validate_sidecars,
+            ))
+    }
+
+    #[tokio::test]
+    async fn serves_valid_zstd_sidecars_to_clients_accepting_zstd() {
+        let dir = TempDir::new(&[("app.js", "hello")]);
+        dir.write("app.js.zst", frame(b"hello"));
+        let app = sidecar_app(&dir);
+        assert_eq!(encoding_of(&app, "gzip, zstd").await.unwrap(), "zstd");
+        assert_eq!(encoding_of(&app, "gzip, br").await, None);
+        assert_eq!(encoding_of(&app, "zstd;q=0").await, None);
+
+        dir.write("app.js.zst", &frame(b"hello")[..8]);
+        assert_eq!(encoding_of(&sidecar_app(&dir), "zstd").await, None);
+    }
+
+    /// A gzip stream holding `content` in a stored block, without its CRC.
+    fn gzip(content: &[u8]) -> Vec<u8> {
+        let len = u16::try_from(content.len()).unwrap();
+        let mut stream = vec![0x1f, 0x8b, 0x08, 0, 0, 0, 0, 0, 0, 0xff, 0x01];
+        stream.extend_from_slice(&len.to_le_bytes());
+        stream.extend_from_slice(&(!len).to_le_bytes());
+        stream.extend_from_slice(content);
+        stream.extend_from_slice(&[0; 4]);
+        stream.extend_from_slice(&u32::from(len).to_le_bytes());
+        stream
+    }
+
+    fn brotli(content: &[u8]) -> Vec<u8> {
+        let mut compressed = Vec::new();
+        brotli::BrotliCompress(
+            &mut &content[..],
+            &mut compressed,
+            &brotli::enc::BrotliEncoderParams::default(),
+        )
+        .unwrap();
+        compressed
+    }
+
+    #[tokio::test]
+    async fn corrupt_gzip_and_brotli_sidecars_are_not_served() {
+        let dir = TempDir::new(&[("app.js", "hello")]);
+        dir.write("app.js.gz", gzip(b"hello"));
+        dir.write("app.js.br", brotli(b"hello"));
+        let app = axum::Router::new()
+            .fallback_service(
+                tower_http::services::ServeDir::new(&*dir)
+                    .precompressed_gzip()
+                    .precompressed_br(),
+            )
+            .layer(axum::middleware::from_fn_with_state(
+                (Arc::new(Sidecars::default()), Arc::from(&*dir)),
+                validate_sidecars,
+            ));
+        assert_eq!(encoding_of(&app, "gzip").await.unwrap(), "gzip");
+        assert_eq!(encoding_of(&app, "br").await.unwrap(), "br");
+
+        // A changed sidecar is validated again
+        dir.write("app.js.gz", gzip(b"hell"));
+        dir.write("app.js.br", &brotli(b"hello")[..4]);
+        assert_eq!(encoding_of(&app, "gzip").await, None);
+        assert_eq!(encoding_of(&app, "br").await, None);
+        assert_eq!(encoding_of(&app, "br;q=0.5, gzip").await, None);
+    }
+
+    #[tokio::test]
+    async fn br_fallback_decompresses_brotli_only_files() {
+        use tower::ServiceExt;
+
+        let dir = TempDir::new(&[]);
+        dir.write("app.js.br", brotli(b"console.log(1)"));
+        let app = axum::Router::new()
+            .fallback_service(tower_http::services::ServeDir::new(&*dir).precompressed_br())
+            .layer(axum::middleware::from_fn_with_state(
+                Arc::from(&*dir),
+                br_fallback,
+            ));
+        let get = |accept: &str| {
+            let req = Request::get("/app.js")
+                .header(header::ACCEPT_ENCODING, accept)
+                .header(header::RANGE, "bytes=0-1")
+                .body(Body::empty())
+                .unwrap();
+            app.clone().oneshot(req)
+        };
+        let response = get("br").await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "br");
+        let response = get("gzip").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+        assert_eq!(response.headers()[header::ACCEPT_RANGES], "none");
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], "14");
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "console.log(1)");
+    }
+}
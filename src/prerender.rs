@@ -0,0 +1,122 @@
+use std::{path::Path, sync::Arc};
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderValue, Uri},
+    middleware::Next,
+    response::Response,
+};
+
+use crate::etag;
+
+/// The bots served prerendered snapshots and the directory below the document
+/// root holding them.
+pub struct Prerender {
+    bots: Vec<String>,
+    dir: String,
+}
+
+impl Prerender {
+    /// Creates the prerendering for the comma-separated user agents `bots`,
+    /// matched case-insensitively as substrings, e.g. "Googlebot,Bingbot".
+    pub fn new(bots: &str, dir: &str) -> Self {
+        Self {
+            bots: bots
+                .split(',')
+                .map(|bot| bot.trim().to_ascii_lowercase())
+                .filter(|bot| !bot.is_empty())
+                .collect(),
+            dir: dir.trim_matches('/').to_string(),
+        }
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.bots.is_empty()
+    }
+
+    fn is_bot(&self, req: &Request) -> bool {
+        req.headers()
+            .get(header::USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_ascii_lowercase)
+            .is_some_and(|agent| self.bots.iter().any(|bot| agent.contains(bot.as_str())))
+    }
+}
+
+/// Serves the prerendered snapshot of a file below `root`, e.g.
+/// "prerendered/about.html" for "/about.html", to bots if it exists, and the
+/// file itself to browsers. Every response varies by `User-Agent`.
+pub async fn serve(
+    State((prerender, root)): State<(Arc<Prerender>, Arc<Path>)>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    if prerender.is_bot(&req) {
+        if let Some(uri) = snapshot_uri(&prerender.dir, &root, req.uri()).await {
+            *req.uri_mut() = uri;
+        }
+    }
+    let mut response = next.run(req).await;
+    response
+        .headers_mut()
+        .append(header::VARY, HeaderValue::from_static("User-Agent"));
+    response
+}
+
+async fn snapshot_uri(dir: &str, root: &Path, uri: &Uri) -> Option<Uri> {
+    let snapshot = format!("/{dir}{}", uri.path());
+    let file = etag::resolve(root, &snapshot)?;
+    if !tokio::fs::metadata(&file).await.is_ok_and(|m| m.is_file()) {
+        return None;
+    }
+    let snapshot = match uri.query() {
+        Some(query) => format!("{snapshot}?{query}"),
+        None => snapshot,
+    };
+    snapshot.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, Router};
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+    use tower_http::services::ServeDir;
+
+    use super::*;
+    use crate::temp_dir::TempDir;
+
+    #[tokio::test]
+    async fn bots_are_served_the_snapshot_if_it_exists() {
+        let dir = TempDir::new(&[
+            ("about.html", "app"),
+            ("contact.html", "app"),
+            ("prerendered/about.html", "snapshot"),
+        ]);
+        let prerender = Prerender::new("Googlebot, ,bingbot", "/prerendered/");
+        assert!(!prerender.is_empty());
+        assert!(Prerender::new(" , ", "prerendered").is_empty());
+        let app = Router::new().fallback_service(ServeDir::new(&*dir)).layer(
+            axum::middleware::from_fn_with_state((Arc::new(prerender), Arc::from(&*dir)), serve),
+        );
+        for (uri, agent, expected) in [
+            (
+                "/about.html",
+                "Mozilla/5.0 (compatible; Googlebot/2.1)",
+                "snapshot",
+            ),
+            ("/about.html?a=1", "BingBot", "snapshot"),
+            ("/about.html", "Mozilla/5.0 Firefox", "app"),
+            ("/contact.html", "Googlebot", "app"),
+        ] {
+            let req = Request::get(uri)
+                .header(header::USER_AGENT, agent)
+                .body(Body::empty())
+                .unwrap();
+            let response = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(response.headers()[header::VARY], "User-Agent");
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            assert_eq!(body, expected, "{uri} {agent}");
+        }
+    }
+}
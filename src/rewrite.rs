@@ -0,0 +1,139 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::{uri::PathAndQuery, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use regex_automata::meta::Regex;
+
+/// An internal rewrite of the request paths matching `regex`.
+///
+/// The match is replaced with `replacement`, in which `$1` or `${name}` refer
+/// to the groups captured by the regex.
+#[derive(Debug)]
+pub struct Rewrite {
+    regex: Regex,
+    replacement: String,
+}
+
+impl std::str::FromStr for Rewrite {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (regex, replacement) = s.split_once('=').ok_or_else(|| s.to_string())?;
+        let regex = Regex::new(regex.trim()).map_err(|e| format!("{s}: {e}"))?;
+        Ok(Self {
+            regex,
+            replacement: replacement.trim().to_string(),
+        })
+    }
+}
+
+impl Rewrite {
+    /// Returns the rewritten `path`, or `None` if the regex does not match.
+    fn apply(&self, path: &str) -> Option<String> {
+        let mut captures = self.regex.create_captures();
+        self.regex.captures(path, &mut captures);
+        let matched = captures.get_match()?;
+        let mut rewritten = path[..matched.start()].to_string();
+        captures.interpolate_string_into(path, &self.replacement, &mut rewritten);
+        rewritten.push_str(&path[matched.end()..]);
+        Some(rewritten)
+    }
+}
+
+/// Parses a semicolon-separated list of `regex=replacement` rewrites, e.g.
+/// `^/blog/\d+/(\w+)$=/posts/$1.html`.
+pub fn parse_rewrites(s: &str) -> Result<Arc<[Rewrite]>, String> {
+    s.split(';')
+        .map(str::trim)
+        .filter(|rewrite| !rewrite.is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+/// Serves the request from the path of the first matching rewrite, without
+/// redirecting the client.
+pub async fn rewrite(
+    State(rewrites): State<Arc<[Rewrite]>>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let Some(rewritten) = rewrites
+        .iter()
+        .find_map(|rewrite| rewrite.apply(req.uri().path()))
+    else {
+        return next.run(req).await;
+    };
+    let query = req
+        .uri()
+        .query()
+        .map(|q| format!("?{q}"))
+        .unwrap_or_default();
+    tracing::debug!("rewriting '{}' to '{}'", req.uri().path(), rewritten);
+
+    let mut parts = req.uri().clone().into_parts();
+    parts.path_and_query = match format!("{rewritten}{query}").parse::<PathAndQuery>() {
+        Ok(path_and_query) if rewritten.starts_with('/') => Some(path_and_query),
+        _ => {
+            tracing::error!("invalid rewritten path '{}'", rewritten);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    match axum::http::Uri::from_parts(parts) {
+        Ok(uri) => *req.uri_mut() = uri,
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, routing::get, Router};
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[test]
+    fn apply_interpolates_the_captured_groups() {
+        let rewrite = r"^/blog/\d+/(?P<slug>\w+)$=/posts/${slug}.html"
+            .parse::<Rewrite>()
+            .unwrap();
+        assert_eq!(
+            rewrite.apply("/blog/2024/hello").as_deref(),
+            Some("/posts/hello.html")
+        );
+        assert_eq!(rewrite.apply("/blog/new/hello"), None);
+        assert_eq!(parse_rewrites("^/a$=/b; ;^/c$=/d").unwrap().len(), 2);
+        assert!(parse_rewrites("^/a$").is_err());
+        assert!(parse_rewrites("^/(a$=/b").is_err());
+    }
+
+    #[tokio::test]
+    async fn rewritten_paths_are_routed() {
+        let site = Router::new()
+            .route("/", get(|| async { "index" }))
+            .fallback(|uri: axum::http::Uri| async move { uri.to_string() });
+        let rewrites = parse_rewrites(r"^/app/.*$=/;^/old/(\w+)$=/new/$1;^/bad$=bad").unwrap();
+        let app = Router::new()
+            .fallback_service(site)
+            .layer(axum::middleware::from_fn_with_state(rewrites, rewrite));
+        let get = |uri: &str| {
+            let app = app.clone();
+            let req = Request::get(uri).body(Body::empty()).unwrap();
+            async move {
+                let response = app.oneshot(req).await.unwrap();
+                let status = response.status();
+                let body = response.into_body().collect().await.unwrap().to_bytes();
+                (status, String::from_utf8(body.to_vec()).unwrap())
+            }
+        };
+        assert_eq!(get("/app/settings").await.1, "index");
+        assert_eq!(get("/old/page?a=1").await.1, "/new/page?a=1");
+        assert_eq!(get("/other").await.1, "/other");
+        assert_eq!(get("/bad").await.0, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
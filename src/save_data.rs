@@ -0,0 +1,120 @@
+use std::{path::Path, sync::Arc};
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, Uri},
+    middleware::Next,
+    response::Response,
+};
+
+use crate::etag;
+
+static SAVE_DATA: HeaderName = HeaderName::from_static("save-data");
+
+/// Returns whether the client asks to reduce data usage with `Save-Data: on`.
+fn is_on(headers: &HeaderMap) -> bool {
+    headers
+        .get(&SAVE_DATA)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.trim().eq_ignore_ascii_case("on"))
+}
+
+/// Compresses responses to clients sending `Save-Data: on`.
+///
+/// With `only` set, compression is otherwise disabled, so `Accept-Encoding` is
+/// removed from all other requests. Every response varies by `Save-Data`.
+pub async fn compress(State(only): State<bool>, mut req: Request, next: Next) -> Response {
+    if only && !is_on(req.headers()) {
+        req.headers_mut().remove(header::ACCEPT_ENCODING);
+    }
+    let mut response = next.run(req).await;
+    response
+        .headers_mut()
+        .append(header::VARY, HeaderValue::from_static("Save-Data"));
+    response
+}
+
+/// Serves the `.save-data` variant of a file below `root`, e.g.
+/// "photo.save-data.jpg" for "photo.jpg", to clients sending `Save-Data: on`
+/// if it exists.
+pub async fn variant(State(root): State<Arc<Path>>, mut req: Request, next: Next) -> Response {
+    if is_on(req.headers()) {
+        if let Some(uri) = variant_uri(&root, req.uri()).await {
+            *req.uri_mut() = uri;
+        }
+    }
+    next.run(req).await
+}
+
+async fn variant_uri(root: &Path, uri: &Uri) -> Option<Uri> {
+    let path = uri.path();
+    let (stem, ext) = path.rsplit_once('.')?;
+    if stem.is_empty() || stem.ends_with('/') || ext.contains('/') {
+        return None;
+    }
+    let variant = format!("{stem}.save-data.{ext}");
+    let file = etag::resolve(root, &variant)?;
+    if !tokio::fs::metadata(&file).await.is_ok_and(|m| m.is_file()) {
+        return None;
+    }
+    let variant = match uri.query() {
+        Some(query) => format!("{variant}?{query}"),
+        None => variant,
+    };
+    variant.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, Router};
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+    use tower_http::services::ServeDir;
+
+    use super::*;
+    use crate::temp_dir::TempDir;
+
+    fn get(uri: &str, save_data: bool) -> Request {
+        let mut req = Request::get(uri).header(header::ACCEPT_ENCODING, "gzip");
+        if save_data {
+            req = req.header(&SAVE_DATA, "on");
+        }
+        req.body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn variant_is_served_to_save_data_clients() {
+        let dir = TempDir::new(&[
+            ("photo.jpg", "full"),
+            ("photo.save-data.jpg", "small"),
+            ("logo.png", "logo"),
+        ]);
+        let app = Router::new().fallback_service(ServeDir::new(&*dir)).layer(
+            axum::middleware::from_fn_with_state(Arc::from(&*dir), variant),
+        );
+        for (uri, save_data, expected) in [
+            ("/photo.jpg", false, "full"),
+            ("/photo.jpg", true, "small"),
+            ("/logo.png", true, "logo"),
+        ] {
+            let response = app.clone().oneshot(get(uri, save_data)).await.unwrap();
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            assert_eq!(body, expected, "{uri} {save_data}");
+        }
+    }
+
+    #[tokio::test]
+    async fn compress_only_keeps_the_encodings_of_save_data_clients() {
+        let app = Router::new()
+            .fallback(|headers: HeaderMap| async move {
+                headers.contains_key(header::ACCEPT_ENCODING).to_string()
+            })
+            .layer(axum::middleware::from_fn_with_state(true, compress));
+        for (save_data, expected) in [(false, "false"), (true, "true")] {
+            let response = app.clone().oneshot(get("/", save_data)).await.unwrap();
+            assert_eq!(response.headers()[header::VARY], "Save-Data");
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            assert_eq!(body, expected);
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use std::{
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::{Body, BodyDataStream},
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use futures_util::{Stream, StreamExt};
+
+use crate::etag;
+
+/// Logs serving files below `root` whose opening or reading takes at least
+/// `threshold`, e.g. on a slow network file system.
+///
+/// Opening is timed until the response is ready, reading while the body waits
+/// for the next chunk of the file, so a slow client is not mistaken for a slow
+/// file system.
+pub async fn log_slow(
+    State((threshold, root)): State<(Duration, Arc<Path>)>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Some(path) = etag::resolve(&root, req.uri().path()) else {
+        return next.run(req).await;
+    };
+    let start = Instant::now();
+    let response = next.run(req).await;
+    let elapsed = start.elapsed();
+    if elapsed >= threshold {
+        tracing::warn!(
+            "slow file system: opening '{}' took {} ms",
+            path.display(),
+            elapsed.as_millis()
+        );
+    }
+    response.map(|body| {
+        Body::from_stream(Timed {
+            inner: body.into_data_stream(),
+            waiting: None,
+            threshold,
+            path,
+        })
+    })
+}
+
+/// A body logging waits of at least `threshold` for its next chunk.
+struct Timed {
+    inner: BodyDataStream,
+    waiting: Option<Instant>,
+    threshold: Duration,
+    path: PathBuf,
+}
+
+impl Stream for Timed {
+    type Item = <BodyDataStream as Stream>::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = self.inner.poll_next_unpin(cx);
+        if poll.is_pending() {
+            self.waiting.get_or_insert_with(Instant::now);
+        } else if let Some(since) = self.waiting.take() {
+            let elapsed = since.elapsed();
+            if elapsed >= self.threshold {
+                tracing::warn!(
+                    "slow file system: reading '{}' took {} ms",
+                    self.path.display(),
+                    elapsed.as_millis()
+                );
+            }
+        }
+        poll
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::Router;
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+    use tower_http::services::ServeDir;
+
+    use super::*;
+    use crate::{captured::Captured, temp_dir::TempDir};
+
+    #[tokio::test]
+    async fn log_slow_logs_opening_and_reading_files_over_the_threshold() {
+        let dir = TempDir::new(&[("a.txt", "content")]);
+        let app = |threshold| {
+            Router::new().fallback_service(ServeDir::new(&*dir)).layer(
+                axum::middleware::from_fn_with_state((threshold, Arc::from(&*dir)), log_slow),
+            )
+        };
+        let get = || Request::get("/a.txt").body(Body::empty()).unwrap();
+        let (captured, _guard) = Captured::start();
+
+        let response = app(Duration::from_secs(30)).oneshot(get()).await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "content");
+        assert!(captured.lines().is_empty());
+
+        let response = app(Duration::ZERO).oneshot(get()).await.unwrap();
+        response.into_body().collect().await.unwrap();
+        let path = dir.join("a.txt");
+        let lines = captured.lines();
+        assert!(lines[0].starts_with(&format!(
+            "slow file system: opening '{}' took",
+            path.display()
+        )));
+        assert!(lines[1..]
+            .iter()
+            .all(|line| line.starts_with("slow file system: reading")));
+    }
+}
@@ -0,0 +1,205 @@
+use axum::{
+    extract::Request,
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use tracing::{
+    field::{display, Empty},
+    Span,
+};
+
+static TRACEPARENT: HeaderName = HeaderName::from_static("traceparent");
+
+/// A W3C Trace Context as carried by the `traceparent` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceContext {
+    trace_id: u128,
+    parent_id: Option<u64>,
+    span_id: u64,
+    flags: u8,
+}
+
+impl TraceContext {
+    /// Starts a new trace.
+    fn new() -> Self {
+        Self {
+            trace_id: random_nonzero(),
+            parent_id: None,
+            span_id: random_nonzero(),
+            flags: 0,
+        }
+    }
+
+    /// Continues the trace of a `traceparent` header value in a new span.
+    ///
+    /// Returns `None` for malformed values, which according to the
+    /// specification must be ignored.
+    fn parse(value: &str) -> Option<Self> {
+        let mut fields = value.trim().split('-');
+        let version = fields.next().filter(|v| v.len() == 2)?;
+        let trace_id = fields.next().filter(|v| v.len() == 32)?;
+        let parent_id = fields.next().filter(|v| v.len() == 16)?;
+        let flags = fields.next().filter(|v| v.len() == 2)?;
+        // later versions may append fields, version 00 must not
+        if version == "ff" || (version == "00" && fields.next().is_some()) {
+            return None;
+        }
+        u8::from_str_radix(version, 16).ok()?;
+
+        let trace_id = u128::from_str_radix(trace_id, 16)
+            .ok()
+            .filter(|&id| id != 0)?;
+        let parent_id = u64::from_str_radix(parent_id, 16)
+            .ok()
+            .filter(|&id| id != 0)?;
+        let flags = u8::from_str_radix(flags, 16).ok()?;
+        Some(Self {
+            trace_id,
+            parent_id: Some(parent_id),
+            span_id: random_nonzero(),
+            flags,
+        })
+    }
+
+    /// The `traceparent` header value identifying the span of this server.
+    fn header_value(&self) -> HeaderValue {
+        HeaderValue::from_str(&format!(
+            "00-{:032x}-{:016x}-{:02x}",
+            self.trace_id, self.span_id, self.flags
+        ))
+        .expect("hex is a valid header value")
+    }
+}
+
+fn random_nonzero<T>() -> T
+where
+    T: Default + PartialEq,
+    rand::distr::StandardUniform: rand::distr::Distribution<T>,
+{
+    loop {
+        let id = rand::random::<T>();
+        if id != T::default() {
+            return id;
+        }
+    }
+}
+
+/// Continues the trace of an incoming `traceparent` header or starts a new
+/// one, and returns the `traceparent` of the request span with the response.
+pub async fn propagate(mut req: Request, next: Next) -> Response {
+    let context = req
+        .headers()
+        .get(&TRACEPARENT)
+        .and_then(|v| v.to_str().ok())
+        .and_then(TraceContext::parse)
+        .unwrap_or_else(TraceContext::new);
+    req.extensions_mut().insert(context);
+
+    let mut response = next.run(req).await;
+    response
+        .headers_mut()
+        .insert(TRACEPARENT.clone(), context.header_value());
+    response
+}
+
+/// Creates the request span, recording its trace context if present.
+pub fn make_span(req: &Request) -> Span {
+    let span = tracing::debug_span!(
+        "request",
+        method = %req.method(),
+        uri = %req.uri(),
+        version = ?req.version(),
+        trace_id = Empty,
+        parent_id = Empty,
+        span_id = Empty,
+    );
+    if let Some(context) = req.extensions().get::<TraceContext>() {
+        span.record(
+            "trace_id",
+            display(format_args!("{:032x}", context.trace_id)),
+        );
+        if let Some(parent_id) = context.parent_id {
+            span.record("parent_id", display(format_args!("{parent_id:016x}")));
+        }
+        span.record("span_id", display(format_args!("{:016x}", context.span_id)));
+    }
+    span
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[test]
+    fn parse_follows_the_specification() {
+        let context =
+            TraceContext::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").unwrap();
+        assert_eq!(context.trace_id, 0x4bf9_2f35_77b3_4da6_a3ce_929d_0e0e_4736);
+        assert_eq!(context.parent_id, Some(0x00f0_67aa_0ba9_02b7));
+        assert_eq!(context.flags, 1);
+        assert_ne!(context.span_id, 0);
+        // Later versions may append fields
+        assert!(
+            TraceContext::parse("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-x")
+                .is_some()
+        );
+
+        for value in [
+            "",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-x",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e473g-00f067aa0ba902b7-01",
+        ] {
+            assert_eq!(TraceContext::parse(value), None, "{value}");
+        }
+    }
+
+    #[tokio::test]
+    async fn propagate_continues_or_starts_a_trace() {
+        let app = Router::new()
+            .fallback(|| async {})
+            .layer(axum::middleware::from_fn(propagate));
+        let traceparent = |req: Request| {
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(req).await.unwrap();
+                response.headers()[&TRACEPARENT]
+                    .to_str()
+                    .unwrap()
+                    .to_string()
+            }
+        };
+
+        let continued = traceparent(
+            Request::get("/")
+                .header(
+                    &TRACEPARENT,
+                    "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+                )
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        assert!(continued.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+        assert!(continued.ends_with("-01"));
+        assert!(!continued.contains("00f067aa0ba902b7"));
+
+        let started = traceparent(
+            Request::get("/")
+                .header(&TRACEPARENT, "malformed")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        let new = TraceContext::parse(&started).unwrap();
+        assert_eq!(new.flags, 0);
+        assert!(!started.contains("4bf92f3577b34da6a3ce929d0e0e4736"));
+    }
+}
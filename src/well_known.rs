@@ -0,0 +1,114 @@
+use std::{collections::HashSet, sync::Arc};
+
+use axum::{
+    extract::State,
+    http::{header, HeaderValue},
+    response::{IntoResponse, Response},
+    Extension,
+};
+use bytes::Bytes;
+
+use crate::middleware::Generated;
+
+/// A document served below "/.well-known/" instead of a file of the document
+/// root.
+pub struct Document {
+    content_type: HeaderValue,
+    body: Bytes,
+}
+
+/// Parses a semicolon-separated list of `name=value` documents served at
+/// "/.well-known/name", e.g. "security.txt=@/etc/security.txt".
+///
+/// A value starting with `@` is the path of a file read at startup, any other
+/// value is the content itself. The content type is guessed from the name,
+/// which must be unique and must not contain the route syntax `{`, `}`, `*` or
+/// `:`.
+pub fn parse(s: &str) -> Result<Vec<(String, Arc<Document>)>, String> {
+    let mut names = HashSet::new();
+    s.split(';')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (name, value) = entry.split_once('=').ok_or_else(|| entry.to_string())?;
+            let name = name.trim().trim_start_matches('/');
+            if name.is_empty()
+                || name.split('/').any(|s| s.is_empty() || s == "..")
+                || name.contains(['{', '}', '*', ':'])
+                || !names.insert(name.to_string())
+            {
+                return Err(entry.to_string());
+            }
+            let body = match value.strip_prefix('@') {
+                Some(path) => std::fs::read(path.trim())
+                    .map_err(|e| format!("{}: {}", path.trim(), e))?
+                    .into(),
+                None => Bytes::copy_from_slice(value.as_bytes()),
+            };
+            let content_type = mime_guess::from_path(name).first_or_text_plain();
+            let document = Document {
+                content_type: HeaderValue::from_str(content_type.as_ref())
+                    .map_err(|_| entry.to_string())?,
+                body,
+            };
+            Ok((format!("/.well-known/{name}"), Arc::new(document)))
+        })
+        .collect()
+}
+
+/// Serves a configured well-known document.
+pub async fn serve(State(document): State<Arc<Document>>) -> Response {
+    (
+        [(header::CONTENT_TYPE, document.content_type.clone())],
+        Extension(Generated),
+        document.body.clone(),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::Request, routing::get, Router};
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[test]
+    fn parse_rejects_invalid_names() {
+        for s in [
+            "a.txt=x;a.txt=y",
+            "a.txt=x;/a.txt=y",
+            "{name}=x",
+            "*rest=x",
+            "a/:b=x",
+            "../a.txt=x",
+            "a//b=x",
+            "=x",
+            "a.txt",
+        ] {
+            assert!(parse(s).is_err(), "{s}");
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_parsed_documents() {
+        let documents = parse("security.txt=Contact: x@example.com; app/config.json={}").unwrap();
+        let mut app = Router::new();
+        for (path, document) in documents {
+            app = app.route(&path, get(serve).with_state(document));
+        }
+        let req = Request::get("/.well-known/security.txt")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/plain");
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "Contact: x@example.com");
+        let req = Request::get("/.well-known/app/config.json")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(req).await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+    }
+}